@@ -0,0 +1,266 @@
+// Copyright Jacob Salzberg
+// SPDX-License-Identifier: Apache-2.0
+
+// Native driver exploring the demonic choices of test_demonic_stack.rs, so
+// that the demonic model can be debugged without a solver. By default each
+// harness of its HARNESSES is run once for every combination of answers of
+// demonic_nondet(), and every failing run is printed with its answers, 1 for
+// true and 0 for false in the order the choices were made.
+//
+//   rustc --edition 2021 enumerate_demonic.rs --extern stacked_borrows=libstacked_borrows.so
+//   ./enumerate_demonic [--random RUNS] [--seed SEED] [HARNESS]
+//   ./enumerate_demonic --choices ANSWERS HARNESS
+//   ./enumerate_demonic --playback FILE HARNESS > TEST.rs
+//
+// Programs with too many choices to enumerate are fuzzed instead with
+// --random: the harnesses are run RUNS times with pseudo-random answers,
+// from seed SEED on or from a seed taken from the clock, and the seed of
+// every failing run is printed. --seed alone replays the run of that seed.
+//
+// A single run is traced with --choices, taking the answers as printed
+// above: the monitored stack is printed after every operation of the run,
+// and before the check of a failing access, which is easier to follow than
+// the writes to the model state in a CBMC trace.
+//
+// --playback turns the test Kani writes for a counterexample with
+// --concrete-playback into a native unit test replaying its answers through
+// replay_demonic_choices with the same tracing, e.g.
+//
+//   kani test_demonic_stack.rs --harness main --concrete-playback=print > playback.rs
+//   ./enumerate_demonic --playback playback.rs main > main_playback.rs
+//   rustc --edition 2021 --test main_playback.rs --extern stacked_borrows=libstacked_borrows.so
+//   ./main_playback --nocapture
+//
+// Every value of the counterexample has to be an answer of demonic_nondet(),
+// the only nondeterminism of test_demonic_stack.rs, so counterexamples of
+// the other models are rejected. The test Kani prints cannot be run as is:
+// natively there is no kani::concrete_playback_run, as test_demonic_stack.rs
+// registers kani as a tool for its harness attributes and cannot also have a
+// kani module.
+//
+// Like test_demonic_stack.rs it needs a nightly toolchain. It exits with 1
+// when a result is unexpected: a run of a harness expected to pass fails, or
+// enumerating every choice of a harness expected to fail finds no failing
+// run, see HARNESSES. A single run or fuzzing may miss the failing choices,
+// so only a failure is unexpected there.
+#![feature(register_tool)]
+#![register_tool(kani)]
+// the crate attributes of test_demonic_stack.rs are set here instead
+#![allow(unused_attributes)]
+
+#[path = "test_demonic_stack.rs"]
+#[allow(dead_code)]
+mod model;
+
+use std::process::exit;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+struct Options {
+    random: Option<u64>,
+    seed: Option<u64>,
+    // answers of the single run to trace
+    replay: Option<Vec<bool>>,
+    // playback file and answers of a Kani counterexample to write a unit
+    // test for
+    playback: Option<(String, Vec<bool>)>,
+    harness: Option<String>,
+}
+
+fn usage() -> ! {
+    eprintln!("usage: enumerate_demonic [--random RUNS] [--seed SEED] [HARNESS]");
+    eprintln!("       enumerate_demonic --choices ANSWERS HARNESS");
+    eprintln!("       enumerate_demonic --playback FILE HARNESS");
+    exit(2);
+}
+
+// Parses answers printed as 1s and 0s.
+fn parse_answers(answers: &str) -> Option<Vec<bool>> {
+    answers
+        .chars()
+        .map(|c| match c {
+            '1' => Some(true),
+            '0' => Some(false),
+            _ => None,
+        })
+        .collect()
+}
+
+// Returns the answers of a Kani concrete playback test: its values are the
+// innermost `vec![..]` of bytes, in the order of the kani::any calls, and an
+// answer of demonic_nondet() is a bool, a single byte 0 or 1.
+fn playback_answers(test: &str) -> Result<Vec<bool>, String> {
+    let mut answers = Vec::new();
+    let mut rest = test;
+    while let Some(start) = rest.find("vec![") {
+        rest = &rest[start + "vec![".len()..];
+        let end = rest.find(']').ok_or("unterminated vec!")?;
+        let bytes: Vec<&str> = rest[..end].split(',').map(str::trim).filter(|b| !b.is_empty()).collect();
+        if bytes.is_empty() || bytes.iter().any(|b| b.parse::<u8>().is_err()) {
+            // the outer vec! of the values
+            continue;
+        }
+        match bytes[..] {
+            ["0"] => answers.push(false),
+            ["1"] => answers.push(true),
+            _ => {
+                return Err(format!(
+                    "value {} [{}] is not a demonic choice: only counterexamples of test_demonic_stack.rs, \
+                     whose only nondeterminism is demonic_nondet(), can be played back",
+                    answers.len(),
+                    bytes.join(", ")
+                ))
+            }
+        }
+    }
+    if answers.is_empty() {
+        return Err("no concrete values".to_string());
+    }
+    Ok(answers)
+}
+
+// Native unit test replaying the answers of a counterexample of the harness,
+// to be compiled next to test_demonic_stack.rs.
+fn playback_test(harness: &str, file: &str, answers: &[bool]) -> String {
+    let values: Vec<&str> = answers.iter().map(|&answer| if answer { "true" } else { "false" }).collect();
+    format!(
+        "// Replays the Kani counterexample of {file} for the harness {harness} of
+// test_demonic_stack.rs, written by enumerate_demonic --playback. Run with
+// --nocapture to see the monitored stack after every operation.
+#![feature(register_tool)]
+#![register_tool(kani)]
+#![allow(unused_attributes)]
+
+#[path = \"test_demonic_stack.rs\"]
+#[allow(dead_code)]
+mod model;
+
+#[test]
+fn {harness}_playback() {{
+    let answers = [{values}];
+    let (_, harness, _) = model::HARNESSES.iter().find(|(name, _, _)| *name == \"{harness}\").unwrap();
+    if let Err((choices, message)) = model::replay_demonic_choices(*harness, &answers) {{
+        let choices: String = choices.iter().map(|&answer| if answer {{ '1' }} else {{ '0' }}).collect();
+        panic!(\"choices {{}}: {{}}\", choices, message);
+    }}
+}}
+",
+        values = values.join(", ")
+    )
+}
+
+fn parse_options() -> Options {
+    let mut random = None;
+    let mut seed = None;
+    let mut replay = None;
+    let mut playback = None;
+    let mut harness = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--random" => random = Some(args.next().and_then(|n| n.parse().ok()).unwrap_or_else(|| usage())),
+            "--seed" => seed = Some(args.next().and_then(|s| s.parse().ok()).unwrap_or_else(|| usage())),
+            "--choices" => replay = Some(args.next().and_then(|a| parse_answers(&a)).unwrap_or_else(|| usage())),
+            "--playback" => {
+                let file = args.next().unwrap_or_else(|| usage());
+                let test = std::fs::read_to_string(&file).unwrap_or_else(|e| {
+                    eprintln!("enumerate_demonic: cannot read {}: {}", file, e);
+                    exit(1);
+                });
+                let answers = playback_answers(&test).unwrap_or_else(|e| {
+                    eprintln!("enumerate_demonic: {}: {}", file, e);
+                    exit(1);
+                });
+                playback = Some((file, answers));
+            }
+            _ if arg.starts_with('-') || harness.is_some() => usage(),
+            _ => harness = Some(arg),
+        }
+    }
+    let single = replay.is_some() as u8 + playback.is_some() as u8;
+    if single > 1 || (single == 1 && (random.is_some() || seed.is_some() || harness.is_none())) {
+        usage();
+    }
+    Options { random, seed, replay, playback, harness }
+}
+
+fn answers(choices: &[bool]) -> String {
+    choices.iter().map(|&answer| if answer { '1' } else { '0' }).collect()
+}
+
+// Explores the choices of one harness as the options ask and returns whether
+// a run failed.
+fn explore(name: &str, harness: fn(), options: &Options) -> bool {
+    if let Some(replay) = &options.replay {
+        return match model::replay_demonic_choices(harness, replay) {
+            Ok(()) => {
+                println!("{}: passes", name);
+                false
+            }
+            Err((choices, message)) => {
+                println!("{}: fails, choices {}: {}", name, answers(&choices), message);
+                true
+            }
+        };
+    }
+    match (options.random, options.seed) {
+        (None, None) => {
+            let (runs, failures) = model::enumerate_demonic_choices(harness);
+            println!("{}: {} runs, {} failing", name, runs, failures.len());
+            for (choices, message) in &failures {
+                println!("  choices {}: {}", answers(choices), message);
+            }
+            !failures.is_empty()
+        }
+        (None, Some(seed)) => match model::run_with_seed(harness, seed) {
+            Ok(()) => {
+                println!("{}: seed {} passes", name, seed);
+                false
+            }
+            Err((choices, message)) => {
+                println!("{}: seed {} fails, choices {}: {}", name, seed, answers(&choices), message);
+                true
+            }
+        },
+        (Some(runs), seed) => {
+            let first_seed = seed.unwrap_or_else(|| {
+                SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_nanos() as u64)
+            });
+            let failures = model::fuzz_demonic_choices(harness, first_seed, runs);
+            println!("{}: {} runs from seed {}, {} failing", name, runs, first_seed, failures.len());
+            for (seed, message) in &failures {
+                println!("  seed {}: {}", seed, message);
+            }
+            !failures.is_empty()
+        }
+    }
+}
+
+fn main() {
+    let options = parse_options();
+    if let Some(only) = &options.harness {
+        if !model::HARNESSES.iter().any(|(name, _, _)| name == only) {
+            eprintln!("enumerate_demonic: no harness {}", only);
+            exit(2);
+        }
+    }
+    if let (Some((file, answers)), Some(harness)) = (&options.playback, &options.harness) {
+        print!("{}", playback_test(harness, file, answers));
+        return;
+    }
+    let exhaustive = options.replay.is_none() && options.random.is_none() && options.seed.is_none();
+    let mut unexpected = false;
+    for (name, harness, should_fail) in model::HARNESSES {
+        if options.harness.as_deref().is_some_and(|only| only != *name) {
+            continue;
+        }
+        let failed = explore(name, *harness, &options);
+        if failed && !should_fail {
+            println!("{}: unexpected failure", name);
+            unexpected = true;
+        } else if !failed && *should_fail && exhaustive {
+            println!("{}: expected to fail for some choice", name);
+            unexpected = true;
+        }
+    }
+    exit(if unexpected { 1 } else { 0 });
+}
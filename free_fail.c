@@ -0,0 +1,23 @@
+#ifdef DEMONIC
+#include "stacked_borrows_demonic.h"
+#else
+#include "stacked_borrows.h"
+#endif
+
+/// frees a local through a raw pointer
+int main() {
+  SB_INIT(true, 16);
+
+  // let mut local = 5;
+  int local = 5;
+  NEW_LOCAL(local);
+
+  // let x = &mut local as *mut i32;
+  USE2_LOCAL(local);
+  int *x = &local;
+  SHARED_RW_FROM_LOCAL(x, local);
+
+  // drop(Box::from_raw(x));
+  FREE(x); // should fail
+  return 0;
+}
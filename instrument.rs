@@ -0,0 +1,1082 @@
+// Copyright Jacob Salzberg
+// SPDX-License-Identifier: Apache-2.0
+
+// Source to source stacked borrows instrumentation, shared by the
+// `#[stacked_borrows::instrument]` attribute (stacked_borrows.rs) and the
+// standalone instrumenter (instrumenter.rs). It only depends on std, so it
+// works on plain source text rather than on proc_macro tokens.
+//
+// The instrumented code uses the macros of macros.rs, which expand to the
+// `sb_*` functions of whichever model the file defines. Statements are
+// instrumented as follows:
+//
+//   fn f(x: &mut T, ..)              bind_arg!(0, x); at the start of the body,
+//                                    then fn_entry!(let x = &mut *x); for the
+//                                    retag of references on function entry and
+//                                    let _f__protected = protect!(x, ..);
+//   let a = 5;                       new_local!(let a = 5);
+//   let q = g(..);                   new_local!(let q = g(..)); bind_return!(q);
+//   return p; or tail p              pass_return!(p); first in functions
+//                                    returning a reference or a raw pointer,
+//                                    reborrows are bound to f__return first
+//   let r = &mut a;                  unique_from_local!(let r = &mut a);
+//   let r = &a;                      shared_from_local!(let r = &a);
+//   let r = &mut *p;                 unique_from_ref!(let r = &mut *p);
+//   let r = unsafe { &mut *p };      unique_from_ref!(let r = unsafe { &mut *p });
+//   let r = &*p;                     shared_from_ref!(let r = &*p);
+//   let p = &mut a as *mut T;        raw_from_local!(let p = &mut a as *mut T);
+//   let p = r as *mut T;             raw_from_ref!(let p = r as *mut T);
+//   *p = v;                          store!(*p = v);
+//   let x = *p;                      load!(let x = *p);
+//   *p = e;                          followed by use2!(p);
+//   ... *p ...                       preceded by read1!(p);
+//   let x = y;                       copy!(let x = y); x gets the tag of the
+//                                    pointer y, or y is read as a local
+//   a = e;                           followed by use2_local!(a);, a += e is
+//                                    also preceded by read1_local!(a);
+//   a = b;                           copy!(a = b);
+//   g(&mut *p, q)                    { unique_from_ref!(let g__arg0 = &mut *p);
+//                                    pass_arg!(0, g__arg0); pass_arg!(1, q);
+//                                    g(g__arg0, q) }, see thread_calls
+//
+// where y, a and b are parameters or variables bound by the statements
+// above, whose tag the model knows. Blocks of if, else, while, loop, for and
+// unsafe statements are instrumented recursively. Anything else is kept as
+// is: patterns other than a single variable, `let mut` bindings of
+// references, method calls, reads of locals in other expressions and
+// closures are not tracked.
+#![allow(dead_code)]
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Tree {
+    Ident(String),
+    Punct(String),
+    Literal(String),
+    // delimiter ('(', '[' or '{') and contents
+    Group(char, Vec<Tree>),
+}
+
+// multi character operators, longest first
+const OPERATORS: [&str; 25] = [
+    ">>=", "<<=", "...", "..=", "::", "->", "=>", "==", "!=", "<=", ">=", "&&", "||",
+    "+=", "-=", "*=", "/=", "%=", "^=", "&=", "|=", "<<", ">>", "..", "#!",
+];
+
+const ASSIGNMENTS: [&str; 11] = ["=", "+=", "-=", "*=", "/=", "%=", "^=", "&=", "|=", "<<=", ">>="];
+
+// arguments whose provenance can be passed to a call, see tag_map.rs
+const MAX_CALL_ARGS: usize = 8;
+
+// suffix stacked_borrows.rs gives identifiers, followed by the index of
+// their span, so that the same variable may be spelled differently
+pub const SPAN_MARKER: &str = "__sb_span_";
+
+const KEYWORDS: [&str; 14] = [
+    "as", "break", "else", "for", "if", "in", "let", "loop", "match", "move", "return", "unsafe",
+    "where", "while",
+];
+
+////// lexer //////
+
+/// Splits source text into token trees. Comments are dropped.
+pub fn lex(source: &str) -> Result<Vec<Tree>, String> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut pos = 0;
+    let trees = lex_group(&chars, &mut pos, None)?;
+    Ok(trees)
+}
+
+fn lex_group(chars: &[char], pos: &mut usize, close: Option<char>) -> Result<Vec<Tree>, String> {
+    let mut trees = Vec::new();
+    while *pos < chars.len() {
+        let c = chars[*pos];
+        let next = chars.get(*pos + 1).copied();
+        // r#type, not a raw string
+        let raw_identifier = c == 'r' && next == Some('#')
+            && chars.get(*pos + 2).is_some_and(|d| d.is_alphabetic() || *d == '_');
+        if c.is_whitespace() {
+            *pos += 1;
+        } else if c == '/' && next == Some('/') {
+            while *pos < chars.len() && chars[*pos] != '\n' {
+                *pos += 1;
+            }
+        } else if c == '/' && next == Some('*') {
+            let mut depth = 0;
+            while *pos < chars.len() {
+                if chars[*pos] == '/' && chars.get(*pos + 1) == Some(&'*') {
+                    depth += 1;
+                    *pos += 2;
+                } else if chars[*pos] == '*' && chars.get(*pos + 1) == Some(&'/') {
+                    depth -= 1;
+                    *pos += 2;
+                    if depth == 0 {
+                        break;
+                    }
+                } else {
+                    *pos += 1;
+                }
+            }
+        } else if c == '(' || c == '[' || c == '{' {
+            *pos += 1;
+            let closing = match c { '(' => ')', '[' => ']', _ => '}' };
+            let contents = lex_group(chars, pos, Some(closing))?;
+            trees.push(Tree::Group(c, contents));
+        } else if c == ')' || c == ']' || c == '}' {
+            if close != Some(c) {
+                return Err(format!("unbalanced '{}'", c));
+            }
+            *pos += 1;
+            return Ok(trees);
+        } else if c == '"' || ((c == 'b' || c == 'r') && (next == Some('"') || next == Some('#')) && !raw_identifier) {
+            trees.push(Tree::Literal(lex_string(chars, pos)));
+        } else if c == 'b' && next == Some('\'') {
+            *pos += 1;
+            let literal = lex_char(chars, pos).unwrap_or_default();
+            trees.push(Tree::Literal(format!("b{}", literal)));
+        } else if c == '\'' {
+            match lex_char(chars, pos) {
+                Some(literal) => trees.push(Tree::Literal(literal)),
+                None => {
+                    // lifetime
+                    let start = *pos;
+                    *pos += 1;
+                    while *pos < chars.len() && (chars[*pos].is_alphanumeric() || chars[*pos] == '_') {
+                        *pos += 1;
+                    }
+                    trees.push(Tree::Ident(chars[start..*pos].iter().collect()));
+                }
+            }
+        } else if c.is_alphabetic() || c == '_' {
+            let start = *pos;
+            if raw_identifier {
+                *pos += 2;
+            }
+            while *pos < chars.len() && (chars[*pos].is_alphanumeric() || chars[*pos] == '_') {
+                *pos += 1;
+            }
+            trees.push(Tree::Ident(chars[start..*pos].iter().collect()));
+        } else if c.is_ascii_digit() {
+            let start = *pos;
+            while *pos < chars.len() {
+                let d = chars[*pos];
+                let fraction = d == '.' && chars.get(*pos + 1).is_some_and(|e| e.is_ascii_digit())
+                    && !chars[start..*pos].contains(&'.');
+                if d.is_alphanumeric() || d == '_' || fraction {
+                    *pos += 1;
+                } else {
+                    break;
+                }
+            }
+            trees.push(Tree::Literal(chars[start..*pos].iter().collect()));
+        } else {
+            let rest: String = chars[*pos..chars.len().min(*pos + 3)].iter().collect();
+            let operator = OPERATORS.iter().find(|op| rest.starts_with(**op));
+            let operator = match operator {
+                Some(op) => op.to_string(),
+                None => c.to_string(),
+            };
+            *pos += operator.chars().count();
+            trees.push(Tree::Punct(operator));
+        }
+    }
+    match close {
+        Some(c) => Err(format!("missing '{}'", c)),
+        None => Ok(trees),
+    }
+}
+
+// Lexes a string literal, with an optional b or r prefix and raw hashes.
+fn lex_string(chars: &[char], pos: &mut usize) -> String {
+    let start = *pos;
+    let mut raw = false;
+    while chars[*pos] != '"' && chars[*pos] != '#' {
+        raw |= chars[*pos] == 'r';
+        *pos += 1;
+    }
+    let mut hashes = 0;
+    while chars[*pos] == '#' {
+        hashes += 1;
+        *pos += 1;
+    }
+    *pos += 1;
+    while *pos < chars.len() {
+        if !raw && chars[*pos] == '\\' {
+            *pos += 2;
+        } else if chars[*pos] == '"' && chars[*pos + 1..].iter().take(hashes).filter(|h| **h == '#').count() == hashes {
+            *pos += 1 + hashes;
+            break;
+        } else {
+            *pos += 1;
+        }
+    }
+    chars[start..(*pos).min(chars.len())].iter().collect()
+}
+
+// Lexes a character literal at pos, or returns None if pos starts a
+// lifetime.
+fn lex_char(chars: &[char], pos: &mut usize) -> Option<String> {
+    let start = *pos;
+    let end = if chars.get(start + 1) == Some(&'\\') {
+        let mut end = start + 2;
+        while end < chars.len() && chars[end] != '\'' {
+            end += 1;
+        }
+        end
+    } else if chars.get(start + 2) == Some(&'\'') {
+        start + 2
+    } else {
+        return None;
+    };
+    *pos = end + 1;
+    Some(chars[start..*pos].iter().collect())
+}
+
+////// printer //////
+
+fn is_ident(tree: Option<&Tree>, name: &str) -> bool {
+    matches!(tree, Some(Tree::Ident(i)) if i == name)
+}
+
+fn is_punct(tree: Option<&Tree>, op: &str) -> bool {
+    matches!(tree, Some(Tree::Punct(p)) if p == op)
+}
+
+fn ident_name(tree: Option<&Tree>) -> Option<&str> {
+    match tree {
+        Some(Tree::Ident(i)) if !KEYWORDS.contains(&i.as_str()) && i != "mut" => Some(i),
+        _ => None,
+    }
+}
+
+// Name of a variable without its span marker, see SPAN_MARKER.
+fn unmarked(name: &str) -> &str {
+    name.find(SPAN_MARKER).map_or(name, |start| &name[..start])
+}
+
+// Whether the token at i starts an operand, so that a `&`, `*` or `-` there
+// is a unary operator.
+fn is_prefix_position(trees: &[Tree], i: usize) -> bool {
+    if i == 0 {
+        return true;
+    }
+    match &trees[i - 1] {
+        Tree::Punct(p) => p != "?",
+        Tree::Ident(name) => (KEYWORDS.contains(&name.as_str()) && name != "as") || name == "mut",
+        _ => false,
+    }
+}
+
+// Whether the `<` at i opens generic arguments or parameters rather than
+// being a comparison.
+fn opens_generics(trees: &[Tree], i: usize) -> bool {
+    match i.checked_sub(1).map(|j| &trees[j]) {
+        Some(Tree::Punct(p)) => p == "::",
+        Some(Tree::Ident(name)) => {
+            name.starts_with(|c: char| c.is_uppercase()) || name == "impl"
+                || (i >= 2 && ["fn", "struct", "enum", "trait", "type"].iter().any(|k| is_ident(trees.get(i - 2), k)))
+        }
+        _ => false,
+    }
+}
+
+// Whether the `>` or `>>` at i closes generic arguments or parameters.
+fn closes_generics(trees: &[Tree], i: usize) -> bool {
+    let mut depth = 0;
+    let mut j = i;
+    while j > 0 {
+        j -= 1;
+        if is_punct(trees.get(j), ">") {
+            depth += 1;
+        } else if is_punct(trees.get(j), ">>") {
+            depth += 2;
+        } else if is_punct(trees.get(j), "<") {
+            if depth == 0 {
+                return opens_generics(trees, j);
+            }
+            depth -= 1;
+        }
+    }
+    false
+}
+
+/// Prints token trees on a single line with the usual spacing.
+pub fn print(trees: &[Tree]) -> String {
+    let mut out = String::new();
+    for (i, tree) in trees.iter().enumerate() {
+        if i > 0 && space_before(trees, i) {
+            out.push(' ');
+        }
+        match tree {
+            Tree::Ident(s) | Tree::Punct(s) | Tree::Literal(s) => out.push_str(s),
+            Tree::Group(delimiter, contents) => {
+                let inner = print(contents);
+                match delimiter {
+                    '(' => { out.push('('); out.push_str(&inner); out.push(')'); }
+                    '[' => { out.push('['); out.push_str(&inner); out.push(']'); }
+                    _ if inner.is_empty() => out.push_str("{}"),
+                    _ => { out.push_str("{ "); out.push_str(&inner); out.push_str(" }"); }
+                }
+            }
+        }
+    }
+    out
+}
+
+fn space_before(trees: &[Tree], i: usize) -> bool {
+    let previous = &trees[i - 1];
+    let current = &trees[i];
+    if let Tree::Punct(p) = current {
+        if [",", ";", ".", "?", "::", ":", "..", "..="].contains(&p.as_str()) {
+            return false;
+        }
+        if p == "!" && matches!(previous, Tree::Ident(_)) {
+            return false;
+        }
+        if p == "<" {
+            return !opens_generics(trees, i);
+        }
+        if (p == ">" || p == ">>") && closes_generics(trees, i) {
+            return false;
+        }
+    }
+    if let Tree::Punct(p) = previous {
+        if [".", "::", "#", "#!", "..", "..="].contains(&p.as_str()) {
+            return false;
+        }
+        if p == "!" && matches!(current, Tree::Group(..)) {
+            return false;
+        }
+        if p == "*" && (is_ident(Some(current), "mut") || is_ident(Some(current), "const")) {
+            return false;
+        }
+        if ["&", "*", "-", "!"].contains(&p.as_str()) && is_prefix_position(trees, i - 1) {
+            return false;
+        }
+        if p == "<" && opens_generics(trees, i - 1) {
+            return false;
+        }
+    }
+    if let Tree::Group(delimiter, _) = current {
+        if *delimiter != '{' {
+            // calls, indexing and parameters, but not `if (..)` or `x = (..)`
+            return match previous {
+                Tree::Ident(name) => KEYWORDS.contains(&name.as_str()) || name == "mut",
+                Tree::Punct(p) if p == ">" || p == ">>" => !closes_generics(trees, i - 1),
+                Tree::Punct(p) => p != "&" && p != "#" && p != "#!" && p != "!",
+                _ => false,
+            };
+        }
+    }
+    true
+}
+
+////// items //////
+
+/// Splits the top level of a source file into items, each with its outer
+/// attributes. Inner attributes `#![..]` are items of their own.
+pub fn split_items(trees: &[Tree]) -> Vec<Vec<Tree>> {
+    let mut items = Vec::new();
+    let mut current: Vec<Tree> = Vec::new();
+    for tree in trees {
+        current.push(tree.clone());
+        let inner_attribute = is_punct(current.first(), "#!") && current.len() == 2;
+        // a block ends the item unless it is part of the initializer of a
+        // const or static, or of a use tree
+        let has = |keyword: &str| current.iter().any(|t| is_ident(Some(t), keyword));
+        let initialized = !has("fn") && (has("const") || has("static") || has("type"));
+        let block_ends_item = matches!(tree, Tree::Group('{', _)) && !initialized && !has("use");
+        if inner_attribute || block_ends_item || is_punct(Some(tree), ";") {
+            items.push(std::mem::take(&mut current));
+        }
+    }
+    if !current.is_empty() {
+        items.push(current);
+    }
+    items
+}
+
+/// Returns the name of the function defined by item, None if item is not a
+/// function with a body.
+pub fn function_name(item: &[Tree]) -> Option<&str> {
+    let fn_index = item.iter().position(|t| is_ident(Some(t), "fn"))?;
+    match item.last() {
+        Some(Tree::Group('{', _)) => ident_name(item.get(fn_index + 1)),
+        _ => None,
+    }
+}
+
+////// harnesses //////
+
+// Pointer parameter of a function under test: whether it is a reference or a
+// raw pointer, whether it is mutable and its pointee type.
+struct PointerParam {
+    reference: bool,
+    mutable: bool,
+    pointee: String,
+}
+
+// Parses the type of a parameter, None if it is not a reference or a raw
+// pointer.
+fn pointer_param(ty: &[Tree]) -> Option<PointerParam> {
+    let reference = is_punct(ty.first(), "&");
+    if !reference && !is_punct(ty.first(), "*") {
+        return None;
+    }
+    let mut i = 1;
+    if matches!(ty.get(i), Some(Tree::Ident(lifetime)) if lifetime.starts_with('\'')) {
+        i += 1;
+    }
+    let mutable = is_ident(ty.get(i), "mut");
+    if mutable || is_ident(ty.get(i), "const") {
+        i += 1;
+    }
+    Some(PointerParam { reference, mutable, pointee: print(&ty[i..]) })
+}
+
+// Enumerates the aliasing configurations of the pointer parameters: the
+// source of every parameter is either itself, for a fresh allocation, or an
+// earlier parameter of the same pointee type whose source is itself.
+fn aliasing_configurations(params: &[Option<PointerParam>]) -> Vec<Vec<usize>> {
+    let mut configurations = vec![Vec::new()];
+    for (i, param) in params.iter().enumerate() {
+        let mut next = Vec::new();
+        for sources in configurations {
+            let mut choices = vec![i];
+            if let Some(param) = param {
+                choices.extend((0..i).filter(|&j| sources[j] == j && matches!(&params[j],
+                    Some(other) if other.pointee == param.pointee)));
+            }
+            for source in choices {
+                let mut sources = sources.clone();
+                sources.push(source);
+                next.push(sources);
+            }
+        }
+        configurations = next;
+    }
+    configurations
+}
+
+/// Generates the instrumented source of a `#[kani::proof]` harness for a
+/// function item. Every argument is a fresh `kani::any()` value, except that
+/// a reference or pointer argument may nondeterministically point to the
+/// same allocation as an earlier argument of the same pointee type. One
+/// proof covers every aliasing configuration. Only the locals pointer
+/// arguments are derived from get stacks, and only pointer arguments pass
+/// their tag to the function.
+pub fn aliasing_harness(item: &[Tree]) -> Result<String, String> {
+    let name = function_name(item).ok_or("expected a function")?;
+    let fn_index = item.iter().position(|t| is_ident(Some(t), "fn")).unwrap();
+    // lifetime parameters are fine, type and const parameters are not
+    let mut params_index = fn_index + 2;
+    if is_punct(item.get(params_index), "<") {
+        while !is_punct(item.get(params_index), ">") && params_index < item.len() {
+            if matches!(&item[params_index], Tree::Ident(i) if !i.starts_with('\'')) {
+                return Err(format!("cannot generate a harness for the generic function {}", name));
+            }
+            params_index += 1;
+        }
+        params_index += 1;
+    }
+    let params = match item.get(params_index) {
+        Some(Tree::Group('(', params)) => split_top_level(params, ","),
+        _ => return Err(format!("expected the parameters of {}", name)),
+    };
+    if params.len() > MAX_CALL_ARGS {
+        return Err(format!("{} has more than {} parameters", name, MAX_CALL_ARGS));
+    }
+    let mut pointers = Vec::new();
+    for param in &params {
+        let colon = param.iter().position(|t| is_punct(Some(t), ":"))
+            .ok_or_else(|| format!("cannot generate a harness for the method {}", name))?;
+        let ty = &param[colon + 1..];
+        pointers.push(pointer_param(ty).map(Ok).unwrap_or_else(|| Err(print(ty))));
+    }
+
+    let mut lines = vec!["#[kani::proof]".to_string(), "#[allow(unused_unsafe)]".to_string()];
+    lines.push(format!("fn {}__harness() {{", name));
+    for (i, pointer) in pointers.iter().enumerate() {
+        match pointer {
+            Ok(pointer) => {
+                lines.push(format!("    new_local!(let mut local{}: {} = kani::any());", i, pointer.pointee));
+                lines.push(format!("    raw_from_local!(let raw{} = &mut local{} as *mut {});", i, i, pointer.pointee));
+            }
+            Err(ty) => lines.push(format!("    let arg{}: {} = kani::any();", i, ty)),
+        }
+    }
+    let pointers: Vec<Option<PointerParam>> = pointers.into_iter().map(Result::ok).collect();
+    let configurations = aliasing_configurations(&pointers);
+    // the reborrows of the arguments, their tags and the call
+    let call = |sources: &Vec<usize>, pad: &str| {
+        let mut statements = Vec::new();
+        let mut passes = Vec::new();
+        let mut args = Vec::new();
+        for (i, (pointer, source)) in pointers.iter().zip(sources).enumerate() {
+            let reborrow = match pointer {
+                Some(PointerParam { reference: true, mutable: true, .. }) => Some(("unique_from_ref", "&mut *")),
+                Some(PointerParam { reference: true, mutable: false, .. }) => Some(("shared_from_ref", "&*")),
+                _ => None,
+            };
+            let arg = match (pointer, reborrow) {
+                (Some(_), Some((form, borrow))) => {
+                    let arg = format!("{}__arg{}", name, i);
+                    statements.push(format!("{}    {}!(let {} = {}raw{});", pad, form, arg, borrow, source));
+                    arg
+                }
+                (Some(_), None) => format!("raw{}", source),
+                (None, _) => format!("arg{}", i),
+            };
+            if pointer.is_some() {
+                passes.push(format!("{}    pass_arg!({}, {});", pad, i, arg));
+            }
+            args.push(arg);
+        }
+        let mut block = vec![format!("{}unsafe {{", pad)];
+        block.extend(statements);
+        block.extend(passes);
+        block.push(format!("{}    {}({});", pad, name, args.join(", ")));
+        block.push(format!("{}}}", pad));
+        block
+    };
+    if configurations.len() == 1 {
+        lines.extend(call(&configurations[0], "    "));
+    } else {
+        lines.push("    let configuration: u32 = kani::any();".to_string());
+        for (n, sources) in configurations.iter().enumerate() {
+            let condition = if n == 0 {
+                format!("    if configuration == {} {{", n)
+            } else if n + 1 < configurations.len() {
+                format!("    }} else if configuration == {} {{", n)
+            } else {
+                "    } else {".to_string()
+            };
+            lines.push(condition);
+            lines.extend(call(sources, "        "));
+        }
+        lines.push("    }".to_string());
+    }
+    lines.push("}".to_string());
+    Ok(lines.join("\n"))
+}
+
+////// instrumentation //////
+
+/// Instruments the source of a single function item.
+pub fn instrument_function(source: &str) -> Result<String, String> {
+    let trees = lex(source)?;
+    let lines = instrument_item(&trees)?;
+    Ok(lines.join("\n"))
+}
+
+/// Instruments a function item given as token trees and returns the lines
+/// of its source.
+pub fn instrument_item(trees: &[Tree]) -> Result<Vec<String>, String> {
+    let fn_index = trees.iter().position(|t| is_ident(Some(t), "fn"))
+        .ok_or("expected a function")?;
+    let name = ident_name(trees.get(fn_index + 1)).ok_or("expected a function name")?;
+    let params = trees[fn_index..].iter().find_map(|t| match t {
+        Tree::Group('(', params) => Some(params),
+        _ => None,
+    }).ok_or("expected function parameters")?;
+    let body = match trees.last() {
+        Some(Tree::Group('{', body)) => body,
+        _ => return Err(format!("expected the body of {}", name)),
+    };
+    let arrow = trees.iter().position(|t| is_punct(Some(t), "->"));
+    let returns_pointer = arrow.is_some_and(|i| is_punct(trees.get(i + 1), "&") || is_punct(trees.get(i + 1), "*"));
+
+    let mut instrumenter = Instrumenter { function: name.to_string(), returns_pointer, callees: Vec::new(), variables: Vec::new() };
+    let mut body_lines = Vec::new();
+    let mut retags = Vec::new();
+    let mut references = Vec::new();
+    for (index, param) in split_top_level(params, ",").iter().enumerate() {
+        // x: &mut T, x: &T, x: *mut T, x: *const T
+        let mut param = &param[..];
+        let mutable = is_ident(param.first(), "mut");
+        if mutable {
+            param = &param[1..];
+        }
+        if let (Some(param_name), true) = (ident_name(param.first()), is_punct(param.get(1), ":")) {
+            if is_punct(param.get(2), "&") || is_punct(param.get(2), "*") {
+                body_lines.push(format!("    bind_arg!({}, {});", index, param_name));
+                instrumenter.track(param_name);
+            }
+            if let Some(PointerParam { reference: true, mutable: unique, .. }) = pointer_param(&param[2..]) {
+                let binding = if mutable { format!("mut {}", param_name) } else { param_name.to_string() };
+                let borrow = if unique { "&mut *" } else { "&*" };
+                retags.push(format!("    fn_entry!(let {} = {}{});", binding, borrow, param_name));
+                references.push(param_name.to_string());
+            }
+        }
+    }
+    body_lines.extend(retags);
+    if !references.is_empty() {
+        body_lines.push(format!("    let _{}__protected = protect!({});", name.trim_start_matches("r#"), references.join(", ")));
+    }
+    body_lines.extend(instrumenter.block(body, 1, true));
+
+    // attributes on their own lines
+    let mut lines = Vec::new();
+    let mut start = 0;
+    while is_punct(trees.get(start), "#") && matches!(trees.get(start + 1), Some(Tree::Group('[', _))) {
+        lines.push(print(&trees[start..start + 2]));
+        start += 2;
+    }
+    lines.push(format!("{} {{", print(&trees[start..trees.len() - 1])));
+    lines.extend(body_lines);
+    lines.push("}".to_string());
+    Ok(lines)
+}
+
+// Splits trees on the given top level separator, ignoring separators inside
+// generic arguments.
+fn split_top_level(trees: &[Tree], separator: &str) -> Vec<Vec<Tree>> {
+    let mut parts = vec![Vec::new()];
+    let mut angles = 0;
+    for tree in trees {
+        if is_punct(Some(tree), "<") {
+            angles += 1;
+        } else if is_punct(Some(tree), ">") && angles > 0 {
+            angles -= 1;
+        } else if is_punct(Some(tree), ">>") && angles > 0 {
+            angles = if angles > 1 { angles - 2 } else { 0 };
+        }
+        if angles == 0 && is_punct(Some(tree), separator) {
+            parts.push(Vec::new());
+        } else {
+            parts.last_mut().unwrap().push(tree.clone());
+        }
+    }
+    if parts.last().unwrap().is_empty() {
+        parts.pop();
+    }
+    parts
+}
+
+// Splits the contents of a block into statements. The boolean is true when
+// the statement was terminated by a semicolon.
+fn split_statements(body: &[Tree]) -> Vec<(Vec<Tree>, bool)> {
+    let mut statements = Vec::new();
+    let mut current: Vec<Tree> = Vec::new();
+    for (i, tree) in body.iter().enumerate() {
+        if is_punct(Some(tree), ";") {
+            statements.push((std::mem::take(&mut current), true));
+            continue;
+        }
+        current.push(tree.clone());
+        // a block statement ends with its last block unless followed by
+        // `else` or used in an expression
+        if is_block_statement(&current) && matches!(tree, Tree::Group('{', _))
+            && !is_ident(body.get(i + 1), "else") && !is_punct(body.get(i + 1), ".")
+            && !is_punct(body.get(i + 1), "?") && !is_punct(body.get(i + 1), ";") {
+            statements.push((std::mem::take(&mut current), false));
+        }
+    }
+    if !current.is_empty() {
+        statements.push((current, false));
+    }
+    statements
+}
+
+fn is_block_statement(statement: &[Tree]) -> bool {
+    match statement.first() {
+        Some(Tree::Ident(keyword)) => ["if", "while", "loop", "for", "unsafe", "match"].contains(&keyword.as_str()),
+        Some(Tree::Group('{', _)) => true,
+        _ => false,
+    }
+}
+
+// Returns the name and whether the binding is mutable for
+// `let [mut] name [: T] = init`, None for any other let statement.
+fn let_binding(statement: &[Tree]) -> Option<(String, bool)> {
+    let mut i = 1;
+    let mutable = is_ident(statement.get(i), "mut");
+    if mutable {
+        i += 1;
+    }
+    let name = ident_name(statement.get(i))?;
+    let typed_or_initialised = is_punct(statement.get(i + 1), "=") || is_punct(statement.get(i + 1), ":");
+    if !typed_or_initialised || !statement.iter().any(|t| is_punct(Some(t), "=")) {
+        return None;
+    }
+    Some((name.to_string(), mutable))
+}
+
+// Classifies an expression creating a reference or a raw pointer and returns
+// the macro instrumenting it.
+fn reference_form(init: &[Tree]) -> Option<&'static str> {
+    let get = |i: usize| init.get(i);
+    match init.len() {
+        // &a
+        2 if is_punct(get(0), "&") && ident_name(get(1)).is_some() => Some("shared_from_local"),
+        // &mut a, &*p
+        3 if is_punct(get(0), "&") && is_ident(get(1), "mut") && ident_name(get(2)).is_some() =>
+            Some("unique_from_local"),
+        3 if is_punct(get(0), "&") && is_punct(get(1), "*") && ident_name(get(2)).is_some() =>
+            Some("shared_from_ref"),
+        // &mut *p
+        4 if is_punct(get(0), "&") && is_ident(get(1), "mut") && is_punct(get(2), "*")
+            && ident_name(get(3)).is_some() => Some("unique_from_ref"),
+        // unsafe { &mut *p }, unsafe { &*p }
+        2 if is_ident(get(0), "unsafe") => match get(1) {
+            Some(Tree::Group('{', block)) => match reference_form(block) {
+                Some("unique_from_ref") => Some("unique_from_ref"),
+                Some("shared_from_ref") => Some("shared_from_ref"),
+                _ => None,
+            },
+            _ => None,
+        },
+        // &mut a as *mut T
+        n if n >= 7 && is_punct(get(0), "&") && is_ident(get(1), "mut") && ident_name(get(2)).is_some()
+            && is_ident(get(3), "as") && is_punct(get(4), "*") && is_ident(get(5), "mut") =>
+            Some("raw_from_local"),
+        // r as *mut T
+        n if n >= 5 && ident_name(get(0)).is_some() && is_ident(get(1), "as") && is_punct(get(2), "*")
+            && is_ident(get(3), "mut") => Some("raw_from_ref"),
+        _ => None,
+    }
+}
+
+// Whether the initializer of a let statement is a call of a function,
+// possibly in an unsafe block, whose result may carry the tag it returned.
+fn is_call(init: &[Tree]) -> bool {
+    let n = init.len();
+    match init {
+        [unsafe_keyword, Tree::Group('{', block)] if is_ident(Some(unsafe_keyword), "unsafe") => is_call(block),
+        [.., callee, Tree::Group('(', _)] => {
+            ident_name(Some(callee)).is_some_and(|c| c.starts_with(|c: char| c.is_lowercase()))
+                && !(n > 2 && is_punct(init.get(n - 3), "."))
+        }
+        _ => false,
+    }
+}
+
+// Returns the variables read through by prefix `*` operators, skipping
+// reborrows `&*p` and `&mut *p`.
+fn deref_reads(trees: &[Tree]) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    for i in 0..trees.len() {
+        match &trees[i] {
+            Tree::Group(_, contents) => {
+                for name in deref_reads(contents) {
+                    if !names.contains(&name) {
+                        names.push(name);
+                    }
+                }
+            }
+            Tree::Punct(p) if p == "*" && is_prefix_position(trees, i) => {
+                let reborrow = is_punct(i.checked_sub(1).and_then(|j| trees.get(j)), "&")
+                    || (is_ident(i.checked_sub(1).and_then(|j| trees.get(j)), "mut")
+                        && is_punct(i.checked_sub(2).and_then(|j| trees.get(j)), "&"));
+                if let Some(name) = ident_name(trees.get(i + 1)) {
+                    if !reborrow && !names.iter().any(|n| n == name) {
+                        names.push(name.to_string());
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    names
+}
+
+struct Instrumenter {
+    // name of the instrumented function
+    function: String,
+    // whether it returns a reference or a raw pointer, whose tag is then
+    // passed back to the caller
+    returns_pointer: bool,
+    // callee of every call site passing tags so far
+    callees: Vec<String>,
+    // variables whose tag the model knows: locals and pointers
+    variables: Vec<String>,
+}
+
+impl Instrumenter {
+    fn track(&mut self, variable: &str) {
+        self.variables.push(unmarked(variable).to_string());
+    }
+
+    fn tracked(&self, variable: &str) -> bool {
+        self.variables.iter().any(|v| v == unmarked(variable))
+    }
+
+    // Instruments the statements of a block. When tail is set the value of
+    // the block is the value returned by the function.
+    fn block(&mut self, body: &[Tree], depth: usize, tail: bool) -> Vec<String> {
+        let mut lines = Vec::new();
+        let statements = split_statements(body);
+        let count = statements.len();
+        for (i, (statement, semicolon)) in statements.into_iter().enumerate() {
+            let returned = tail && i + 1 == count && !semicolon;
+            lines.extend(self.statement(statement, semicolon, depth, returned));
+        }
+        lines
+    }
+
+    fn statement(&mut self, mut statement: Vec<Tree>, semicolon: bool, depth: usize, returned: bool) -> Vec<String> {
+        let pad = "    ".repeat(depth);
+        let end = if semicolon { ";" } else { "" };
+        let mut before = Vec::new();
+        let mut after = Vec::new();
+
+        if is_block_statement(&statement) && !is_ident(statement.first(), "match") {
+            // the blocks of if and unsafe statements give their value to the
+            // statement, not those of loops
+            let returned = returned && (is_ident(statement.first(), "if") || is_ident(statement.first(), "unsafe")
+                || matches!(statement.first(), Some(Tree::Group('{', _))));
+            // if/while/loop/for/unsafe: instrument the blocks, check the
+            // reads of the conditions before the statement
+            let mut lines = Vec::new();
+            let mut header: Vec<Tree> = Vec::new();
+            let mut first = true;
+            for tree in statement {
+                match tree {
+                    Tree::Group('{', block) => {
+                        for name in deref_reads(&header) {
+                            before.push(format!("{}read1!({});", pad, name));
+                        }
+                        self.thread_calls(&mut header, false);
+                        let prefix = if first { pad.clone() } else { format!("{}}} ", pad) };
+                        let text = print(&header);
+                        if text.is_empty() {
+                            lines.push(format!("{}{{", prefix));
+                        } else {
+                            lines.push(format!("{}{} {{", prefix, text));
+                        }
+                        lines.extend(self.block(&block, depth + 1, returned));
+                        header.clear();
+                        first = false;
+                    }
+                    tree => header.push(tree),
+                }
+            }
+            lines.push(format!("{}}}{}", pad, end));
+            before.extend(lines);
+            return before;
+        }
+
+        let binding = if is_ident(statement.first(), "let") { let_binding(&statement) } else { None };
+        if let Some((name, mutable)) = &binding {
+            let equals = statement.iter().position(|t| is_punct(Some(t), "=")).unwrap();
+            let init = &statement[equals + 1..];
+            // the macros take neither `mut` nor a type annotation
+            if let (Some(form), false, 2) = (reference_form(init), mutable, equals) {
+                self.track(name);
+                return vec![format!("{}{}!({});", pad, form, print(&statement))];
+            }
+            // let x = *p; carries the tag of a loaded pointer
+            if init.len() == 2 && is_punct(init.first(), "*") && ident_name(init.get(1)).is_some() {
+                self.track(name);
+                return vec![format!("{}load!({});", pad, print(&statement))];
+            }
+            // let x = y; copies the tag of the pointer y, or reads the local y
+            if let (Some(source), 1) = (ident_name(init.first()), init.len()) {
+                if self.tracked(source) {
+                    self.track(name);
+                    return vec![format!("{}copy!({});", pad, print(&statement))];
+                }
+            }
+        }
+
+        // *p = v; stores the tag of a pointer value
+        if statement.len() == 4 && is_punct(statement.first(), "*") && ident_name(statement.get(1)).is_some()
+            && is_punct(statement.get(2), "=") && ident_name(statement.get(3)).is_some() {
+            return vec![format!("{}store!({});", pad, print(&statement))];
+        }
+
+        // return p; and the tail expression p pass the tag of p to the caller
+        let return_keyword = is_ident(statement.first(), "return");
+        if self.returns_pointer && (returned || return_keyword) {
+            let value = if return_keyword { &statement[1..] } else { &statement[..] };
+            let keyword = if return_keyword { "return " } else { "" };
+            if let (Some(name), 1) = (ident_name(value.first()), value.len()) {
+                return vec![format!("{}pass_return!({});", pad, name), format!("{}{}{}", pad, print(&statement), end)];
+            }
+            if let Some(form) = reference_form(value) {
+                let result = format!("{}__return", self.function);
+                return vec![
+                    format!("{}{}!(let {} = {});", pad, form, result, print(value)),
+                    format!("{}pass_return!({});", pad, result),
+                    format!("{}{}{}{}", pad, keyword, result, end),
+                ];
+            }
+        }
+
+        // *p = e;
+        let mut reads_from = 0;
+        if is_punct(statement.first(), "*") && statement.len() > 3 {
+            if let (Some(name), Some(Tree::Punct(op))) = (ident_name(statement.get(1)), statement.get(2)) {
+                if ASSIGNMENTS.contains(&op.as_str()) {
+                    if op != "=" {
+                        before.push(format!("{}read1!({});", pad, name));
+                    }
+                    after.push(format!("{}use2!({});", pad, name));
+                    reads_from = 3;
+                }
+            }
+        }
+        // a = e; to a local, a = b; between variables copies a pointer
+        if let (Some(name), Some(Tree::Punct(op))) = (ident_name(statement.first()), statement.get(1)) {
+            if ASSIGNMENTS.contains(&op.as_str()) && self.tracked(name) {
+                let source = ident_name(statement.get(2)).filter(|source| self.tracked(source));
+                if let (Some(_), 3, "=") = (source, statement.len(), op.as_str()) {
+                    return vec![format!("{}copy!({});", pad, print(&statement))];
+                }
+                if op != "=" {
+                    before.push(format!("{}read1_local!({});", pad, name));
+                }
+                after.push(format!("{}use2_local!({});", pad, name));
+                reads_from = 2;
+            }
+        }
+        for name in deref_reads(&statement[reads_from..]) {
+            before.push(format!("{}read1!({});", pad, name));
+        }
+
+        // locals get their own stacks, pointers returned by calls then get
+        // the tag passed by the callee
+        let borrows = statement.iter().any(|t| is_punct(Some(t), "&") || is_punct(Some(t), "&&"));
+        let equals = statement.iter().position(|t| is_punct(Some(t), "="));
+        let returns_call = equals.is_some_and(|equals| is_call(&statement[equals + 1..]));
+        self.thread_calls(&mut statement, true);
+        let mut text = print(&statement);
+        if let (Some((name, _)), false) = (&binding, borrows) {
+            text = format!("new_local!({})", text);
+            self.track(name);
+            if returns_call {
+                after.insert(0, format!("{}bind_return!({});", pad, name));
+            }
+        }
+        before.push(format!("{}{}{}", pad, text, end));
+        before.extend(after);
+        before
+    }
+
+    // Threads provenance through the calls in trees. A call passing tags is
+    // replaced by a block which evaluates its arguments in order, binding
+    // each reference created in them and each other expression with side
+    // effects to a variable of its own, then passes the tag of every
+    // variable argument right before the call:
+    //
+    //   g(&mut *p, h(q), r)   { unique_from_ref!(let g__arg0 = &mut *p);
+    //                           let g__arg1 = { pass_arg!(0, q); h(q) }; bind_return!(g__arg1);
+    //                           pass_arg!(0, g__arg0); pass_arg!(1, g__arg1); pass_arg!(2, r);
+    //                           g(g__arg0, g__arg1, r) }
+    //
+    // Nested and sibling calls thus pass their tags in the order they are
+    // called, and the conditions of if and while and the operands of && and
+    // || keep their evaluation order. Later calls of g in the function bind
+    // g__1__arg0, g__2__arg0 and so on, and each block scopes the variables
+    // it binds. block is set when trees are statements, where a call starting
+    // a statement is parenthesized when an operator follows it.
+    fn thread_calls(&mut self, trees: &mut Vec<Tree>, block: bool) {
+        let mut i = 0;
+        while i < trees.len() {
+            let method = i > 1 && (is_punct(trees.get(i - 2), ".") || is_ident(trees.get(i - 2), "fn"));
+            let callee = i.checked_sub(1).and_then(|j| ident_name(trees.get(j))).map(|s| s.to_string())
+                .filter(|c| !method && c.starts_with(|c: char| c.is_lowercase()));
+            let mut call = None;
+            if let Tree::Group(delimiter, contents) = &mut trees[i] {
+                let results: Vec<bool> = split_top_level(contents, ",").iter().map(|arg| is_call(arg)).collect();
+                self.thread_calls(contents, *delimiter == '{');
+                if let (Some(callee), '(') = (callee, *delimiter) {
+                    call = self.call_block(&callee, contents, &results);
+                }
+            }
+            if let Some(call) = call {
+                let start = path_start(trees, i - 1);
+                let mut replaced: Vec<Tree> = trees[start..i].to_vec();
+                replaced.push(trees[i].clone());
+                let mut contents = call;
+                contents.extend(replaced);
+                let mut call = Tree::Group('{', contents);
+                let statement_start = block && (start == 0 || is_punct(trees.get(start - 1), ";"));
+                if statement_start && i + 1 < trees.len() && !is_punct(trees.get(i + 1), ";") {
+                    call = Tree::Group('(', vec![call]);
+                }
+                trees.splice(start..=i, [call]);
+                i = start;
+            }
+            i += 1;
+        }
+    }
+
+    // Statements of the block replacing a call of callee, see thread_calls,
+    // before the call itself, whose arguments become the bound variables.
+    // results tells which arguments are calls, whose value may carry the tag
+    // their callee returned. None when the call passes no tag.
+    fn call_block(&mut self, callee: &str, args: &mut Vec<Tree>, results: &[bool]) -> Option<Vec<Tree>> {
+        let site = self.callees.iter().filter(|c| *c == unmarked(callee)).count();
+        let prefix = if site == 0 { callee.to_string() } else { format!("{}__{}", callee, site) };
+        let mut statements = Vec::new();
+        let mut passes = Vec::new();
+        let mut new_args = Vec::new();
+        for (index, arg) in split_top_level(args, ",").into_iter().enumerate() {
+            if !new_args.is_empty() {
+                new_args.push(Tree::Punct(",".to_string()));
+            }
+            // variables, literals and closures are evaluated in place
+            let closure = is_punct(arg.first(), "|") || is_punct(arg.first(), "||") || is_ident(arg.first(), "move");
+            let name = match (ident_name(arg.first()), &arg[..]) {
+                (Some(name), [_]) => name.to_string(),
+                (None, [Tree::Literal(_)]) => {
+                    new_args.extend(arg);
+                    continue;
+                }
+                _ if closure => {
+                    new_args.extend(arg);
+                    continue;
+                }
+                _ => {
+                    let name = format!("{}__arg{}", prefix, index);
+                    let mut binding = vec![Tree::Ident("let".to_string()), Tree::Ident(name.clone()), Tree::Punct("=".to_string())];
+                    match reference_form(&arg) {
+                        Some(form) => {
+                            binding.extend(arg);
+                            statements.extend(macro_statement(form, binding));
+                        }
+                        None => {
+                            statements.extend(binding);
+                            statements.extend(arg);
+                            statements.push(Tree::Punct(";".to_string()));
+                            if results[index] {
+                                statements.extend(macro_statement("bind_return", vec![Tree::Ident(name.clone())]));
+                            }
+                        }
+                    }
+                    name
+                }
+            };
+            if index < MAX_CALL_ARGS {
+                let pass = vec![Tree::Literal(index.to_string()), Tree::Punct(",".to_string()), Tree::Ident(name.clone())];
+                passes.extend(macro_statement("pass_arg", pass));
+            }
+            new_args.push(Tree::Ident(name));
+        }
+        if passes.is_empty() {
+            return None;
+        }
+        self.callees.push(unmarked(callee).to_string());
+        *args = new_args;
+        statements.extend(passes);
+        Some(statements)
+    }
+}
+
+// `name!(tokens);`
+fn macro_statement(name: &str, tokens: Vec<Tree>) -> Vec<Tree> {
+    vec![Tree::Ident(name.to_string()), Tree::Punct("!".to_string()), Tree::Group('(', tokens), Tree::Punct(";".to_string())]
+}
+
+// Index of the first token of the path ending with the identifier at i,
+// e.g. of std in std::ptr::null_mut.
+fn path_start(trees: &[Tree], mut i: usize) -> usize {
+    while i >= 2 && is_punct(trees.get(i - 1), "::") && ident_name(trees.get(i - 2)).is_some() {
+        i -= 2;
+    }
+    if i >= 1 && is_punct(trees.get(i - 1), "::") {
+        i -= 1;
+    }
+    i
+}
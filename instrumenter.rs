@@ -0,0 +1,176 @@
+// Copyright Jacob Salzberg
+// SPDX-License-Identifier: Apache-2.0
+
+// Standalone counterpart of the `#[stacked_borrows::instrument]` attribute.
+// Reads a Rust source file such as original.rs and writes a self-contained
+// harness in the style of test.rs: the ghost state and model functions of
+// test.rs, or of test_demonic.rs with --demonic, followed by the items of the
+// source file with every function instrumented as described in
+// instrument.rs. Other items are copied on a single line.
+//
+//   rustc --edition 2021 instrumenter.rs
+//   ./instrumenter [--demonic [--monitors K]] [--harness NAME | --generate NAME] original.rs [-o output.rs]
+//
+// The harness function, main by default, is marked #[kani::proof] unless it
+// already is. With --generate, a harness NAME__harness calling the function
+// NAME under every aliasing of its pointer arguments is generated instead.
+// --monitors sets the number of locations the demonic model monitors, 1 by
+// default. The generated file declares the same modules as test.rs, so it
+// has to live next to shadow_map.rs, tag_map.rs and macros.rs.
+
+#[path = "instrument.rs"]
+mod instrument;
+
+use instrument::{aliasing_harness, function_name, instrument_item, lex, print, split_items, Tree};
+use std::process::exit;
+
+const FULL_MODEL: &str = include_str!("test.rs");
+const DEMONIC_MODEL: &str = include_str!("test_demonic.rs");
+
+// line of the models after which their own tests start
+const END_OF_MODEL: &str = "////// end of model //////";
+
+// declaration of the number of monitors in the demonic model
+const MONITORS: &str = "const MONITORS: usize = ";
+
+struct Options {
+    demonic: bool,
+    monitors: Option<usize>,
+    harness: String,
+    generate: Option<String>,
+    input: String,
+    output: Option<String>,
+}
+
+fn usage() -> ! {
+    eprintln!("usage: instrumenter [--demonic [--monitors K]] [--harness NAME | --generate NAME] INPUT.rs [-o OUTPUT.rs]");
+    exit(2);
+}
+
+fn parse_options() -> Options {
+    let mut demonic = false;
+    let mut monitors = None;
+    let mut harness = "main".to_string();
+    let mut generate = None;
+    let mut input = None;
+    let mut output = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--demonic" => demonic = true,
+            "--monitors" => {
+                let k = args.next().and_then(|k| k.parse().ok()).unwrap_or_else(|| usage());
+                monitors = Some(k);
+            }
+            "--harness" => harness = args.next().unwrap_or_else(|| usage()),
+            "--generate" => generate = Some(args.next().unwrap_or_else(|| usage())),
+            "-o" => output = Some(args.next().unwrap_or_else(|| usage())),
+            _ if arg.starts_with('-') || input.is_some() => usage(),
+            _ => input = Some(arg),
+        }
+    }
+    let input = input.unwrap_or_else(|| usage());
+    if monitors.is_some() && !demonic {
+        usage();
+    }
+    Options { demonic, monitors, harness, generate, input, output }
+}
+
+// Returns the copyright header of a model and its ghost state and model
+// functions, without the leading comments and the tests.
+fn split_model(model: &str) -> (Vec<&str>, Vec<&str>) {
+    let lines: Vec<&str> = model.lines().collect();
+    let header_end = lines.iter().position(|l| l.is_empty()).unwrap_or(0);
+    let start = lines.iter().position(|l| !l.is_empty() && !l.starts_with("//")).unwrap_or(0);
+    let end = lines.iter().position(|l| *l == END_OF_MODEL).unwrap_or(lines.len());
+    let mut body = lines[start..end].to_vec();
+    while body.last().is_some_and(|l| l.is_empty()) {
+        body.pop();
+    }
+    (lines[..header_end].to_vec(), body)
+}
+
+fn instrument_file(source: &str, options: &Options) -> Result<String, String> {
+    let (model_name, model) = if options.demonic {
+        ("test_demonic.rs", DEMONIC_MODEL)
+    } else {
+        ("test.rs", FULL_MODEL)
+    };
+    let (header, model) = split_model(model);
+    let monitors = options.monitors.map(|k| format!("{}{};", MONITORS, k));
+    let model: Vec<&str> = model
+        .into_iter()
+        .map(|l| match &monitors {
+            Some(declaration) if l.starts_with(MONITORS) => declaration.as_str(),
+            _ => l,
+        })
+        .collect();
+
+    let mut inner_attributes = Vec::new();
+    let mut items = Vec::new();
+    let mut harness_found = false;
+    let mut generated = None;
+    for item in split_items(&lex(source)?) {
+        if matches!(item.first(), Some(Tree::Punct(p)) if p == "#!") {
+            let attribute = print(&item);
+            if !model.contains(&attribute.as_str()) {
+                inner_attributes.push(attribute);
+            }
+            continue;
+        }
+        let name = function_name(&item).map(|name| name.to_string());
+        let mut lines = match name {
+            Some(_) => instrument_item(&item)?,
+            None => vec![print(&item)],
+        };
+        if name.is_some() && name == options.generate {
+            generated = Some(aliasing_harness(&item)?);
+        } else if name.as_deref() == Some(options.harness.as_str()) && options.generate.is_none() {
+            harness_found = true;
+            if !lines.iter().any(|l| l == "#[kani::proof]") {
+                lines.insert(0, "#[kani::proof]".to_string());
+            }
+        }
+        items.push(lines.join("\n"));
+    }
+    match (&options.generate, generated) {
+        (Some(function), None) => return Err(format!("no function {} to generate a harness for", function)),
+        (Some(_), Some(harness)) => items.push(harness),
+        (None, _) if !harness_found => return Err(format!("no function {} to use as harness", options.harness)),
+        (None, _) => {}
+    }
+
+    let mut out = Vec::new();
+    out.extend(header.iter().map(|l| l.to_string()));
+    out.push(String::new());
+    out.push(format!("// Generated by instrumenter.rs from {} with the model of {}.", options.input, model_name));
+    out.push("// Do not edit, regenerate it instead.".to_string());
+    out.push(String::new());
+    out.extend(inner_attributes);
+    out.extend(model.iter().map(|l| l.to_string()));
+    for item in items {
+        out.push(String::new());
+        out.push(item);
+    }
+    out.push(String::new());
+    Ok(out.join("\n"))
+}
+
+fn main() {
+    let options = parse_options();
+    let source = std::fs::read_to_string(&options.input).unwrap_or_else(|e| {
+        eprintln!("instrumenter: cannot read {}: {}", options.input, e);
+        exit(1);
+    });
+    let instrumented = instrument_file(&source, &options).unwrap_or_else(|e| {
+        eprintln!("instrumenter: {}: {}", options.input, e);
+        exit(1);
+    });
+    match &options.output {
+        Some(output) => std::fs::write(output, instrumented).unwrap_or_else(|e| {
+            eprintln!("instrumenter: cannot write {}: {}", output, e);
+            exit(1);
+        }),
+        None => print!("{}", instrumented),
+    }
+}
@@ -0,0 +1,413 @@
+// Copyright Jacob Salzberg
+// SPDX-License-Identifier: Apache-2.0
+
+// Rust counterpart of the instrumentation macros of stacked_borrows.h.
+//
+// Each macro wraps the statement it instruments and expands to a call of the
+// model function of the same name as in C (`sb_new_local`, `sb_use2`, ...),
+// which every Rust model defines over its own ghost state. The name of the
+// variable is passed along and prefixes the message of a failing check,
+// e.g. "USE2 x_rename: Stack violated at offset 0.".
+//
+// Macros that create a pointer take the address of the variable they borrow
+// from before the borrow, as the borrow checker forbids it afterwards.
+#![allow(unused_macros)]
+
+/// `new_local!(let mut local = 5);`
+macro_rules! new_local {
+    (let mut $local:ident $(: $t:ty)? = $init:expr) => {
+        let mut $local $(: $t)? = $init;
+        sb_new_local(&$local as *const _, concat!("NEW_LOCAL ", stringify!($local), ": "));
+    };
+    (let $local:ident $(: $t:ty)? = $init:expr) => {
+        let $local $(: $t)? = $init;
+        sb_new_local(&$local as *const _, concat!("NEW_LOCAL ", stringify!($local), ": "));
+    };
+}
+
+/// `unique_from_local!(let new_ref = &mut local);`
+macro_rules! unique_from_local {
+    (let $new:ident = &mut $local:ident) => {
+        let local_address = &$local as *const _;
+        let $new = &mut $local;
+        sb_unique_from_local(&$new as *const _, local_address,
+                             concat!("UNIQUE_FROM_LOCAL ", stringify!($new), ": "));
+    };
+}
+
+/// `shared_from_local!(let new_ref = &local);`
+macro_rules! shared_from_local {
+    (let $new:ident = &$local:ident) => {
+        let $new = &$local;
+        sb_shared_from_local(&$new as *const _, &$local as *const _,
+                             concat!("SHARED_RO_FROM_LOCAL ", stringify!($new), ": "));
+    };
+}
+
+/// `unique_from_ref!(let new_ref = &mut *old_ref);`, also accepts
+/// `unsafe { &mut *old_raw }`.
+macro_rules! unique_from_ref {
+    (let $new:ident = &mut *$old:ident) => {
+        let old_slot = &$old as *const _;
+        let $new = &mut *$old;
+        sb_unique_from_ref(&$new as *const _, old_slot,
+                           concat!("UNIQUE_FROM_REF ", stringify!($new), ": "));
+    };
+    (let $new:ident = unsafe { &mut *$old:ident }) => {
+        let old_slot = &$old as *const _;
+        let $new = unsafe { &mut *$old };
+        sb_unique_from_ref(&$new as *const _, old_slot,
+                           concat!("UNIQUE_FROM_REF ", stringify!($new), ": "));
+    };
+}
+
+/// `shared_from_ref!(let new_ref = &*old_ref);`, also accepts
+/// `unsafe { &*old_raw }`.
+macro_rules! shared_from_ref {
+    (let $new:ident = &*$old:ident) => {
+        let $new = &*$old;
+        sb_shared_from_ref(&$new as *const _, &$old as *const _,
+                           concat!("SHARED_RO_FROM_REF ", stringify!($new), ": "));
+    };
+    (let $new:ident = unsafe { &*$old:ident }) => {
+        let $new = unsafe { &*$old };
+        sb_shared_from_ref(&$new as *const _, &$old as *const _,
+                           concat!("SHARED_RO_FROM_REF ", stringify!($new), ": "));
+    };
+}
+
+/// `raw_from_ref!(let new_raw = old_ref as *mut T);`
+macro_rules! raw_from_ref {
+    (let $new:ident = $old:ident as *mut $t:ty) => {
+        let old_slot = &$old as *const _;
+        let $new = $old as *mut $t;
+        sb_raw_from_ref(&$new as *const _, old_slot,
+                        concat!("SHARED_RW_FROM_REF ", stringify!($new), ": "));
+    };
+}
+
+/// `raw_from_local!(let new_raw = &mut local as *mut T);`
+macro_rules! raw_from_local {
+    (let $new:ident = &mut $local:ident as *mut $t:ty) => {
+        let local_address = &$local as *const _;
+        let $new = &mut $local as *mut $t;
+        sb_raw_from_local(&$new as *const _, local_address,
+                          concat!("SHARED_RW_FROM_LOCAL ", stringify!($new), ": "));
+    };
+}
+
+/// `use2!(x);` after a write through the pointer held by x.
+macro_rules! use2 {
+    ($used:ident) => {
+        sb_use2(&$used as *const _, concat!("USE2 ", stringify!($used), ": "))
+    };
+}
+
+/// `read1!(x);` after a read through the pointer held by x.
+macro_rules! read1 {
+    ($used:ident) => {
+        sb_read1(&$used as *const _, concat!("READ1 ", stringify!($used), ": "))
+    };
+}
+
+/// `use2_local!(a);` after a write to the local a, `a = 3;`. Nothing when a
+/// holds a pointer, whose tag is that of its value rather than of a.
+macro_rules! use2_local {
+    ($local:ident) => {
+        if !holds_pointer!($local) {
+            sb_use2_local(&$local as *const _, concat!("USE2_LOCAL ", stringify!($local), ": "));
+        }
+    };
+}
+
+/// `read1_local!(a);` before a read of the local a, `a += 1;`. Nothing when
+/// a holds a pointer.
+macro_rules! read1_local {
+    ($local:ident) => {
+        if !holds_pointer!($local) {
+            sb_read1_local(&$local as *const _, concat!("READ1_LOCAL ", stringify!($local), ": "));
+        }
+    };
+}
+
+/// `copy!(let x = y);` or `copy!(x = y);`. When y holds a pointer, x gets
+/// its tag, so that `*x = 5` goes through the pointer y was. Otherwise it
+/// is a read of the local y and a new local, or a write to the local x.
+macro_rules! copy {
+    (let mut $x:ident $(: $t:ty)? = $y:ident) => {
+        let (source_slot, source_is_pointer) = (&$y as *const _, holds_pointer!($y));
+        let mut $x $(: $t)? = $y;
+        copy!(@let $x, source_slot, source_is_pointer, $y);
+    };
+    (let $x:ident $(: $t:ty)? = $y:ident) => {
+        let (source_slot, source_is_pointer) = (&$y as *const _, holds_pointer!($y));
+        let $x $(: $t)? = $y;
+        copy!(@let $x, source_slot, source_is_pointer, $y);
+    };
+    ($x:ident = $y:ident) => {
+        let (source_slot, source_is_pointer) = (&$y as *const _, holds_pointer!($y));
+        $x = $y;
+        if source_is_pointer {
+            sb_copy_pointer(&$x as *const _, source_slot);
+        } else {
+            sb_read1_local(source_slot, concat!("READ1_LOCAL ", stringify!($y), ": "));
+            sb_use2_local(&$x as *const _, concat!("USE2_LOCAL ", stringify!($x), ": "));
+        }
+    };
+    (@let $x:ident, $source_slot:ident, $source_is_pointer:ident, $y:ident) => {
+        if $source_is_pointer {
+            sb_copy_pointer(&$x as *const _, $source_slot);
+        } else {
+            sb_read1_local($source_slot, concat!("READ1_LOCAL ", stringify!($y), ": "));
+            sb_new_local(&$x as *const _, concat!("NEW_LOCAL ", stringify!($x), ": "));
+        }
+    };
+}
+
+/// `storage_dead!(local);` where the local goes out of scope, as MIR's
+/// StorageDead. Every later use of a tag derived from it fails.
+macro_rules! storage_dead {
+    ($local:ident) => {
+        sb_storage_dead(std::ptr::addr_of!($local), concat!("STORAGE_DEAD ", stringify!($local), ": "))
+    };
+}
+
+/// `storage_live!(local);` where the local comes back into scope, e.g. on
+/// the next iteration of a loop.
+macro_rules! storage_live {
+    ($local:ident) => {
+        sb_storage_live(std::ptr::addr_of!($local), concat!("STORAGE_LIVE ", stringify!($local), ": "))
+    };
+}
+
+// Whether the variable holds a reference or a raw pointer, see Variable in
+// tag_map.rs.
+macro_rules! holds_pointer {
+    ($variable:ident) => {{
+        #[allow(unused_imports)]
+        use tag_map::{PointerVariable, ValueVariable};
+        (&tag_map::Variable(std::ptr::addr_of!($variable))).holds_pointer()
+    }};
+}
+
+/// `store!(*p = v);`, a write through p. When v is a reference or a raw
+/// pointer its tag is stored along with it, so that `load!` gives it back.
+macro_rules! store {
+    (*$used:ident = $value:ident) => {
+        let value_slot = &$value as *const _;
+        let value_is_pointer = holds_pointer!($value);
+        *$used = $value;
+        if value_is_pointer {
+            sb_store_pointer(&$used as *const _, value_slot, concat!("STORE ", stringify!($used), ": "));
+        } else {
+            sb_use2(&$used as *const _, concat!("USE2 ", stringify!($used), ": "));
+        }
+    };
+}
+
+/// `load!(let x = *p);`, a read through p. When x is a reference or a raw
+/// pointer it gets the tag stored with the value by `store!`, otherwise it
+/// is a new local.
+macro_rules! load {
+    (let mut $x:ident $(: $t:ty)? = *$used:ident) => {
+        let mut $x $(: $t)? = *$used;
+        load!(@bind $x, $used);
+    };
+    (let $x:ident $(: $t:ty)? = *$used:ident) => {
+        let $x $(: $t)? = *$used;
+        load!(@bind $x, $used);
+    };
+    (@bind $x:ident, $used:ident) => {
+        if holds_pointer!($x) {
+            sb_load_pointer(&$x as *const _, &$used as *const _, concat!("LOAD ", stringify!($x), ": "));
+        } else {
+            sb_read1(&$used as *const _, concat!("READ1 ", stringify!($used), ": "));
+            sb_new_local(&$x as *const _, concat!("NEW_LOCAL ", stringify!($x), ": "));
+        }
+    };
+}
+
+/// `pass_arg!(0, x);` before a call taking x as argument number 0.
+macro_rules! pass_arg {
+    ($index:expr, $arg:ident) => {
+        sb_pass_arg($index, &$arg as *const _)
+    };
+}
+
+/// `bind_arg!(0, x);` at the start of a function taking x as parameter
+/// number 0.
+macro_rules! bind_arg {
+    ($index:expr, $param:ident) => {
+        sb_bind_arg($index, &$param as *const _)
+    };
+}
+
+/// `bind_return!(r);` after `new_local!(let r = f(..));`: when r is a
+/// reference or a raw pointer it gets the tag f passed with `pass_return!`,
+/// or the stub of a summary, instead of the tag of the local. Calls of
+/// uninstrumented functions pass no tag and r keeps its own.
+macro_rules! bind_return {
+    ($result:ident) => {
+        if holds_pointer!($result) {
+            sb_bind_return(&$result as *const _);
+        }
+    };
+}
+
+/// `pass_return!(r);` before a function returns the pointer held by r.
+macro_rules! pass_return {
+    ($result:ident) => {
+        sb_pass_return(&$result as *const _)
+    };
+}
+
+/// `fn_entry!(let x = &mut *x);` or `fn_entry!(let x = &*x);` after
+/// `bind_arg!(0, x);`: the retag of a reference parameter on function entry,
+/// through a reborrow shadowing the parameter.
+macro_rules! fn_entry {
+    (let mut $param:ident = &mut *$old:ident) => {
+        let old_slot = &$old as *const _;
+        let mut $param = &mut *$old;
+        sb_unique_from_ref(&$param as *const _, old_slot, concat!("FN_ENTRY ", stringify!($param), ": "));
+    };
+    (let $param:ident = &mut *$old:ident) => {
+        let old_slot = &$old as *const _;
+        let $param = &mut *$old;
+        sb_unique_from_ref(&$param as *const _, old_slot, concat!("FN_ENTRY ", stringify!($param), ": "));
+    };
+    (let mut $param:ident = &*$old:ident) => {
+        let mut $param = &*$old;
+        sb_shared_from_ref(&$param as *const _, &$old as *const _, concat!("FN_ENTRY ", stringify!($param), ": "));
+    };
+    (let $param:ident = &*$old:ident) => {
+        let $param = &*$old;
+        sb_shared_from_ref(&$param as *const _, &$old as *const _, concat!("FN_ENTRY ", stringify!($param), ": "));
+    };
+}
+
+/// `let _f__protected = protect!(x, y);` after the retags of function entry:
+/// protects the references x and y until the function returns. The
+/// protectors end when the guard it returns is dropped, on every return path.
+macro_rules! protect {
+    ($($param:ident),*) => {{
+        struct EndProtect(usize);
+        impl Drop for EndProtect {
+            fn drop(&mut self) {
+                sb_end_protect(self.0);
+            }
+        }
+        EndProtect(0 $( + sb_protect(&$param as *const _) )*)
+    }};
+}
+
+/// Declares the borrow effects of a function on its pointer arguments:
+///
+///     summary! {
+///         fn example1(x: &mut i32, y: &mut i32) -> i32;
+///         stub example1__summary;
+///         check example1__check;
+///         reads(); writes(x, y); protects(x, y); returns();
+///     }
+///
+/// Writing through an argument allows reading through it too. `returns()`
+/// returns an arbitrary value, `returns(&mut *x)` and `returns(&*x)` a
+/// reborrow of an argument. Two functions with the signature of example1 are
+/// defined:
+/// - the stub retags and protects the arguments of protects() as on function
+///   entry and applies the other effects without running the body, callers
+///   are checked against the summary with
+///   `#[kani::stub(example1, example1__summary)]`,
+/// - the check runs example1 and fails if it accesses or protects memory
+///   that existed before the call beyond what the summary allows, or returns
+///   a pointer that is not the declared reborrow, so that the body is
+///   checked once against the summary by a harness calling the check.
+macro_rules! summary {
+    (fn $f:ident$(<$($lifetime:lifetime),*>)?($($param:ident: $t:ty),* $(,)?) $(-> $ret:ty)?;
+     stub $stub:ident;
+     check $check:ident;
+     reads($($read:ident),*); writes($($write:ident),*); protects($($protected:ident),*);
+     returns($($result:tt)*);) => {
+        fn $stub$(<$($lifetime),*>)?($($param: $t),*) $(-> $ret)? {
+            let index = 0;
+            $( sb_bind_arg(index, &$param as *const _); let index = index + 1; )*
+            let _ = index;
+            $( sb_fn_entry(&$protected as *const _, concat!("SUMMARY FN_ENTRY ", stringify!($protected), ": ")); )*
+            let _protected = protect!($($protected),*);
+            $( sb_read1(&$read as *const _, concat!("SUMMARY READ ", stringify!($read), ": ")); )*
+            $( sb_use2(&$write as *const _, concat!("SUMMARY WRITE ", stringify!($write), ": ")); )*
+            summary_result!($($result)*)
+        }
+
+        fn $check$(<$($lifetime),*>)?($($param: $t),*) $(-> $ret)? {
+            let index = 0;
+            $( sb_bind_arg(index, &$param as *const _); let index = index + 1; )*
+            let _ = index;
+            sb_begin_summary_check();
+            $( sb_summary_arg(&$read as *const _, false); )*
+            $( sb_summary_arg(&$write as *const _, true); )*
+            $( sb_summary_protect(&$protected as *const _); )*
+            let returned_arg = summary_returned_arg!($($result)*);
+            let index = 0;
+            $( sb_pass_arg(index, &$param as *const _); let index = index + 1; )*
+            let _ = index;
+            let result = $f($($param),*);
+            summary_check_result!(result, returned_arg, $($result)*);
+            sb_end_summary_check();
+            result
+        }
+    };
+}
+
+// Variable holding the argument the result of a summary is reborrowed from,
+// taken before the call reborrows it.
+macro_rules! summary_returned_arg {
+    () => {
+        ()
+    };
+    (&mut *$arg:ident) => {
+        &$arg as *const _
+    };
+    (&*$arg:ident) => {
+        &$arg as *const _
+    };
+}
+
+// Checks the tag the body returned its result with against the returns() of
+// its summary, and passes it on to the caller of the check.
+macro_rules! summary_check_result {
+    ($result:ident, $returned_arg:ident,) => {
+        let _ = $returned_arg;
+    };
+    ($result:ident, $returned_arg:ident, &mut *$arg:ident) => {
+        sb_bind_return(&$result as *const _);
+        sb_check_summary_return(&$result as *const _, $returned_arg, true,
+                                concat!("SUMMARY RETURN ", stringify!($arg), ": "));
+        sb_pass_return(&$result as *const _);
+    };
+    ($result:ident, $returned_arg:ident, &*$arg:ident) => {
+        sb_bind_return(&$result as *const _);
+        sb_check_summary_return(&$result as *const _, $returned_arg, false,
+                                concat!("SUMMARY RETURN ", stringify!($arg), ": "));
+        sb_pass_return(&$result as *const _);
+    };
+}
+
+// Return value of the stub of a summary.
+macro_rules! summary_result {
+    () => {
+        kani::any()
+    };
+    (&mut *$arg:ident) => {{
+        let arg_slot = &$arg as *const _;
+        let result = &mut *$arg;
+        sb_unique_from_ref(&result as *const _, arg_slot, concat!("SUMMARY RETURN ", stringify!($arg), ": "));
+        sb_pass_return(&result as *const _);
+        result
+    }};
+    (&*$arg:ident) => {{
+        let result = &*$arg;
+        sb_shared_from_ref(&result as *const _, &$arg as *const _, concat!("SUMMARY RETURN ", stringify!($arg), ": "));
+        sb_pass_return(&result as *const _);
+        result
+    }};
+}
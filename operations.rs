@@ -0,0 +1,93 @@
+// Copyright Jacob Salzberg
+// SPDX-License-Identifier: Apache-2.0
+
+// Operation sequences run through both Rust models by the soundness proofs
+// of soundness.rs, and the interpreter running them on a model.
+//
+// Two locals are in scope, pointers 0 and 1 are the locals themselves. Every
+// reborrow appends a new pointer, the operand of an operation is the index
+// of an existing pointer. Mutable reborrows are checked as writes, shared
+// reborrows as reads.
+#![allow(dead_code)]
+
+pub const LOCALS: usize = 2;
+
+// maximum length of an operation sequence
+pub const MAX_OPS: usize = 5;
+
+// maximum number of pointers, the locals and one per operation
+pub const MAX_POINTERS: usize = LOCALS + MAX_OPS;
+
+type OpKind = u8;
+
+// `let p = &mut *operand;`
+pub const OP_UNIQUE: OpKind = 0;
+// `let p = operand as *mut _;`
+pub const OP_RAW: OpKind = 1;
+// `let p = &*operand;`
+pub const OP_SHARED: OpKind = 2;
+// `*operand = ..;`
+pub const OP_WRITE: OpKind = 3;
+// `.. = *operand;`
+pub const OP_READ: OpKind = 4;
+
+/// An operation and its operand.
+pub type Op = (OpKind, usize);
+
+/// Whether the operation appends a new pointer.
+pub fn is_reborrow(op: OpKind) -> bool {
+    op <= OP_SHARED
+}
+
+/// First operation violating the stacked borrows rules, as its index in the
+/// sequence and the local its operand points to.
+pub type Violation = (usize, usize);
+
+/// Pointer kind and tag of a pointer in a model.
+pub type Provenance = (u32, u32);
+
+/// The operations of a model the interpreter runs, on a pointer to one of
+/// the locals.
+pub trait Model {
+    /// Creates the stack of a local and returns the provenance of the local.
+    fn allocate(&mut self, local: *const i32) -> Provenance;
+    /// Whether an access through the pointer is allowed. Changes nothing.
+    fn can_access(&self, local: *const i32, provenance: Provenance, write: bool) -> bool;
+    fn write(&mut self, local: *const i32, provenance: Provenance);
+    fn read(&mut self, local: *const i32, provenance: Provenance);
+    /// Returns the provenance of the new pointer.
+    fn reborrow(&mut self, op: OpKind, local: *const i32, provenance: Provenance) -> Provenance;
+}
+
+/// Runs a sequence of operations through the model and returns the first one
+/// violating the rules. Accesses are checked with can_access rather than
+/// asserted, so that the proofs can compare the violations of two models.
+pub fn first_violation<M: Model>(model: &mut M, locals: [*const i32; LOCALS], ops: &[Op]) -> Option<Violation> {
+    // local pointed to and provenance of every pointer
+    let mut pointers = [(0, (0, 0)); MAX_POINTERS];
+    let mut count = 0;
+    while count < LOCALS {
+        pointers[count] = (count, model.allocate(locals[count]));
+        count += 1;
+    }
+    let mut i = 0;
+    while i < ops.len() {
+        let (op, operand) = ops[i];
+        assert!(operand < count);
+        let (local, provenance) = pointers[operand];
+        let write = op == OP_WRITE || op == OP_UNIQUE || op == OP_RAW;
+        if !model.can_access(locals[local], provenance, write) {
+            return Some((i, local));
+        }
+        if op == OP_WRITE {
+            model.write(locals[local], provenance);
+        } else if op == OP_READ {
+            model.read(locals[local], provenance);
+        } else {
+            pointers[count] = (local, model.reborrow(op, locals[local], provenance));
+            count += 1;
+        }
+        i += 1;
+    }
+    None
+}
@@ -0,0 +1,1269 @@
+// Copyright Jacob Salzberg
+// SPDX-License-Identifier: Apache-2.0
+
+// Generated by instrumenter.rs from original.rs with the model of test.rs.
+// Do not edit, regenerate it instead.
+
+#![allow(non_snake_case)]
+
+const STACK_DEPTH: usize = 15;
+const MAX_NUM_OBJECTS: usize = 1024;
+const MAX_OBJECT_SIZE: usize = 64;
+type PtrId = u32;
+type StackItemKind = u32;
+
+const KIND_UNIQUE: StackItemKind = 0;
+const KIND_SHARED_RW: StackItemKind = 1;
+const KIND_SHARED_RO: StackItemKind = 2;
+
+type PointerValueKind = u32;
+pub const KIND_IDENTIFIED : StackItemKind = 0;
+pub const KIND_NONE: StackItemKind = 1;
+// pointers cast back from integers, may use any exposed tag
+const KIND_WILDCARD: PointerValueKind = 2;
+
+// maximum number of tags exposed by pointer to integer casts
+const MAX_EXPOSED_TAGS: usize = 8;
+
+// maximum number of references protected by ongoing function calls
+const MAX_PROTECTED_TAGS: usize = 8;
+
+// maximum number of arguments in the reads, writes and protects of the
+// summary of a function, see summary! in macros.rs
+const MAX_SUMMARY_ARGS: usize = 8;
+
+// Kind of the allocation a location belongs to, decides the base item
+// of its borrow stacks.
+type AllocationKind = u32;
+// stack locals and heap allocations are owned by a Unique item
+pub const ALLOC_STACK: AllocationKind = 0;
+const ALLOC_HEAP: AllocationKind = 1;
+// `static mut` starts with a SharedRW item, any raw pointer may write to it
+const ALLOC_STATIC_MUT: AllocationKind = 2;
+// `static` and string literals start with a SharedRO item, writes are UB
+const ALLOC_STATIC: AllocationKind = 3;
+
+mod shadow_map;
+use shadow_map::ShadowMap;
+mod tag_map;
+use tag_map::{slot_target, PointerSlot, Retag, TagMap};
+#[macro_use]
+mod macros;
+
+/*
+// Uncomment to debug with println
+use std::collections::HashMap;
+static mut MY_MAP : Option<HashMap<usize, usize>> = None;
+static mut MY_COUNT : usize = 0;
+*/
+
+fn pointer_object<U: Sized>(ptr: *const U) -> usize {
+    kani::mem::pointer_object(ptr)
+    /*
+    // Uncomment to debug with println
+    unsafe {
+        match MY_MAP {
+            Some(_) => {},
+            None => MY_MAP = Some(HashMap::new()),
+        };
+        match &mut MY_MAP {
+            Some(ref mut h) => {
+                let mut found = false;
+                let mut output = MY_COUNT;
+                let ptr_v = std::mem::transmute::<*const U, usize>(ptr);
+                match (h.get(&ptr_v)) {
+                    Some(v) => { output = *v; found = true },
+                    None => { }
+                }
+                if !found {
+                    h.insert(ptr_v, MY_COUNT);
+                    MY_COUNT += 1
+                }
+                output
+            }
+            None => { unreachable!() },
+        }
+    }
+    // ptr as *const _ as usize
+    */
+}
+
+fn pointer_offset<U: Sized>(ptr: *const U) -> usize {
+    kani::mem::pointer_offset(ptr)
+}
+
+/// Borrow stack of a byte, bottom first.
+#[derive(Clone, Copy, Debug)]
+struct BorrowStack {
+    ids: [PtrId; STACK_DEPTH],
+    kinds: [StackItemKind; STACK_DEPTH],
+    top: usize,
+}
+
+impl BorrowStack {
+    const EMPTY: BorrowStack = BorrowStack { ids: [0; STACK_DEPTH], kinds: [KIND_SHARED_RW; STACK_DEPTH], top: 0 };
+}
+
+#[derive(Debug)]
+pub struct SState {
+    // borrow stack of every byte, empty until its allocation is created
+    stacks: ShadowMap<BorrowStack>,
+    alloc_kinds: [AllocationKind; MAX_NUM_OBJECTS],
+    alloc_sizes: [usize; MAX_NUM_OBJECTS],
+    // set by StorageDead and deallocation, the stacks of a dead allocation
+    // are empty
+    alloc_dead: [bool; MAX_NUM_OBJECTS],
+    protected_ids: [PtrId; MAX_PROTECTED_TAGS],
+    protected_count: usize,
+    exposed_objects: [usize; MAX_EXPOSED_TAGS],
+    exposed_kinds: [PointerValueKind; MAX_EXPOSED_TAGS],
+    exposed_ids: [PtrId; MAX_EXPOSED_TAGS],
+    exposed_count: usize,
+    // set while a function body is checked against its summary: accesses
+    // to the allocations that existed before the call must fall in one of
+    // the ranges of the summary, writes in one that allows them, and
+    // protectors in one of its protects
+    summary_checked: bool,
+    summary_objects: [usize; MAX_SUMMARY_ARGS],
+    summary_offsets: [usize; MAX_SUMMARY_ARGS],
+    summary_sizes: [usize; MAX_SUMMARY_ARGS],
+    summary_writes: [bool; MAX_SUMMARY_ARGS],
+    summary_protects: [bool; MAX_SUMMARY_ARGS],
+    summary_count: usize,
+    // allocations created by the body being checked
+    body_objects: [bool; MAX_NUM_OBJECTS],
+    // rejects integer to pointer casts instead of giving them wildcard
+    // provenance
+    strict_provenance: bool,
+    // Stacked Borrows 2.1: raw pointers get fresh tags recorded in their
+    // SharedRW items instead of all sharing the bottom tag 0
+    tagged_raw_pointers: bool,
+    // provenance of the pointers stored in memory, kept on every byte of
+    // the stored pointer and cleared by any other write to that byte
+    stored_pointers: ShadowMap<Option<TaggedPointer>>,
+    // kind and tag of every pointer variable and local, see tag_map.rs
+    tags: TagMap,
+    // prefix of the messages of failing checks, names the variable the
+    // current operation goes through, see macros.rs
+    context: &'static str,
+    next_ptr_id: PtrId,
+}
+
+impl Default for SState {
+    fn default() -> SState {
+        SState {
+            stacks: ShadowMap::new(BorrowStack::EMPTY),
+            alloc_kinds: [ALLOC_STACK; MAX_NUM_OBJECTS],
+            alloc_sizes: [0; MAX_NUM_OBJECTS],
+            alloc_dead: [false; MAX_NUM_OBJECTS],
+            protected_ids: [0; MAX_PROTECTED_TAGS],
+            protected_count: 0,
+            exposed_objects: [0; MAX_EXPOSED_TAGS],
+            exposed_kinds: [KIND_NONE; MAX_EXPOSED_TAGS],
+            exposed_ids: [0; MAX_EXPOSED_TAGS],
+            exposed_count: 0,
+            summary_checked: false,
+            summary_objects: [0; MAX_SUMMARY_ARGS],
+            summary_offsets: [0; MAX_SUMMARY_ARGS],
+            summary_sizes: [0; MAX_SUMMARY_ARGS],
+            summary_writes: [false; MAX_SUMMARY_ARGS],
+            summary_protects: [false; MAX_SUMMARY_ARGS],
+            summary_count: 0,
+            body_objects: [false; MAX_NUM_OBJECTS],
+            strict_provenance: false,
+            tagged_raw_pointers: false,
+            stored_pointers: ShadowMap::new(None),
+            tags: TagMap::new(KIND_NONE, 0),
+            context: "",
+            next_ptr_id: 0,
+        }
+    }
+}
+
+/// A raw pointer value with the provenance it was derived with. The offset
+/// is relative to the allocation the pointer was derived from and may go out
+/// of bounds through wrapping arithmetic.
+#[derive(Clone, Copy, Debug)]
+struct TaggedPointer {
+    object: usize,
+    offset: isize,
+    kind: PointerValueKind,
+    tag: PtrId,
+}
+
+impl TaggedPointer {
+    fn new<U>(ptr: *const U, kind: PointerValueKind, tag: PtrId) -> TaggedPointer {
+        TaggedPointer {
+            object: pointer_object(ptr),
+            offset: pointer_offset(ptr) as isize,
+            kind,
+            tag,
+        }
+    }
+}
+
+// Transmutes change no stack, they only decide the provenance of the
+// result. A transmute between two references keeps the tag of the
+// reference, like TRANSMUTE_REF in stacked_borrows.h, and needs no function.
+
+/// `transmute::<&T, *mut T>(reference)`: unlike a cast the stack is left
+/// untouched and the raw pointer keeps the tag of the reference, so it is only
+/// granted what the reference was granted.
+fn transmute_ref_to_raw<U>(loc: *const U, kind: PointerValueKind, tag: PtrId) -> TaggedPointer {
+    TaggedPointer::new(loc, kind, tag)
+}
+
+/// `transmute::<*mut T, &mut T>(ptr)`: the reference keeps the tag of the raw
+/// pointer, no new item is pushed.
+fn transmute_raw_to_ref(ptr: TaggedPointer) -> (PointerValueKind, PtrId) {
+    (ptr.kind, ptr.tag)
+}
+
+// Returns the object, the offset and the size of the bytes covered by ptr.
+fn pointer_range<U>(ptr: *const U) -> (usize, usize, usize) {
+    let obj = pointer_object(ptr);
+    let offset = pointer_offset(ptr);
+    let size = std::mem::size_of::<U>();
+    assert!(obj < MAX_NUM_OBJECTS);
+    assert!(offset <= MAX_OBJECT_SIZE && size <= MAX_OBJECT_SIZE - offset);
+    (obj, offset, size)
+}
+
+impl SState {
+    /// Registers the allocation `ptr` points to and pushes the base item
+    /// matching its kind. Returns the tag of the base item.
+    pub fn new_allocation<U>(&mut self, ptr: *const U, alloc_kind: AllocationKind) -> PtrId {
+        let (obj, offset, size) = pointer_range(ptr);
+        self.alloc_kinds[obj] = alloc_kind;
+        self.alloc_sizes[obj] = offset + size;
+        self.body_objects[obj] = self.summary_checked;
+        if alloc_kind == ALLOC_STATIC_MUT {
+            self.push_shared(ptr)
+        } else if alloc_kind == ALLOC_STATIC {
+            self.push_shared_ro(ptr)
+        } else {
+            self.push_unique(ptr)
+        }
+    }
+
+    // Pushes the item on the stack of every byte in [offset, offset + size).
+    fn push_range(&mut self, obj: usize, offset: usize, size: usize, item_kind: StackItemKind, id: PtrId) {
+        assert!(!self.alloc_dead[obj], "Use after scope.");
+        let mut byte = offset;
+        while byte < offset + size {
+            let stack = self.stacks.get_mut_at(obj, byte);
+            assert!(stack.top < STACK_DEPTH);
+            stack.kinds[stack.top] = item_kind;
+            stack.ids[stack.top] = id;
+            stack.top += 1;
+            byte += 1;
+        }
+    }
+
+    // Returns the tag of a new item of the given kind: SharedRW items all
+    // get the bottom tag 0 unless raw pointers are tagged.
+    fn fresh_tag(&mut self, item_kind: StackItemKind) -> PtrId {
+        if item_kind == KIND_SHARED_RW && !self.tagged_raw_pointers {
+            return 0;
+        }
+        let ptr_id_old = self.next_ptr_id;
+        self.next_ptr_id += 1;
+        ptr_id_old
+    }
+
+    pub fn push_shared<U>(&mut self, ptr: *const U) -> PtrId {
+        let (obj, offset, size) = pointer_range(ptr);
+        let ptr_id_old = self.fresh_tag(KIND_SHARED_RW);
+        self.push_range(obj, offset, size, KIND_SHARED_RW, ptr_id_old);
+        ptr_id_old
+    }
+
+    pub fn push_shared_ro<U>(&mut self, ptr: *const U) -> PtrId {
+        let (obj, offset, size) = pointer_range(ptr);
+        let ptr_id_old = self.fresh_tag(KIND_SHARED_RO);
+        self.push_range(obj, offset, size, KIND_SHARED_RO, ptr_id_old);
+        ptr_id_old
+    }
+
+    pub fn push_unique<U>(&mut self, ptr: *const U) -> PtrId {
+        let (obj, offset, size) = pointer_range(ptr);
+        let ptr_id_old = self.fresh_tag(KIND_UNIQUE);
+        self.push_range(obj, offset, size, KIND_UNIQUE, ptr_id_old);
+        ptr_id_old
+    }
+
+    // Whether the item is a reference protected by an ongoing call.
+    fn item_protected(&self, item_kind: StackItemKind, item_id: PtrId) -> bool {
+        let mut protected = false;
+        let mut i = 0;
+        while (i < MAX_PROTECTED_TAGS) && (i < self.protected_count) {
+            protected |= item_kind != KIND_SHARED_RW && self.protected_ids[i] == item_id;
+            i += 1;
+        }
+        protected
+    }
+
+    // Whether a protected item sits at or above height new_top in the stack
+    // of byte (obj, offset).
+    fn protected_above(&self, obj: usize, offset: usize, new_top: usize) -> bool {
+        let stack = self.stacks.get_ref_at(obj, offset);
+        let mut protected = false;
+        let mut i = new_top;
+        while (i < STACK_DEPTH) && (i < stack.top) {
+            protected |= self.item_protected(stack.kinds[i], stack.ids[i]);
+            i += 1;
+        }
+        protected
+    }
+
+    /// Adds a protector on the reference tag for the duration of a call, as
+    /// done by the retag of reference arguments on function entry.
+    fn protect(&mut self, tag: PtrId) {
+        assert!(self.protected_count < MAX_PROTECTED_TAGS);
+        self.protected_ids[self.protected_count] = tag;
+        self.protected_count += 1;
+    }
+
+    /// Removes the protectors added since the call started, protectors end
+    /// in the reverse order of calls.
+    fn end_protect(&mut self, count: usize) {
+        assert!(count <= self.protected_count);
+        self.protected_count -= count;
+    }
+
+    /// StorageDead: the local goes out of scope. Its stacks are emptied so
+    /// that every tag derived from it becomes invalid, and using any of them
+    /// is reported as a use after scope. A local still protected by an
+    /// ongoing call must not die.
+    fn storage_dead<U>(&mut self, local: *const U) {
+        let obj = pointer_object(local);
+        assert!(obj < MAX_NUM_OBJECTS);
+        assert!(!self.alloc_dead[obj], "StorageDead on a dead local.");
+        let mut byte = 0;
+        while byte < self.alloc_sizes[obj] {
+            assert!(!self.protected_above(obj, byte, 0), "Protected local goes out of scope.");
+            byte += 1;
+        }
+        self.stacks.clear_object(obj);
+        self.stored_pointers.clear_object(obj);
+        self.alloc_dead[obj] = true;
+    }
+
+    /// `drop(Box::from_raw(ptr))` or `dealloc(ptr)`: frees the heap
+    /// allocation ptr points to. Like in Miri, deallocating is a write to
+    /// every byte of the allocation that must not pop a protected item, and
+    /// afterwards every tag of the allocation is invalid.
+    fn deallocate(&mut self, ptr: TaggedPointer) {
+        let obj = ptr.object;
+        assert!(obj < MAX_NUM_OBJECTS);
+        assert!(self.alloc_kinds[obj] == ALLOC_HEAP, "Deallocation of memory not on the heap.");
+        assert!(ptr.offset == 0, "Deallocation not at the start of the allocation.");
+        let size = self.alloc_sizes[obj];
+        self.access_range(obj, 0, size, ptr.kind, ptr.tag, true);
+        let mut byte = 0;
+        while byte < size {
+            assert!(!self.protected_above(obj, byte, 0), "Protected item deallocated.");
+            byte += 1;
+        }
+        self.stacks.clear_object(obj);
+        self.stored_pointers.clear_object(obj);
+        self.alloc_dead[obj] = true;
+    }
+
+    /// StorageLive: the local comes (back) into scope, e.g. on the next
+    /// iteration of a loop, with fresh stacks. Returns the tag of the local.
+    fn storage_live<U>(&mut self, local: *const U) -> PtrId {
+        let obj = pointer_object(local);
+        assert!(obj < MAX_NUM_OBJECTS);
+        self.stacks.clear_object(obj);
+        self.stored_pointers.clear_object(obj);
+        self.alloc_dead[obj] = false;
+        self.new_allocation(local, ALLOC_STACK)
+    }
+
+    // Whether the item was exposed by a pointer to integer cast.
+    fn item_exposed(&self, obj: usize, item_kind: StackItemKind, item_id: PtrId) -> bool {
+        let mut exposed = false;
+        let mut i = 0;
+        while (i < MAX_EXPOSED_TAGS) && (i < self.exposed_count) {
+            if self.exposed_objects[i] == obj {
+                if self.exposed_kinds[i] == KIND_IDENTIFIED {
+                    exposed |= item_kind != KIND_SHARED_RW && self.exposed_ids[i] == item_id;
+                } else {
+                    exposed |= item_kind == KIND_SHARED_RW
+                        && (!self.tagged_raw_pointers || self.exposed_ids[i] == item_id);
+                }
+            }
+            i += 1;
+        }
+        exposed
+    }
+
+    // Whether the item grants an access with the given tag. A wildcard
+    // pointer is granted what any exposed item grants.
+    fn grants(&self, obj: usize, item_kind: StackItemKind, item_id: PtrId, kind: PointerValueKind, id: PtrId,
+              write: bool) -> bool {
+        if kind == KIND_IDENTIFIED {
+            let matching = item_id == id;
+            if write { item_kind == KIND_UNIQUE && matching } else { item_kind != KIND_SHARED_RW && matching }
+        } else if kind == KIND_WILDCARD {
+            (!write || item_kind != KIND_SHARED_RO) && self.item_exposed(obj, item_kind, item_id)
+        } else {
+            item_kind == KIND_SHARED_RW && (!self.tagged_raw_pointers || item_id == id)
+        }
+    }
+
+    // Returns the height of the stack of byte (obj, offset) after an access
+    // with the given tag, or None if no item grants the access.
+    // A write (USE-2) keeps everything up to the granting item, a read
+    // (READ-1) also keeps the SharedRO items right above it. With tagged raw
+    // pointers a write granted by a SharedRW item also keeps the SharedRW
+    // items right above it, the sibling raw pointers, as in Miri. A wildcard
+    // access uses the topmost exposed item that grants it, so that it
+    // invalidates as little as possible.
+    fn granted_top(&self, obj: usize, offset: usize, kind: PointerValueKind, id: PtrId, write: bool) -> Option<usize> {
+        let stack = self.stacks.get_ref_at(obj, offset);
+        let mut found = false;
+        let mut i = 0;
+        let mut new_top = 0;
+        while (i < STACK_DEPTH) && (i < stack.top) {
+            let item_kind = stack.kinds[i];
+            if self.grants(obj, item_kind, stack.ids[i], kind, id, write) {
+                new_top = i+1;
+                found = true;
+            } else if !write && found && new_top == i && item_kind == KIND_SHARED_RO {
+                new_top = i+1;
+            } else if write && found && new_top == i && item_kind == KIND_SHARED_RW && self.tagged_raw_pointers
+                && stack.kinds[i - 1] == KIND_SHARED_RW {
+                new_top = i+1;
+            }
+            i += 1;
+        }
+        if found { Some(new_top) } else { None }
+    }
+
+    /// Starts checking a function body against its summary. Until
+    /// end_summary_check, the body may only access its own allocations and
+    /// the ranges added with summary_arg.
+    fn begin_summary_check(&mut self) {
+        assert!(!self.summary_checked, "Nested summary checks.");
+        self.summary_checked = true;
+        self.summary_count = 0;
+        self.body_objects = [false; MAX_NUM_OBJECTS];
+    }
+
+    /// Allows the body being checked to read the bytes ptr points to, and to
+    /// write them if write is set.
+    fn summary_arg<U>(&mut self, ptr: *const U, write: bool) {
+        self.add_summary_range(ptr, write, false);
+    }
+
+    /// Allows the body being checked to protect the bytes ptr points to.
+    fn summary_protect<U>(&mut self, ptr: *const U) {
+        self.add_summary_range(ptr, false, true);
+    }
+
+    fn add_summary_range<U>(&mut self, ptr: *const U, write: bool, protect: bool) {
+        let (obj, offset, size) = pointer_range(ptr);
+        assert!(self.summary_count < MAX_SUMMARY_ARGS);
+        self.summary_objects[self.summary_count] = obj;
+        self.summary_offsets[self.summary_count] = offset;
+        self.summary_sizes[self.summary_count] = size;
+        self.summary_writes[self.summary_count] = write;
+        self.summary_protects[self.summary_count] = protect;
+        self.summary_count += 1;
+    }
+
+    fn end_summary_check(&mut self) {
+        self.summary_checked = false;
+        self.summary_count = 0;
+    }
+
+    // Whether the summary being checked, if any, allows the access to byte
+    // (obj, offset), or a protector on it when protect is set.
+    fn within_summary(&self, obj: usize, offset: usize, write: bool, protect: bool) -> bool {
+        let mut allowed = !self.summary_checked || self.body_objects[obj];
+        let mut i = 0;
+        while (i < MAX_SUMMARY_ARGS) && (i < self.summary_count) {
+            allowed |= self.summary_objects[i] == obj
+                && self.summary_offsets[i] <= offset && offset < self.summary_offsets[i] + self.summary_sizes[i]
+                && self.summary_protects[i] == protect
+                && (!write || self.summary_writes[i]);
+            i += 1;
+        }
+        allowed
+    }
+
+    // Index of the topmost item of the stack of byte (obj, offset) granting
+    // the access with the given tag, if any.
+    fn granting_index(&self, obj: usize, offset: usize, kind: PointerValueKind, id: PtrId, write: bool) -> Option<usize> {
+        let stack = self.stacks.get_ref_at(obj, offset);
+        let mut index = None;
+        let mut i = 0;
+        while (i < STACK_DEPTH) && (i < stack.top) {
+            if self.grants(obj, stack.kinds[i], stack.ids[i], kind, id, write) {
+                index = Some(i);
+            }
+            i += 1;
+        }
+        index
+    }
+
+    /// Checks that the pointer held by result, returned by the body being
+    /// checked, is a reborrow of the pointer held by arg as its summary
+    /// declares with `returns(&mut *arg)` or `returns(&*arg)`: it points to
+    /// the same bytes, and on each of them the item granting it sits at or
+    /// above the one granting arg.
+    fn check_summary_return<P: PointerSlot, Q: PointerSlot>(&self, result: *const P, arg: *const Q, write: bool) {
+        let (kind, tag) = self.tags.get(result);
+        let (arg_kind, arg_tag) = self.tags.get(arg);
+        let (obj, offset, size) = pointer_range(slot_target(result));
+        assert!((obj, offset, size) == pointer_range(slot_target(arg)),
+                "{}Returned pointer does not point to the argument.", self.context);
+        let mut byte = offset;
+        while byte < offset + size {
+            let derived = match (self.granting_index(obj, byte, kind, tag, write),
+                                 self.granting_index(obj, byte, arg_kind, arg_tag, write)) {
+                (Some(index), Some(arg_index)) => index >= arg_index,
+                _ => false,
+            };
+            assert!(derived, "{}Returned pointer not derived from the argument at offset {}.", self.context, byte);
+            byte += 1;
+        }
+    }
+
+    // Checks the access on every byte in [offset, offset + size) before
+    // updating any stack, so that a failing access reports the first
+    // offending byte and leaves the stacks consistent.
+    fn access_range(&mut self, obj: usize, offset: usize, size: usize, kind: PointerValueKind, id: PtrId, write: bool) {
+        assert!(!self.alloc_dead[obj], "{}Use after scope.", self.context);
+        let mut byte = offset;
+        while byte < offset + size {
+            let new_top = self.granted_top(obj, byte, kind, id, write);
+            assert!(new_top.is_some(), "{}Stack violated at offset {}.", self.context, byte);
+            assert!(!self.protected_above(obj, byte, new_top.unwrap()),
+                    "{}Protected item popped at offset {}.", self.context, byte);
+            assert!(self.within_summary(obj, byte, write, false),
+                    "{}Access outside the function summary at offset {}.", self.context, byte);
+            byte += 1;
+        }
+        let mut byte = offset;
+        while byte < offset + size {
+            let new_top = self.granted_top(obj, byte, kind, id, write).unwrap();
+            self.stacks.get_mut_at(obj, byte).top = new_top;
+            if write {
+                self.stored_pointers.set_at(obj, byte, None);
+            }
+            byte += 1;
+        }
+    }
+
+    // Whether every byte ptr points to has an item granting the access with
+    // the given tag and, when protectors is set, whether the access would
+    // pop no protected item. Changes nothing.
+    fn granted<U>(&self, ptr: *const U, kind: PointerValueKind, id: PtrId, write: bool, protectors: bool) -> bool {
+        let (obj, offset, size) = pointer_range(ptr);
+        let mut granted = !self.alloc_dead[obj];
+        let mut byte = offset;
+        while granted && byte < offset + size {
+            granted = match self.granted_top(obj, byte, kind, id, write) {
+                Some(new_top) => !protectors || !self.protected_above(obj, byte, new_top),
+                None => false,
+            };
+            byte += 1;
+        }
+        granted
+    }
+
+    /// Whether reading through ptr with the given tag is allowed. Unlike
+    /// read_1 it updates no stack, so it can be used in contracts.
+    pub fn can_read<U>(&self, ptr: *const U, kind: PointerValueKind, id: PtrId) -> bool {
+        self.granted(ptr, kind, id, false, true)
+    }
+
+    /// Whether writing through ptr with the given tag is allowed. Unlike
+    /// use_2 it updates no stack, so it can be used in contracts.
+    pub fn can_write<U>(&self, ptr: *const U, kind: PointerValueKind, id: PtrId) -> bool {
+        self.granted(ptr, kind, id, true, true)
+    }
+
+    /// Whether the tag is still on the stacks of the bytes ptr points to,
+    /// i.e. whether it has not been invalidated by a conflicting access.
+    /// Protectors are ignored: a live tag may still not be usable because
+    /// its access would pop a protected item.
+    pub fn tag_live<U>(&self, ptr: *const U, kind: PointerValueKind, id: PtrId) -> bool {
+        self.granted(ptr, kind, id, false, false)
+    }
+
+    /// Borrow stack of the byte ptr points to, bottom first, as the kinds
+    /// and the tags of its items.
+    pub fn stack_at<U>(&self, ptr: *const U) -> (&[StackItemKind], &[PtrId]) {
+        let (obj, offset, _) = pointer_range(ptr);
+        let stack = self.stacks.get_ref_at(obj, offset);
+        let top = stack.top.min(STACK_DEPTH);
+        (&stack.kinds[..top], &stack.ids[..top])
+    }
+
+    /// `*slot = value` where the value is a reference or a raw pointer: a
+    /// write to the slot that also records the provenance of the value, so
+    /// that it can be loaded back from memory with its tag.
+    fn store_pointer<P>(&mut self, slot: *const P, kind: PointerValueKind, id: PtrId, value: TaggedPointer) {
+        let (obj, offset, size) = pointer_range(slot);
+        self.store_pointer_range(obj, offset, size, kind, id, value);
+    }
+
+    // Writes the bytes [offset, offset + size) and records value on each.
+    fn store_pointer_range(&mut self, obj: usize, offset: usize, size: usize, kind: PointerValueKind, id: PtrId,
+                           value: TaggedPointer) {
+        self.access_range(obj, offset, size, kind, id, true);
+        let mut byte = offset;
+        while byte < offset + size {
+            self.stored_pointers.set_at(obj, byte, Some(value));
+            byte += 1;
+        }
+    }
+
+    /// `*slot` where the slot holds a reference or a raw pointer: a read of
+    /// the slot that returns the provenance stored with the value. Every
+    /// byte must still carry it, bytes written as plain data lose it.
+    fn load_pointer<P>(&mut self, slot: *const P, kind: PointerValueKind, id: PtrId) -> TaggedPointer {
+        let (obj, offset, size) = pointer_range(slot);
+        self.load_pointer_range(obj, offset, size, kind, id)
+    }
+
+    // Reads the bytes [offset, offset + size) and returns the pointer
+    // recorded on them.
+    fn load_pointer_range(&mut self, obj: usize, offset: usize, size: usize, kind: PointerValueKind,
+                          id: PtrId) -> TaggedPointer {
+        self.access_range(obj, offset, size, kind, id, false);
+        let mut byte = offset;
+        while byte < offset + size {
+            assert!(self.stored_pointers.get_at(obj, byte).is_some(), "{}Loaded pointer without provenance.",
+                    self.context);
+            byte += 1;
+        }
+        self.stored_pointers.get_at(obj, offset).unwrap()
+    }
+
+    pub fn use_2<U>(&mut self, ptr: *const U, kind: PointerValueKind, id: PtrId) {
+        let (obj, offset, size) = pointer_range(ptr);
+        self.access_range(obj, offset, size, kind, id, true);
+    }
+
+    pub fn read_1<U>(&mut self, ptr: *const U, kind: PointerValueKind, id: PtrId) {
+        let (obj, offset, size) = pointer_range(ptr);
+        self.access_range(obj, offset, size, kind, id, false);
+    }
+
+    // Retags the bytes [offset, offset + size) only: the parent tag is used
+    // on those bytes, then the new item is pushed on them.
+    fn reborrow_range(&mut self, obj: usize, offset: usize, size: usize, kind: PointerValueKind, tag: PtrId,
+                      item_kind: StackItemKind) -> PtrId {
+        if item_kind == KIND_SHARED_RW && self.tagged_raw_pointers {
+            return self.insert_shared_range(obj, offset, size, kind, tag);
+        }
+        self.access_range(obj, offset, size, kind, tag, item_kind != KIND_SHARED_RO);
+        let ptr_id_old = self.fresh_tag(item_kind);
+        self.push_range(obj, offset, size, item_kind, ptr_id_old);
+        ptr_id_old
+    }
+
+    // Raw reborrow of Stacked Borrows 2.1: no access, the SharedRW item is
+    // inserted where a write with the parent tag would start popping, right
+    // above the granting item and its sibling raw pointers, so that raw
+    // pointers derived from the same parent stay usable together.
+    fn insert_shared_range(&mut self, obj: usize, offset: usize, size: usize, kind: PointerValueKind,
+                           tag: PtrId) -> PtrId {
+        assert!(!self.alloc_dead[obj], "{}Use after scope.", self.context);
+        let mut byte = offset;
+        while byte < offset + size {
+            let index = self.granted_top(obj, byte, kind, tag, true);
+            assert!(index.is_some(), "{}Stack violated at offset {}.", self.context, byte);
+            byte += 1;
+        }
+        let ptr_id_old = self.fresh_tag(KIND_SHARED_RW);
+        let mut byte = offset;
+        while byte < offset + size {
+            let index = self.granted_top(obj, byte, kind, tag, true).unwrap();
+            let stack = self.stacks.get_mut_at(obj, byte);
+            assert!(stack.top < STACK_DEPTH);
+            let mut i = stack.top;
+            while i > index {
+                stack.kinds[i] = stack.kinds[i - 1];
+                stack.ids[i] = stack.ids[i - 1];
+                i -= 1;
+            }
+            stack.kinds[index] = KIND_SHARED_RW;
+            stack.ids[index] = ptr_id_old;
+            stack.top += 1;
+            byte += 1;
+        }
+        ptr_id_old
+    }
+
+    // Returns the range of the field at field_offset in the object parent
+    // points to.
+    fn field_range<U>(parent: *const U, field_offset: usize, field_size: usize) -> (usize, usize, usize) {
+        let (obj, offset, size) = pointer_range(parent);
+        assert!(field_offset <= size && field_size <= size - field_offset);
+        (obj, offset + field_offset, field_size)
+    }
+
+    /// `&mut (*parent).field`: reborrows the field bytes only, so borrows of
+    /// disjoint fields can be alive at the same time.
+    fn new_mutable_field_ref<U>(&mut self, parent: *const U, kind: PointerValueKind, tag: PtrId,
+                                field_offset: usize, field_size: usize) -> PtrId {
+        let (obj, offset, size) = Self::field_range(parent, field_offset, field_size);
+        self.reborrow_range(obj, offset, size, kind, tag, KIND_UNIQUE)
+    }
+
+    /// `&(*parent).field`
+    fn new_shared_field_ref<U>(&mut self, parent: *const U, kind: PointerValueKind, tag: PtrId,
+                               field_offset: usize, field_size: usize) -> PtrId {
+        let (obj, offset, size) = Self::field_range(parent, field_offset, field_size);
+        self.reborrow_range(obj, offset, size, kind, tag, KIND_SHARED_RO)
+    }
+
+    // Returns the range of the count elements of elem_size bytes starting
+    // at base.
+    fn slice_range<U>(base: *const U, elem_size: usize, count: usize) -> (usize, usize, usize) {
+        let obj = pointer_object(base);
+        let offset = pointer_offset(base);
+        assert!(obj < MAX_NUM_OBJECTS);
+        assert!(elem_size == 0 || count <= MAX_OBJECT_SIZE / elem_size);
+        let size = elem_size * count;
+        assert!(offset <= MAX_OBJECT_SIZE && size <= MAX_OBJECT_SIZE - offset);
+        (obj, offset, size)
+    }
+
+    /// `&mut *slice_from_raw_parts_mut(base, count)`: reborrows the elements
+    /// of the sub-slice only, as done by `split_at_mut` or `chunks_mut`.
+    fn new_mutable_slice_ref<U>(&mut self, base: *const U, elem_size: usize, count: usize,
+                                kind: PointerValueKind, tag: PtrId) -> PtrId {
+        let (obj, offset, size) = Self::slice_range(base, elem_size, count);
+        self.reborrow_range(obj, offset, size, kind, tag, KIND_UNIQUE)
+    }
+
+    /// `&*slice_from_raw_parts(base, count)`
+    fn new_shared_slice_ref<U>(&mut self, base: *const U, elem_size: usize, count: usize,
+                               kind: PointerValueKind, tag: PtrId) -> PtrId {
+        let (obj, offset, size) = Self::slice_range(base, elem_size, count);
+        self.reborrow_range(obj, offset, size, kind, tag, KIND_SHARED_RO)
+    }
+
+    /// Write to the count elements starting at base.
+    fn use_2_slice<U>(&mut self, base: *const U, elem_size: usize, count: usize, kind: PointerValueKind, id: PtrId) {
+        let (obj, offset, size) = Self::slice_range(base, elem_size, count);
+        self.access_range(obj, offset, size, kind, id, true);
+    }
+
+    /// Read of the count elements starting at base.
+    fn read_1_slice<U>(&mut self, base: *const U, elem_size: usize, count: usize, kind: PointerValueKind, id: PtrId) {
+        let (obj, offset, size) = Self::slice_range(base, elem_size, count);
+        self.access_range(obj, offset, size, kind, id, false);
+    }
+
+    /// `ptr.offset(count)` and `ptr.add(count)`: the result keeps the
+    /// provenance of ptr and must stay in bounds of its allocation, one past
+    /// the end included.
+    fn offset_raw<U>(&self, ptr: TaggedPointer, count: isize) -> TaggedPointer {
+        let result = self.wrapping_offset_raw::<U>(ptr, count);
+        assert!(0 <= result.offset && result.offset as usize <= self.alloc_sizes[ptr.object],
+                "Pointer arithmetic out of bounds.");
+        result
+    }
+
+    /// `ptr.wrapping_offset(count)` and `ptr.wrapping_add(count)`: the
+    /// result keeps the provenance of ptr but may be out of bounds, which is
+    /// only checked when it gets dereferenced.
+    fn wrapping_offset_raw<U>(&self, ptr: TaggedPointer, count: isize) -> TaggedPointer {
+        let delta = count.wrapping_mul(std::mem::size_of::<U>() as isize);
+        TaggedPointer { offset: ptr.offset.wrapping_add(delta), ..ptr }
+    }
+
+    // Returns the range of the U pointed to by ptr, which must be in bounds
+    // of the allocation ptr was derived from.
+    fn raw_range<U>(&self, ptr: TaggedPointer) -> (usize, usize, usize) {
+        self.raw_bytes(ptr, std::mem::size_of::<U>())
+    }
+
+    // Returns the range of the size bytes pointed to by ptr.
+    fn raw_bytes(&self, ptr: TaggedPointer, size: usize) -> (usize, usize, usize) {
+        assert!(ptr.object < MAX_NUM_OBJECTS);
+        let alloc_size = self.alloc_sizes[ptr.object];
+        assert!(0 <= ptr.offset && ptr.offset as usize <= alloc_size && size <= alloc_size - ptr.offset as usize,
+                "Dereference out of bounds.");
+        (ptr.object, ptr.offset as usize, size)
+    }
+
+    /// Write through a raw pointer: checked against the stacks of the bytes
+    /// the pointer reaches, not the ones it was derived from.
+    fn use_2_raw<U>(&mut self, ptr: TaggedPointer) {
+        let (obj, offset, size) = self.raw_range::<U>(ptr);
+        self.access_range(obj, offset, size, ptr.kind, ptr.tag, true);
+    }
+
+    /// Read through a raw pointer.
+    fn read_1_raw<U>(&mut self, ptr: TaggedPointer) {
+        let (obj, offset, size) = self.raw_range::<U>(ptr);
+        self.access_range(obj, offset, size, ptr.kind, ptr.tag, false);
+    }
+
+    /// `ptr.expose_provenance()` or `ptr as usize`: the tag of ptr may from
+    /// now on be used by pointers cast back from integers.
+    fn expose(&mut self, ptr: TaggedPointer) {
+        if ptr.kind == KIND_WILDCARD {
+            return;
+        }
+        assert!(self.exposed_count < MAX_EXPOSED_TAGS);
+        self.exposed_objects[self.exposed_count] = ptr.object;
+        self.exposed_kinds[self.exposed_count] = ptr.kind;
+        self.exposed_ids[self.exposed_count] = ptr.tag;
+        self.exposed_count += 1;
+    }
+
+    /// `ptr::with_exposed_provenance(addr)` or `addr as *const T`, where ptr
+    /// is the pointer value obtained from the integer. The result has
+    /// wildcard provenance, unless strict provenance is enforced in which
+    /// case the cast itself is rejected.
+    fn from_exposed<U>(&self, ptr: *const U) -> TaggedPointer {
+        assert!(!self.strict_provenance, "Integer to pointer cast under strict provenance.");
+        TaggedPointer::new(ptr, KIND_WILDCARD, 0)
+    }
+
+    // Returns the range of the count values of type U starting at ptr.
+    fn raw_count_range<U>(&self, ptr: TaggedPointer, count: usize) -> (usize, usize, usize) {
+        let elem_size = std::mem::size_of::<U>();
+        assert!(elem_size == 0 || count <= MAX_OBJECT_SIZE / elem_size);
+        self.raw_bytes(ptr, elem_size * count)
+    }
+
+    // Moves the provenance stored in size bytes from src to dst, as if
+    // through an intermediate buffer so that the ranges may overlap.
+    fn copy_provenance(&mut self, src_obj: usize, src_offset: usize, dst_obj: usize, dst_offset: usize, size: usize) {
+        let mut pointers = [None; MAX_OBJECT_SIZE];
+        let mut i = 0;
+        while i < size {
+            pointers[i] = self.stored_pointers.get_at(src_obj, src_offset + i);
+            i += 1;
+        }
+        let mut i = 0;
+        while i < size {
+            self.stored_pointers.set_at(dst_obj, dst_offset + i, pointers[i]);
+            i += 1;
+        }
+    }
+
+    /// `ptr::copy(src, dst, count)`: a read of the source followed by a
+    /// write of the destination. Pointers stored in the source keep their
+    /// provenance in the destination.
+    fn copy<U>(&mut self, src: TaggedPointer, dst: TaggedPointer, count: usize) {
+        let (src_obj, src_offset, size) = self.raw_count_range::<U>(src, count);
+        let (dst_obj, dst_offset, _) = self.raw_count_range::<U>(dst, count);
+        self.access_range(src_obj, src_offset, size, src.kind, src.tag, false);
+        self.access_range(dst_obj, dst_offset, size, dst.kind, dst.tag, true);
+        self.copy_provenance(src_obj, src_offset, dst_obj, dst_offset, size);
+    }
+
+    /// `ptr::copy_nonoverlapping(src, dst, count)`, also used for `clone`
+    /// of structs holding references.
+    fn copy_nonoverlapping<U>(&mut self, src: TaggedPointer, dst: TaggedPointer, count: usize) {
+        let (src_obj, src_offset, size) = self.raw_count_range::<U>(src, count);
+        let (dst_obj, dst_offset, _) = self.raw_count_range::<U>(dst, count);
+        assert!(src_obj != dst_obj || src_offset + size <= dst_offset || dst_offset + size <= src_offset,
+                "copy_nonoverlapping on overlapping ranges.");
+        self.copy::<U>(src, dst, count);
+    }
+
+    /// `ptr::swap(a, b)` and `mem::swap(a, b)`: both values are read then
+    /// written, their provenance is exchanged.
+    fn swap<U>(&mut self, a: TaggedPointer, b: TaggedPointer) {
+        let (a_obj, a_offset, size) = self.raw_range::<U>(a);
+        let (b_obj, b_offset, _) = self.raw_range::<U>(b);
+        self.access_range(a_obj, a_offset, size, a.kind, a.tag, false);
+        self.access_range(b_obj, b_offset, size, b.kind, b.tag, false);
+        let mut a_pointers = [None; MAX_OBJECT_SIZE];
+        let mut b_pointers = [None; MAX_OBJECT_SIZE];
+        let mut i = 0;
+        while i < size {
+            a_pointers[i] = self.stored_pointers.get_at(a_obj, a_offset + i);
+            b_pointers[i] = self.stored_pointers.get_at(b_obj, b_offset + i);
+            i += 1;
+        }
+        self.access_range(a_obj, a_offset, size, a.kind, a.tag, true);
+        self.access_range(b_obj, b_offset, size, b.kind, b.tag, true);
+        let mut i = 0;
+        while i < size {
+            self.stored_pointers.set_at(a_obj, a_offset + i, b_pointers[i]);
+            self.stored_pointers.set_at(b_obj, b_offset + i, a_pointers[i]);
+            i += 1;
+        }
+    }
+
+    /// `mem::replace(dst, value)` where U is a reference or raw pointer type:
+    /// reads the old pointer from dst with its provenance, then stores value.
+    fn replace<U>(&mut self, dst: TaggedPointer, value: TaggedPointer) -> TaggedPointer {
+        let (obj, offset, size) = self.raw_range::<U>(dst);
+        let old = self.load_pointer_range(obj, offset, size, dst.kind, dst.tag);
+        self.store_pointer_range(obj, offset, size, dst.kind, dst.tag, value);
+        old
+    }
+
+    pub fn new_mutable_ref<U>(&mut self, loc: *const U, kind: PointerValueKind, tag: PtrId) -> PtrId {
+        self.use_2(loc, kind, tag);
+        self.push_unique(loc)
+    }
+
+    pub fn new_mutable_raw<U>(&mut self, loc: *const U, kind: PointerValueKind, tag: PtrId) -> PtrId {
+        let (obj, offset, size) = pointer_range(loc);
+        self.reborrow_range(obj, offset, size, kind, tag, KIND_SHARED_RW)
+    }
+
+    pub fn new_shared_ref<U>(&mut self, loc: *const U, kind: PointerValueKind, tag: PtrId) -> PtrId {
+        self.read_1(loc, kind, tag);
+        self.push_shared_ro(loc)
+    }
+
+    // The operations below find the tags of pointers in the tag map from
+    // the address of the variable holding them, like the macros of
+    // stacked_borrows.h.
+
+    /// `let local = ...;`: allocates the local and records its tag.
+    fn new_local<U>(&mut self, local: *const U) {
+        let id = self.new_allocation(local, ALLOC_STACK);
+        self.tags.set(local, KIND_IDENTIFIED, id);
+    }
+
+    /// `let new_ref = &mut local;`
+    fn unique_from_local<P: PointerSlot>(&mut self, new_ref: *const P, local: *const P::Target) {
+        let (kind, tag) = self.tags.get(local);
+        let id = self.new_mutable_ref(local, kind, tag);
+        self.tags.set(new_ref, KIND_IDENTIFIED, id);
+    }
+
+    /// `let new_ref = &local;`
+    fn shared_from_local<P: PointerSlot>(&mut self, new_ref: *const P, local: *const P::Target) {
+        let (kind, tag) = self.tags.get(local);
+        let id = self.new_shared_ref(local, kind, tag);
+        self.tags.set(new_ref, KIND_IDENTIFIED, id);
+    }
+
+    /// `let new_raw = &mut local as *mut _;`, through a temporary reference
+    fn raw_from_local<P: PointerSlot>(&mut self, new_raw: *const P, local: *const P::Target) {
+        let (kind, tag) = self.tags.get(local);
+        let temporary_ref__id = self.new_mutable_ref(local, kind, tag);
+        let id = self.new_mutable_raw(local, KIND_IDENTIFIED, temporary_ref__id);
+        self.tags.set(new_raw, KIND_NONE, id);
+    }
+
+    /// `let new_ref = &mut *old_ref;`
+    fn unique_from_ref<P, Q>(&mut self, new_ref: *const P, old_ref: *const Q)
+    where P: PointerSlot, Q: PointerSlot<Target = P::Target> {
+        let (kind, tag) = self.tags.get(old_ref);
+        let id = self.new_mutable_ref(slot_target(old_ref), kind, tag);
+        self.tags.set(new_ref, KIND_IDENTIFIED, id);
+    }
+
+    /// `let new_ref = &*old_ref;`
+    fn shared_from_ref<P, Q>(&mut self, new_ref: *const P, old_ref: *const Q)
+    where P: PointerSlot, Q: PointerSlot<Target = P::Target> {
+        let (kind, tag) = self.tags.get(old_ref);
+        let id = self.new_shared_ref(slot_target(old_ref), kind, tag);
+        self.tags.set(new_ref, KIND_IDENTIFIED, id);
+    }
+
+    /// `let new_raw = old_ref as *mut _;`
+    fn raw_from_ref<P, Q>(&mut self, new_raw: *const P, old_ref: *const Q)
+    where P: PointerSlot, Q: PointerSlot<Target = P::Target> {
+        let (kind, tag) = self.tags.get(old_ref);
+        let id = self.new_mutable_raw(slot_target(old_ref), kind, tag);
+        self.tags.set(new_raw, KIND_NONE, id);
+    }
+
+    /// `*ptr = ...` through the pointer held by the variable at slot.
+    fn use_2_at<P: PointerSlot>(&mut self, slot: *const P) {
+        let (kind, tag) = self.tags.get(slot);
+        self.use_2(slot_target(slot), kind, tag);
+    }
+
+    /// `... = *ptr` through the pointer held by the variable at slot.
+    fn read_1_at<P: PointerSlot>(&mut self, slot: *const P) {
+        let (kind, tag) = self.tags.get(slot);
+        self.read_1(slot_target(slot), kind, tag);
+    }
+
+    /// `*ptr = value` where value is a pointer: a write through the pointer
+    /// held by the variable at slot that stores the tag of value with it.
+    fn store_pointer_at<P: PointerSlot, V>(&mut self, slot: *const P, value: *const V) {
+        let (kind, tag) = self.tags.get(slot);
+        let (value_kind, value_tag) = self.tags.get(value);
+        // value holds a pointer, read it as such to find what it points to
+        let pointer = unsafe { *(value as *const *const u8) };
+        self.store_pointer(slot_target(slot), kind, tag, TaggedPointer::new(pointer, value_kind, value_tag));
+    }
+
+    /// `let x = *ptr` where x is a pointer: a read through the pointer held
+    /// by the variable at slot, x gets the tag stored with the value.
+    fn load_pointer_at<X, P: PointerSlot>(&mut self, x: *const X, slot: *const P) {
+        let (kind, tag) = self.tags.get(slot);
+        let pointer = self.load_pointer(slot_target(slot), kind, tag);
+        self.tags.set(x, pointer.kind, pointer.tag);
+    }
+
+    /// Protects the reference held by the variable at slot for the duration
+    /// of a call. Returns the number of protectors added, 0 for raw
+    /// pointers which are not protected.
+    fn protect_at<P: PointerSlot>(&mut self, slot: *const P) -> usize {
+        let (kind, tag) = self.tags.get(slot);
+        if kind != KIND_IDENTIFIED {
+            return 0;
+        }
+        let (obj, offset, size) = pointer_range(slot_target(slot));
+        let mut byte = offset;
+        while byte < offset + size {
+            assert!(self.within_summary(obj, byte, false, true),
+                    "{}Protector outside the function summary at offset {}.", self.context, byte);
+            byte += 1;
+        }
+        self.protect(tag);
+        1
+    }
+
+    /// Retag of the parameter held by the variable at slot on function
+    /// entry, `let x = &mut *x;` or `let x = &*x;` as written by fn_entry!.
+    fn fn_entry_at<P: PointerSlot>(&mut self, slot: *const P) {
+        match P::RETAG {
+            Retag::Unique => self.unique_from_ref(slot, slot),
+            Retag::Shared => self.shared_from_ref(slot, slot),
+            Retag::None => {}
+        }
+    }
+
+    /// `local = ...`
+    fn use_2_local<U>(&mut self, local: *const U) {
+        let (kind, tag) = self.tags.get(local);
+        self.use_2(local, kind, tag);
+    }
+
+    /// `... = local`
+    fn read_1_local<U>(&mut self, local: *const U) {
+        let (kind, tag) = self.tags.get(local);
+        self.read_1(local, kind, tag);
+    }
+}
+
+// Model state of the instrumented functions. They keep their original
+// signatures, so they cannot be handed the state and look it up here.
+static mut SSTATE: Option<SState> = None;
+
+// Runs f on the model state. SSTATE is only reached through a raw pointer and
+// every model function borrows it once, for the duration of f, so that no two
+// mutable references to it are ever live at the same time.
+fn with_sstate<R>(f: impl FnOnce(&mut SState) -> R) -> R {
+    let sstate = unsafe { &mut *std::ptr::addr_of_mut!(SSTATE) };
+    f(sstate.get_or_insert_with(SState::default))
+}
+
+// Same, with the context naming the variable in the messages of the checks
+// failing in f.
+fn with_context<R>(context: &'static str, f: impl FnOnce(&mut SState) -> R) -> R {
+    with_sstate(|sstate| {
+        sstate.context = context;
+        let result = f(sstate);
+        sstate.context = "";
+        result
+    })
+}
+
+// Read-only access for the queries of sb, None before the first operation. As
+// for with_sstate, the borrow of SSTATE does not outlive f.
+fn with_sstate_query<R>(f: impl FnOnce(Option<&SState>) -> R) -> R {
+    f(unsafe { (*std::ptr::addr_of!(SSTATE)).as_ref() })
+}
+
+// Model functions the macros of macros.rs expand to. The context names the
+// variable in the messages of the checks failing during the operation.
+
+fn sb_new_local<U>(local: *const U, context: &'static str) {
+    with_context(context, |sstate| sstate.new_local(local));
+}
+
+fn sb_unique_from_local<P: PointerSlot>(new_ref: *const P, local: *const P::Target, context: &'static str) {
+    with_context(context, |sstate| sstate.unique_from_local(new_ref, local));
+}
+
+fn sb_shared_from_local<P: PointerSlot>(new_ref: *const P, local: *const P::Target, context: &'static str) {
+    with_context(context, |sstate| sstate.shared_from_local(new_ref, local));
+}
+
+fn sb_unique_from_ref<P, Q>(new_ref: *const P, old_ref: *const Q, context: &'static str)
+where P: PointerSlot, Q: PointerSlot<Target = P::Target> {
+    with_context(context, |sstate| sstate.unique_from_ref(new_ref, old_ref));
+}
+
+fn sb_shared_from_ref<P, Q>(new_ref: *const P, old_ref: *const Q, context: &'static str)
+where P: PointerSlot, Q: PointerSlot<Target = P::Target> {
+    with_context(context, |sstate| sstate.shared_from_ref(new_ref, old_ref));
+}
+
+fn sb_raw_from_ref<P, Q>(new_raw: *const P, old_ref: *const Q, context: &'static str)
+where P: PointerSlot, Q: PointerSlot<Target = P::Target> {
+    with_context(context, |sstate| sstate.raw_from_ref(new_raw, old_ref));
+}
+
+fn sb_raw_from_local<P: PointerSlot>(new_raw: *const P, local: *const P::Target, context: &'static str) {
+    with_context(context, |sstate| sstate.raw_from_local(new_raw, local));
+}
+
+fn sb_use2<P: PointerSlot>(used: *const P, context: &'static str) {
+    with_context(context, |sstate| sstate.use_2_at(used));
+}
+
+fn sb_read1<P: PointerSlot>(used: *const P, context: &'static str) {
+    with_context(context, |sstate| sstate.read_1_at(used));
+}
+
+fn sb_use2_local<U>(local: *const U, context: &'static str) {
+    with_context(context, |sstate| sstate.use_2_local(local));
+}
+
+fn sb_read1_local<U>(local: *const U, context: &'static str) {
+    with_context(context, |sstate| sstate.read_1_local(local));
+}
+
+fn sb_copy_pointer<X, P>(x: *const X, source: *const P) {
+    with_sstate(|sstate| {
+        let (kind, tag) = sstate.tags.get(source);
+        sstate.tags.set(x, kind, tag);
+    });
+}
+
+fn sb_store_pointer<P: PointerSlot, V>(used: *const P, value: *const V, context: &'static str) {
+    with_context(context, |sstate| sstate.store_pointer_at(used, value));
+}
+
+fn sb_load_pointer<X, P: PointerSlot>(x: *const X, used: *const P, context: &'static str) {
+    with_context(context, |sstate| sstate.load_pointer_at(x, used));
+}
+
+fn sb_pass_arg<P>(index: usize, arg: *const P) {
+    with_sstate(|sstate| sstate.tags.pass_arg(index, arg));
+}
+
+fn sb_bind_arg<P>(index: usize, param: *const P) {
+    with_sstate(|sstate| sstate.tags.bind_arg(index, param));
+}
+
+fn sb_pass_return<P>(result: *const P) {
+    with_sstate(|sstate| sstate.tags.pass_return(result));
+}
+
+fn sb_bind_return<P>(result: *const P) {
+    with_sstate(|sstate| sstate.tags.bind_return(result));
+}
+
+fn sb_fn_entry<P: PointerSlot>(param: *const P, context: &'static str) {
+    with_context(context, |sstate| sstate.fn_entry_at(param));
+}
+
+fn sb_protect<P: PointerSlot>(arg: *const P) -> usize {
+    with_sstate(|sstate| sstate.protect_at(arg))
+}
+
+fn sb_end_protect(count: usize) {
+    with_sstate(|sstate| sstate.end_protect(count));
+}
+
+fn sb_begin_summary_check() {
+    with_sstate(|sstate| sstate.begin_summary_check());
+}
+
+fn sb_summary_arg<P: PointerSlot>(arg: *const P, write: bool) {
+    with_sstate(|sstate| sstate.summary_arg(slot_target(arg), write));
+}
+
+fn sb_summary_protect<P: PointerSlot>(arg: *const P) {
+    with_sstate(|sstate| sstate.summary_protect(slot_target(arg)));
+}
+
+fn sb_check_summary_return<P: PointerSlot, Q: PointerSlot>(result: *const P, arg: *const Q, write: bool,
+                                                            context: &'static str) {
+    with_context(context, |sstate| sstate.check_summary_return(result, arg, write));
+}
+
+fn sb_end_summary_check() {
+    with_sstate(|sstate| sstate.end_summary_check());
+}
+
+/// Queries of the model without side effects, usable in Kani function
+/// contracts:
+///
+///     #[kani::requires(sb::can_write(&x, sb::arg_provenance(0)))]
+///     #[kani::ensures(|_| sb::tag_live(&x, sb::arg_provenance(0)))]
+///
+/// A pointer is given as a reference to the variable holding it, as for the
+/// macros of macros.rs, and its provenance as a (kind, tag) pair.
+mod sb {
+    use super::{with_sstate_query, PointerValueKind, PointerSlot, PtrId, StackItemKind};
+
+    pub type Provenance = (PointerValueKind, PtrId);
+
+    /// Provenance of the pointer held by the variable.
+    pub fn provenance<P>(slot: &P) -> Provenance {
+        with_sstate_query(|s| s.map_or((super::KIND_NONE, 0), |s| s.tags.get(slot as *const P)))
+    }
+
+    /// Provenance passed by the caller as argument number index, i.e. the
+    /// provenance of a parameter in a precondition, before the body binds
+    /// it.
+    pub fn arg_provenance(index: usize) -> Provenance {
+        with_sstate_query(|s| s.map_or((super::KIND_NONE, 0), |s| s.tags.arg(index)))
+    }
+
+    pub fn can_read<P: PointerSlot>(slot: &P, (kind, tag): Provenance) -> bool {
+        with_sstate_query(|s| s.is_some_and(|s| s.can_read(slot.address(), kind, tag)))
+    }
+
+    pub fn can_write<P: PointerSlot>(slot: &P, (kind, tag): Provenance) -> bool {
+        with_sstate_query(|s| s.is_some_and(|s| s.can_write(slot.address(), kind, tag)))
+    }
+
+    pub fn tag_live<P: PointerSlot>(slot: &P, (kind, tag): Provenance) -> bool {
+        with_sstate_query(|s| s.is_some_and(|s| s.tag_live(slot.address(), kind, tag)))
+    }
+
+    /// Runs f on the borrow stack of the byte the pointer points to, see
+    /// SState::stack_at, e.g. `sb::stack(&x, |kinds, _| kinds.len() == 1)`.
+    pub fn stack<P: PointerSlot, R>(slot: &P, f: impl FnOnce(&[StackItemKind], &[PtrId]) -> R) -> R {
+        with_sstate_query(|s| match s {
+            Some(s) => {
+                let (kinds, ids) = s.stack_at(slot.address());
+                f(kinds, ids)
+            }
+            None => f(&[], &[]),
+        })
+    }
+}
+
+#[kani::proof]
+fn main() {
+    new_local!(let mut local = 5);
+    raw_from_local!(let raw_pointer = &mut local as *mut i32);
+    new_local!(let result = unsafe { { unique_from_ref!(let example1__arg0 = &mut *raw_pointer); unique_from_ref!(let example1__arg1 = &mut *raw_pointer); pass_arg!(0, example1__arg0); pass_arg!(1, example1__arg1); example1(example1__arg0, example1__arg1) } });
+    bind_return!(result);
+    assert_eq!(result, 13);
+}
+
+fn example1(x: &mut i32, y: &mut i32) -> i32 {
+    bind_arg!(0, x);
+    bind_arg!(1, y);
+    fn_entry!(let x = &mut *x);
+    fn_entry!(let y = &mut *y);
+    let _example1__protected = protect!(x, y);
+    *x = 42;
+    use2!(x);
+    *y = 13;
+    use2!(y);
+    read1!(x);
+    *x
+}
@@ -0,0 +1,40 @@
+#ifdef DEMONIC
+#include "stacked_borrows_demonic.h"
+#else
+#include "stacked_borrows.h"
+#endif
+
+/// passes in both modes: untagged raw pointers share the bottom tag, and with
+/// -DSB_TAGGED_RAW a raw reborrow performs no access, so raw_pointer2 is
+/// inserted next to raw_pointer1 instead of popping it
+int main() {
+  SB_INIT(true, 16);
+
+  // let mut local = 5;
+  int32_t local = 5;
+  NEW_LOCAL(local);
+
+  // let x = &mut local;
+  USE2_LOCAL(local);
+  int32_t *x = &local;
+  UNIQUE_FROM_LOCAL(x, local);
+
+  // let raw_pointer1 = x as *mut i32;
+#ifndef SB_TAGGED_RAW
+  USE2(x);
+#endif
+  int32_t *raw_pointer1 = x;
+  SHARED_RW_FROM_REF(raw_pointer1, x);
+
+  // let raw_pointer2 = x as *mut i32;
+#ifndef SB_TAGGED_RAW
+  USE2(x); // pops raw_pointer1, which shares the tag of raw_pointer2
+#endif
+  int32_t *raw_pointer2 = x;
+  SHARED_RW_FROM_REF(raw_pointer2, x);
+
+  // unsafe { *raw_pointer1 = 42 };
+  USE2(raw_pointer1);
+  *raw_pointer1 = 42;
+  return 0;
+}
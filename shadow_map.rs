@@ -0,0 +1,155 @@
+// Copyright Jacob Salzberg
+// SPDX-License-Identifier: Apache-2.0
+
+// Rust port of shadow_map_mult.h.
+//
+// A shadow map associates a value of type T with every byte of every object
+// manipulated by user code. It is modelled as a map from object ids to lazily
+// allocated shadow objects holding one T per byte of their source object.
+// Unlike the C version T is not restricted to 1, 2, 4 or 8 shadow bytes: it
+// can be a borrow stack, a tag, the provenance of a stored pointer, ...
+//
+// Under Kani object ids and offsets come from kani::mem. Natively an address
+// does not know which object it belongs to, so allocations are looked up in
+// a registry of (base address, size) ranges. An address outside every
+// registered allocation is registered on first use with the size of its
+// pointee. Object id 0 is reserved for the null pointer, as in CBMC.
+#![allow(dead_code)]
+
+#[cfg(not(kani))]
+use std::cell::RefCell;
+
+#[cfg(not(kani))]
+thread_local! {
+    // (base address, size) of every allocation seen so far, indexed by
+    // object id - 1
+    static ALLOCATIONS: RefCell<Vec<(usize, usize)>> = const { RefCell::new(Vec::new()) };
+}
+
+// Returns the object id and the offset of the address, registering a new
+// allocation of the given size if no live one contains it. The most recent
+// allocations are checked first so that a reused address gets a new id.
+#[cfg(not(kani))]
+fn locate(address: usize, size: usize) -> (usize, usize) {
+    if address == 0 {
+        return (0, 0);
+    }
+    ALLOCATIONS.with(|allocations| {
+        let mut allocations = allocations.borrow_mut();
+        let mut i = allocations.len();
+        while i > 0 {
+            let (base, base_size) = allocations[i - 1];
+            if base <= address && address < base + base_size.max(1) {
+                return (i, address - base);
+            }
+            i -= 1;
+        }
+        allocations.push((address, size));
+        (allocations.len(), 0)
+    })
+}
+
+/// Registers size bytes starting at ptr as a new allocation, e.g. a local
+/// coming into scope or the result of `Box::new`. Under Kani allocations are
+/// known to the verifier and this does nothing.
+#[cfg(not(kani))]
+pub fn register_allocation<U>(ptr: *const U, size: usize) {
+    let address = ptr as *const u8 as usize;
+    ALLOCATIONS.with(|allocations| allocations.borrow_mut().push((address, size)));
+}
+
+#[cfg(kani)]
+pub fn register_allocation<U>(_ptr: *const U, _size: usize) {}
+
+#[cfg(not(kani))]
+pub fn object_id<U>(ptr: *const U) -> usize {
+    locate(ptr as *const u8 as usize, std::mem::size_of::<U>()).0
+}
+
+#[cfg(kani)]
+pub fn object_id<U>(ptr: *const U) -> usize {
+    kani::mem::pointer_object(ptr)
+}
+
+#[cfg(not(kani))]
+pub fn object_offset<U>(ptr: *const U) -> usize {
+    locate(ptr as *const u8 as usize, std::mem::size_of::<U>()).1
+}
+
+#[cfg(kani)]
+pub fn object_offset<U>(ptr: *const U) -> usize {
+    kani::mem::pointer_offset(ptr)
+}
+
+#[derive(Debug)]
+pub struct ShadowMap<T> {
+    // value of the bytes that were never written
+    default: T,
+    // shadow objects, indexed by object id, allocated on first write
+    shadows: Vec<Option<Vec<T>>>,
+}
+
+impl<T: Copy> ShadowMap<T> {
+    /// Creates a shadow map where every byte initially maps to default.
+    pub const fn new(default: T) -> ShadowMap<T> {
+        ShadowMap { default, shadows: Vec::new() }
+    }
+
+    /// Returns the shadow value of the byte at offset in object.
+    pub fn get_at(&self, object: usize, offset: usize) -> T {
+        match self.shadows.get(object) {
+            Some(Some(shadow)) if offset < shadow.len() => shadow[offset],
+            _ => self.default,
+        }
+    }
+
+    /// Returns a reference to the shadow value of the byte at offset in
+    /// object, without allocating its shadow object.
+    pub fn get_ref_at(&self, object: usize, offset: usize) -> &T {
+        match self.shadows.get(object) {
+            Some(Some(shadow)) if offset < shadow.len() => &shadow[offset],
+            _ => &self.default,
+        }
+    }
+
+    /// Returns the shadow value of the byte at offset in object, allocating
+    /// its shadow object if needed.
+    pub fn get_mut_at(&mut self, object: usize, offset: usize) -> &mut T {
+        if self.shadows.len() <= object {
+            self.shadows.resize(object + 1, None);
+        }
+        let default = self.default;
+        let shadow = self.shadows[object].get_or_insert_with(Vec::new);
+        if shadow.len() <= offset {
+            shadow.resize(offset + 1, default);
+        }
+        &mut shadow[offset]
+    }
+
+    pub fn set_at(&mut self, object: usize, offset: usize, value: T) {
+        *self.get_mut_at(object, offset) = value;
+    }
+
+    /// Returns the shadow value of the byte pointed to by ptr.
+    pub fn get<U>(&self, ptr: *const U) -> T {
+        self.get_at(object_id(ptr), object_offset(ptr))
+    }
+
+    /// Returns the shadow value of the byte pointed to by ptr, allocating its
+    /// shadow object if needed.
+    pub fn get_mut<U>(&mut self, ptr: *const U) -> &mut T {
+        self.get_mut_at(object_id(ptr), object_offset(ptr))
+    }
+
+    pub fn set<U>(&mut self, ptr: *const U, value: T) {
+        *self.get_mut(ptr) = value;
+    }
+
+    /// Resets every byte of object to the default value, e.g. when the
+    /// object is deallocated.
+    pub fn clear_object(&mut self, object: usize) {
+        if let Some(shadow) = self.shadows.get_mut(object) {
+            *shadow = None;
+        }
+    }
+}
@@ -0,0 +1,152 @@
+// Copyright Jacob Salzberg
+// SPDX-License-Identifier: Apache-2.0
+
+// Soundness of the demonic model with respect to the full model: tracking a
+// single nondeterministically chosen location finds the same violations as
+// tracking all of them. Both models run the same arbitrary bounded sequences
+// of reborrows, reads and writes, see operations.rs.
+//
+//   kani soundness.rs --harness full_violations_found_by_demonic
+//   kani soundness.rs --harness demonic_violations_found_by_full
+//   kani soundness.rs --harness two_monitor_violations_found_by_full
+#![allow(non_snake_case)]
+
+#[path = "test.rs"]
+mod full;
+#[path = "test_demonic.rs"]
+mod demonic;
+mod operations;
+
+use operations::{is_reborrow, Model, Op, Provenance, Violation, LOCALS, MAX_OPS, OP_READ, OP_SHARED, OP_UNIQUE, OP_WRITE};
+
+impl Model for full::SState {
+    fn allocate(&mut self, local: *const i32) -> Provenance {
+        (full::KIND_IDENTIFIED, self.new_allocation(local, full::ALLOC_STACK))
+    }
+
+    fn can_access(&self, local: *const i32, (kind, tag): Provenance, write: bool) -> bool {
+        if write { self.can_write(local, kind, tag) } else { self.can_read(local, kind, tag) }
+    }
+
+    fn write(&mut self, local: *const i32, (kind, tag): Provenance) {
+        self.use_2(local, kind, tag);
+    }
+
+    fn read(&mut self, local: *const i32, (kind, tag): Provenance) {
+        self.read_1(local, kind, tag);
+    }
+
+    fn reborrow(&mut self, op: u8, local: *const i32, (kind, tag): Provenance) -> Provenance {
+        match op {
+            OP_UNIQUE => (full::KIND_IDENTIFIED, self.new_mutable_ref(local, kind, tag)),
+            OP_SHARED => (full::KIND_IDENTIFIED, self.new_shared_ref(local, kind, tag)),
+            _ => (full::KIND_NONE, self.new_mutable_raw(local, kind, tag)),
+        }
+    }
+}
+
+impl<const K: usize> Model for demonic::SState<K> {
+    fn allocate(&mut self, local: *const i32) -> Provenance {
+        (demonic::KIND_IDENTIFIED, self.new_allocation(local))
+    }
+
+    fn can_access(&self, local: *const i32, (kind, tag): Provenance, write: bool) -> bool {
+        if write { self.can_use_2(local, kind, tag) } else { self.can_read_1(local, kind, tag) }
+    }
+
+    fn write(&mut self, local: *const i32, (kind, tag): Provenance) {
+        self.use_2(local, kind, tag);
+    }
+
+    fn read(&mut self, local: *const i32, (kind, tag): Provenance) {
+        self.read_1(local, kind, tag);
+    }
+
+    fn reborrow(&mut self, op: u8, local: *const i32, (kind, tag): Provenance) -> Provenance {
+        match op {
+            OP_UNIQUE => (demonic::KIND_IDENTIFIED, self.new_mutable_ref(local, kind, tag)),
+            OP_SHARED => (demonic::KIND_IDENTIFIED, self.new_shared_ref(local, kind, tag)),
+            _ => (demonic::KIND_NONE, self.new_mutable_raw(local, kind, tag)),
+        }
+    }
+}
+
+// Returns an arbitrary sequence of at most MAX_OPS operations whose operands
+// are existing pointers.
+fn any_ops() -> ([Op; MAX_OPS], usize) {
+    let len: usize = kani::any();
+    kani::assume(len <= MAX_OPS);
+    let mut ops = [(OP_WRITE, 0); MAX_OPS];
+    let mut pointers = LOCALS;
+    let mut i = 0;
+    while i < len {
+        let op: u8 = kani::any();
+        let operand: usize = kani::any();
+        kani::assume(op <= OP_READ && operand < pointers);
+        ops[i] = (op, operand);
+        if is_reborrow(op) {
+            pointers += 1;
+        }
+        i += 1;
+    }
+    (ops, len)
+}
+
+// First violation of the full model.
+fn full_violation(ops: &[Op]) -> Option<Violation> {
+    let a = 0i32;
+    let b = 0i32;
+    operations::first_violation(&mut full::SState::default(), [&a, &b], ops)
+}
+
+// First violation of the demonic model with K monitors, one of them on the
+// pinned local if any.
+fn demonic_violation<const K: usize>(ops: &[Op], pinned: Option<usize>) -> Option<Violation> {
+    let a = 0i32;
+    let b = 0i32;
+    let locals: [*const i32; LOCALS] = [&a, &b];
+    let mut sstate = demonic::SState::<K>::default();
+    if let Some(local) = pinned {
+        sstate.pin_monitor(locals[local]);
+    }
+    operations::first_violation(&mut sstate, locals, ops)
+}
+
+// Every violation of the full model is found by the demonic model when the
+// monitor is on the violating local from its allocation on.
+#[kani::proof]
+#[kani::unwind(16)]
+fn full_violations_found_by_demonic() {
+    let (ops, len) = any_ops();
+    if let Some((index, local)) = full_violation(&ops[..len]) {
+        let found = demonic_violation::<1>(&ops[..len], Some(local));
+        assert_eq!(found, Some((index, local)));
+    }
+}
+
+// Every violation found by the demonic model, whatever the monitor choices,
+// is a violation of the full model, which may stop earlier on another local.
+// Relies on the monitor staying on the local it picked at its allocation: a
+// monitor moving to another local on a push would check the accesses to it
+// against the stack of the previous one.
+#[kani::proof]
+#[kani::unwind(16)]
+fn demonic_violations_found_by_full() {
+    let (ops, len) = any_ops();
+    if let Some((index, _)) = demonic_violation::<1>(&ops[..len], None) {
+        let violation = full_violation(&ops[..len]);
+        assert!(violation.is_some_and(|(full_index, _)| full_index <= index));
+    }
+}
+
+// The same with one monitor per local: more monitors find violations on more
+// locations in one run, but never report valid accesses.
+#[kani::proof]
+#[kani::unwind(16)]
+fn two_monitor_violations_found_by_full() {
+    let (ops, len) = any_ops();
+    if let Some((index, _)) = demonic_violation::<2>(&ops[..len], None) {
+        let violation = full_violation(&ops[..len]);
+        assert!(violation.is_some_and(|(full_index, _)| full_index <= index));
+    }
+}
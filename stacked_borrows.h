@@ -1,6 +1,9 @@
 // Covers the basic definitions of the paper
 // "Stacked Borrows: An Aliasing Model for Rust"
 // until section 3.5 no optimisations.
+// There are no protectors (section 4 of the paper): a local going out of
+// scope or an access never fails because it would pop an item of an ongoing
+// call, unlike the SState of test.rs.
 #ifndef STACKED_BORROWS_DEFINED
 #define STACKED_BORROWS_DEFINED
 
@@ -36,6 +39,18 @@ const sb_kind_t SB_SHARED_RW = 0x4;
 // disabled &mut x
 const sb_kind_t SB_DISABLED = 0x4;
 
+// Allocation kind, decides the base item of a fresh borrow stack
+typedef uint8_t sb_alloc_kind_t;
+// stack locals and heap objects start with a SB_UNIQUE
+const sb_alloc_kind_t SB_ALLOC_STACK = 0x1;
+const sb_alloc_kind_t SB_ALLOC_HEAP = 0x2;
+// static mut starts with a SB_SHARED_RW
+const sb_alloc_kind_t SB_ALLOC_STATIC_MUT = 0x4;
+// static and string literals start with a SB_SHARED_RO, writes are UB
+const sb_alloc_kind_t SB_ALLOC_STATIC = 0x8;
+// local that went out of scope
+const sb_alloc_kind_t SB_ALLOC_DEAD = 0x10;
+
 // Borrow ID type
 // We track at most 128 borrows in the program
 typedef int8_t sb_id_t;
@@ -43,6 +58,15 @@ typedef int8_t sb_id_t;
 // Borrow ID used for raw pointers
 const sb_id_t __sb_id_bottom = -1;
 
+// Stacked Borrows 2.1 mode, compile with -DSB_TAGGED_RAW: raw pointers get
+// fresh borrow IDs recorded in their SB_SHARED_RW items instead of all
+// sharing __sb_id_bottom, so accesses can tell raw pointers apart.
+#ifdef SB_TAGGED_RAW
+const bool SB_TAGGED_RAW_POINTERS = true;
+#else
+const bool SB_TAGGED_RAW_POINTERS = false;
+#endif
+
 // Generates a stream of unique borrow IDs
 sb_id_t __sb_id_fresh = 0;
 
@@ -54,6 +78,11 @@ sb_id_t sb_id_fresh() {
   return res;
 }
 
+// Returns the borrow ID of a new raw pointer
+sb_id_t sb_id_fresh_raw() {
+  return SB_TAGGED_RAW_POINTERS ? sb_id_fresh() : __sb_id_bottom;
+}
+
 // Representation of a borrow stack item
 typedef struct {
   sb_kind_t kind;
@@ -107,6 +136,51 @@ int8_t sb_stack_find(sb_stack_t *stack, sb_kind_t kind, sb_id_t id) {
   return -1;
 }
 
+// Inserts an item at index, moving the items above it up.
+void sb_stack_insert(sb_stack_t *stack, int8_t index, sb_kind_t kind,
+                     sb_id_t id) {
+  assert(stack->top < SB_MAX_STACK_SIZE);
+  for (int8_t i = stack->top; i > index; i--)
+    stack->elems[i] = stack->elems[i - 1];
+  stack->elems[index] = (sb_item_t){.kind = kind, .id = id};
+  stack->top++;
+}
+
+// Height of the stack once a write through the borrow ID pops the items above
+// the SB_UNIQUE or SB_SHARED_RW item granting it, -1 when there is none. With
+// tagged raw pointers a write granted by a SB_SHARED_RW item also keeps the
+// SB_SHARED_RW items right above it, as Miri does.
+int8_t sb_stack_write_top(sb_stack_t *stack, sb_id_t id) {
+  int8_t new_top = -1;
+  for (int8_t i = 0; (i < SB_MAX_STACK_SIZE) && (i < stack->top); i++) {
+    sb_kind_t kind = stack->elems[i].kind;
+    if (new_top < 0) {
+      if (stack->elems[i].id == id &&
+          (kind == SB_UNIQUE || kind == SB_SHARED_RW)) {
+        new_top = i + 1;
+        if (!SB_TAGGED_RAW_POINTERS || kind != SB_SHARED_RW)
+          break;
+      }
+    } else if (kind == SB_SHARED_RW) {
+      new_top = i + 1;
+    } else {
+      break;
+    }
+  }
+  return new_top;
+}
+
+// Stacked Borrows 2.1 raw reborrow: no access, the SB_SHARED_RW item is
+// inserted right above the item granting a write through the parent borrow ID
+// and the raw pointers already sharing it.
+void sb_stack_insert_raw(sb_stack_t *stack, sb_id_t parent_id, sb_id_t new_id) {
+  int8_t index = sb_stack_write_top(stack, parent_id);
+  __CPROVER_assert(index >= 0, "SHARED_RW reborrow");
+  if (index < 0)
+    __CPROVER_assume(false);
+  sb_stack_insert(stack, index, SB_SHARED_RW, new_id);
+}
+
 // shadow map that associates a borrow ID to each pointer variable of the
 // program The borrow ID is stored under the object ID of the memory location
 // that contains the pointer variable.
@@ -148,6 +222,26 @@ sb_stack_t *sb_stack_get(void *ptr) {
   return *shadow_stack;
 }
 
+// Shadow memory that associates each byte with the kind of its allocation
+shadow_map_t __sb_alloc_kind_map;
+
+void sb_alloc_kind_map_init() {
+  shadow_map_init(&__sb_alloc_kind_map, sizeof(sb_alloc_kind_t));
+}
+
+void sb_alloc_kind_set(void *ptr, sb_alloc_kind_t alloc_kind) {
+  *(sb_alloc_kind_t *)shadow_map_get(&__sb_alloc_kind_map, ptr) = alloc_kind;
+}
+
+sb_alloc_kind_t sb_alloc_kind_get(void *ptr) {
+  return *(sb_alloc_kind_t *)shadow_map_get(&__sb_alloc_kind_map, ptr);
+}
+
+// Reports uses of a local after it went out of scope
+void sb_check_live(void *ptr) {
+  __CPROVER_assert(sb_alloc_kind_get(ptr) != SB_ALLOC_DEAD, "use after scope");
+}
+
 // initialise ghost state for stacked borrows
 #define SB_INIT(symbolic_size, max_stack_size)                                 \
   do {                                                                         \
@@ -155,6 +249,7 @@ sb_stack_t *sb_stack_get(void *ptr) {
     SB_MAX_STACK_SIZE = max_stack_size;                                        \
     sb_id_map_init();                                                          \
     sb_stack_map_init();                                                       \
+    sb_alloc_kind_map_init();                                                  \
   } while (0)
 
 ////// stacked borrows rules from the paper //////
@@ -165,6 +260,7 @@ sb_stack_t *sb_stack_get(void *ptr) {
 // through a mutable ref.
 #define NEW_LOCAL(local) sb_new_local(&local)
 void sb_new_local(void *ptr) {
+  sb_alloc_kind_set(ptr, SB_ALLOC_STACK);
   sb_id_t fresh_id = sb_id_fresh();
   sb_id_map_set_local(ptr, fresh_id);
   sb_stack_push(sb_stack_get(ptr), SB_UNIQUE, fresh_id);
@@ -176,11 +272,51 @@ void sb_new_local(void *ptr) {
 // the fresh pointer value. That pointer variable uniquely owns the object.
 #define NEW_DYNAMIC(ptr) sb_new_dynamic(&ptr)
 void sb_new_dynamic(void **ptr) {
+  sb_alloc_kind_set(*ptr, SB_ALLOC_HEAP);
   sb_id_t fresh_id = sb_id_fresh();
   sb_id_map_set_ptr(ptr, fresh_id);
   sb_stack_push(sb_stack_get(*ptr), SB_UNIQUE, fresh_id);
 }
 
+// Initialises the borrow stack for an immutable static. The static is only
+// ever shared, so the stack starts with a SB_SHARED_RO and any write to the
+// static, even through its own ID, is rejected.
+#define NEW_STATIC(static_var) sb_new_static(&static_var)
+void sb_new_static(void *ptr) {
+  sb_alloc_kind_set(ptr, SB_ALLOC_STATIC);
+  sb_id_t fresh_id = sb_id_fresh();
+  sb_id_map_set_local(ptr, fresh_id);
+  sb_stack_push(sb_stack_get(ptr), SB_SHARED_RO, fresh_id);
+}
+
+// Initialises the borrow stack for a static mut. Accesses to a static mut go
+// through raw pointers, so the stack starts with a SB_SHARED_RW.
+#define NEW_STATIC_MUT(static_var) sb_new_static_mut(&static_var)
+void sb_new_static_mut(void *ptr) {
+  sb_alloc_kind_set(ptr, SB_ALLOC_STATIC_MUT);
+  sb_id_t fresh_id = sb_id_fresh_raw();
+  sb_id_map_set_local(ptr, fresh_id);
+  sb_stack_push(sb_stack_get(ptr), SB_SHARED_RW, fresh_id);
+}
+
+// StorageDead: the local goes out of scope. Its borrow stack is emptied and
+// any later use through a borrow derived from it is a use after scope. Since
+// there are no protectors, a local still borrowed by an ongoing call is not
+// reported here, see storage_dead in test.rs.
+#define STORAGE_DEAD(local) sb_storage_dead(&local)
+void sb_storage_dead(void *ptr) {
+  sb_alloc_kind_set(ptr, SB_ALLOC_DEAD);
+  sb_stack_get(ptr)->top = 0;
+}
+
+// StorageLive: the local comes back into scope, e.g. on the next iteration
+// of a loop, with a fresh borrow stack.
+#define STORAGE_LIVE(local) sb_storage_live(&local)
+void sb_storage_live(void *ptr) {
+  sb_stack_get(ptr)->top = 0;
+  sb_new_local(ptr);
+}
+
 #define UNIQUE_FROM_LOCAL(new_ref, local)                                      \
   sb_new_mut_from_local(&new_ref, &local)
 
@@ -224,6 +360,7 @@ void sb_new_mut_from_ref(void **new_ref, void **old_ref) {
   } while (0)
 
 bool sb_use1_local(void *used) {
+  sb_check_live(used);
   sb_id_t used_id = sb_id_map_get_local(used);
   sb_stack_t *stack = sb_stack_get(used);
   for (int8_t i = 0; (i < SB_MAX_STACK_SIZE) && (i < stack->top); i++) {
@@ -244,6 +381,7 @@ bool sb_use1_local(void *used) {
   } while (0)
 
 bool sb_use1(void **used) {
+  sb_check_live(*used);
   sb_id_t used_id = sb_id_map_get_ptr(used);
   sb_stack_t *stack = sb_stack_get(*used);
   for (int8_t i = 0; (i < SB_MAX_STACK_SIZE) && (i < stack->top); i++) {
@@ -260,8 +398,13 @@ bool sb_use1(void **used) {
 
 // New raw pointer from the address of a local variable.
 void sb_new_raw_from_local(void **new_raw, void *local) {
-  sb_id_map_set_ptr(new_raw, __sb_id_bottom);
-  sb_stack_push(sb_stack_get(local), SB_SHARED_RW, __sb_id_bottom);
+  sb_id_t new_id = sb_id_fresh_raw();
+  sb_id_map_set_ptr(new_raw, new_id);
+  if (SB_TAGGED_RAW_POINTERS)
+    sb_stack_insert_raw(sb_stack_get(local), sb_id_map_get_local(local),
+                        new_id);
+  else
+    sb_stack_push(sb_stack_get(local), SB_SHARED_RW, new_id);
 }
 
 #define SHARED_RW_FROM_REF(new_raw, old_ref)                                   \
@@ -269,8 +412,13 @@ void sb_new_raw_from_local(void **new_raw, void *local) {
 
 // New raw pointer from a reference.
 void sb_new_raw_from_ref(void **new_raw, void **old_ref) {
-  sb_id_map_set_ptr(new_raw, __sb_id_bottom);
-  sb_stack_push(sb_stack_get(*old_ref), SB_SHARED_RW, __sb_id_bottom);
+  sb_id_t new_id = sb_id_fresh_raw();
+  sb_id_map_set_ptr(new_raw, new_id);
+  if (SB_TAGGED_RAW_POINTERS)
+    sb_stack_insert_raw(sb_stack_get(*old_ref), sb_id_map_get_ptr(old_ref),
+                        new_id);
+  else
+    sb_stack_push(sb_stack_get(*old_ref), SB_SHARED_RW, new_id);
 }
 
 #define TRANSMUTE_REF(new_ref, old_ref) sb_transmute_ref(&new_ref, &old_ref)
@@ -284,6 +432,9 @@ void sb_transmute_ref(void **new_ref, void **old_ref) {
 // USE-2 Rule from the paper (replaces USE-1).
 // When writing to a memory location check that a mutable ref or raw pointer
 // borrow with same ID is in the stack and pop anything above it.
+// IDs are unique and __sb_id_bottom is only ever used by SB_SHARED_RW items,
+// so matching on the ID finds the right kind of borrow in both modes, see
+// sb_stack_write_top.
 
 #define USE2_LOCAL(used)                                                       \
   do {                                                                         \
@@ -294,16 +445,14 @@ void sb_transmute_ref(void **new_ref, void **old_ref) {
   } while (0)
 
 bool sb_use2_local(void *used) {
+  sb_check_live(used);
   sb_id_t used_id = sb_id_map_get_local(used);
-  sb_kind_t kind = (used_id == __sb_id_bottom) ? SB_SHARED_RW : SB_UNIQUE;
   sb_stack_t *stack = sb_stack_get(used);
-  for (int8_t i = 0; (i < SB_MAX_STACK_SIZE) && (i < stack->top); i++) {
-    if (stack->elems[i].id == used_id && stack->elems[i].kind == kind) {
-      stack->top = i + 1;
-      return true;
-    }
-  }
-  return false;
+  int8_t new_top = sb_stack_write_top(stack, used_id);
+  if (new_top < 0)
+    return false;
+  stack->top = new_top;
+  return true;
 }
 
 #define USE2(used)                                                             \
@@ -315,16 +464,38 @@ bool sb_use2_local(void *used) {
   } while (0)
 
 bool sb_use2(void **used) {
+  sb_check_live(*used);
   sb_id_t used_id = sb_id_map_get_ptr(used);
-  sb_kind_t kind = (used_id == __sb_id_bottom) ? SB_SHARED_RW : SB_UNIQUE;
   sb_stack_t *stack = sb_stack_get(*used);
-  for (int8_t i = 0; (i < SB_MAX_STACK_SIZE) && (i < stack->top); i++) {
-    if (stack->elems[i].id == used_id && stack->elems[i].kind == kind) {
-      stack->top = i + 1;
-      return true;
-    }
-  }
-  return false;
+  int8_t new_top = sb_stack_write_top(stack, used_id);
+  if (new_top < 0)
+    return false;
+  stack->top = new_top;
+  return true;
+}
+
+// Deallocation of the heap object ptr points to, e.g. free(ptr) or
+// drop(Box::from_raw(ptr)). Freeing is a write through ptr that must be
+// granted, afterwards the object is dead like a local after STORAGE_DEAD.
+// Only heap objects can be freed: freeing a local or a static is UB. As for
+// STORAGE_DEAD there are no protectors, see deallocate in test.rs.
+#define FREE(ptr)                                                              \
+  do {                                                                         \
+    bool result = sb_free(&ptr);                                               \
+    __CPROVER_assert(result, "FREE " #ptr);                                    \
+    if (!result)                                                               \
+      __CPROVER_assume(false);                                                 \
+  } while (0)
+
+bool sb_free(void **ptr) {
+  sb_check_live(*ptr);
+  __CPROVER_assert(sb_alloc_kind_get(*ptr) == SB_ALLOC_HEAP,
+                   "free of an allocation not on the heap");
+  if (!sb_use2(ptr))
+    return false;
+  sb_alloc_kind_set(*ptr, SB_ALLOC_DEAD);
+  sb_stack_get(*ptr)->top = 0;
+  return true;
 }
 
 #define SHARED_RO_FROM_LOCAL(new_ref, local)                                   \
@@ -362,6 +533,7 @@ void sb_new_shared_from_ref(void **new_ref, void **old_ref) {
   } while (0)
 
 bool sb_read1_local(void *used) {
+  sb_check_live(used);
   sb_id_t used_id = sb_id_map_get_local(used);
   sb_stack_t *stack = sb_stack_get(used);
   bool found = false;
@@ -392,6 +564,7 @@ bool sb_read1_local(void *used) {
   } while (0)
 
 bool sb_read1(void **used) {
+  sb_check_live(*used);
   sb_id_t used_id = sb_id_map_get_ptr(used);
   sb_stack_t *stack = sb_stack_get(*used);
   bool found = false;
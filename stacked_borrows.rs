@@ -0,0 +1,91 @@
+// Copyright Jacob Salzberg
+// SPDX-License-Identifier: Apache-2.0
+
+// The `stacked_borrows` proc-macro crate, built with `make stacked_borrows`.
+//
+// `#[stacked_borrows::instrument]` rewrites the body of the function it is
+// put on as described in instrument.rs. The instrumented code uses the
+// macros of macros.rs and the `sb_*` functions of the model the file
+// defines.
+//
+// The rewriting works on source text, which has no spans. So that errors in
+// the instrumented function still point into it, every identifier of the
+// item is suffixed with the index of its span before the rewriting, and the
+// suffix is turned back into that span afterwards. Identifiers the
+// instrumentation derives from them, such as `g__arg0` from `g`, get the
+// span of their source. Other tokens get the span of the attribute.
+extern crate proc_macro;
+
+use proc_macro::{Group, Ident, Span, TokenStream, TokenTree};
+
+#[path = "instrument.rs"]
+mod instrument;
+
+use instrument::SPAN_MARKER;
+
+// identifiers the instrumentation matches on, or that cannot be renamed
+const KEYWORDS: [&str; 40] = [
+    "_", "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum", "extern", "false",
+    "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut", "pub", "ref", "return", "self",
+    "Self", "static", "struct", "super", "trait", "true", "type", "union", "unsafe", "use", "where", "while",
+];
+
+#[proc_macro_attribute]
+pub fn instrument(_attr: TokenStream, item: TokenStream) -> TokenStream {
+    let mut spans = Vec::new();
+    let marked = mark_spans(item.clone(), &mut spans);
+    let result = instrument::instrument_function(&marked.to_string())
+        .and_then(|source| source.parse::<TokenStream>().map_err(|error| error.to_string()));
+    match result {
+        Ok(instrumented) => restore_spans(instrumented, &spans),
+        Err(message) => {
+            // keep the function as written, so that its own errors are
+            // reported where they are and its callers still resolve it
+            let mut output: TokenStream = format!("compile_error!({:?});", message).parse().unwrap();
+            output.extend(item);
+            output
+        }
+    }
+}
+
+// Suffixes every identifier with the index of its span in spans.
+fn mark_spans(stream: TokenStream, spans: &mut Vec<Span>) -> TokenStream {
+    stream.into_iter().map(|tree| match tree {
+        TokenTree::Ident(ident) => {
+            let name = ident.to_string();
+            if KEYWORDS.contains(&name.as_str()) || name.starts_with("r#") {
+                return TokenTree::Ident(ident);
+            }
+            spans.push(ident.span());
+            TokenTree::Ident(Ident::new(&format!("{}{}{}", name, SPAN_MARKER, spans.len() - 1), ident.span()))
+        }
+        TokenTree::Group(group) => {
+            let mut marked = Group::new(group.delimiter(), mark_spans(group.stream(), spans));
+            marked.set_span(group.span());
+            TokenTree::Group(marked)
+        }
+        tree => tree,
+    }).collect()
+}
+
+// Removes the suffixes of mark_spans and gives identifiers their span back.
+fn restore_spans(stream: TokenStream, spans: &[Span]) -> TokenStream {
+    stream.into_iter().map(|tree| match tree {
+        TokenTree::Ident(ident) => {
+            let name = ident.to_string();
+            match name.find(SPAN_MARKER) {
+                Some(start) => {
+                    let digits = &name[start + SPAN_MARKER.len()..];
+                    let end = digits.find(|c: char| !c.is_ascii_digit()).unwrap_or(digits.len());
+                    let span = digits[..end].parse().ok().and_then(|index: usize| spans.get(index).copied())
+                        .unwrap_or_else(Span::call_site);
+                    let unmarked = format!("{}{}", &name[..start], &digits[end..]);
+                    TokenTree::Ident(Ident::new(&unmarked, span))
+                }
+                None => TokenTree::Ident(ident),
+            }
+        }
+        TokenTree::Group(group) => TokenTree::Group(Group::new(group.delimiter(), restore_spans(group.stream(), spans))),
+        tree => tree,
+    }).collect()
+}
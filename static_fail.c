@@ -0,0 +1,32 @@
+#ifdef DEMONIC
+#include "stacked_borrows_demonic.h"
+#else
+#include "stacked_borrows.h"
+#endif
+
+/// writes to an immutable static
+static const int answer = 42;
+
+int main() {
+  SB_INIT(true, 16);
+
+  // static ANSWER: i32 = 42;
+  NEW_STATIC(answer);
+
+  // let x = &ANSWER;
+  READ1_LOCAL(answer);
+  int *x = (int *)&answer;
+  SHARED_RO_FROM_LOCAL(x, answer);
+
+  // let val = *x;
+  READ1(x);
+  int val = *x;
+
+  // let raw_pointer: *mut i32 = unsafe { mem::transmute(x) };
+  int *raw_pointer = x;
+  TRANSMUTE_REF(raw_pointer, x);
+
+  // unsafe { *raw_pointer = 13 };
+  USE2(raw_pointer); // should fail
+  return 0;
+}
@@ -0,0 +1,32 @@
+#ifdef DEMONIC
+#include "stacked_borrows_demonic.h"
+#else
+#include "stacked_borrows.h"
+#endif
+
+/// writes to a static mut through raw pointers
+static int counter = 0;
+
+int main() {
+  SB_INIT(true, 16);
+
+  // static mut COUNTER: i32 = 0;
+  NEW_STATIC_MUT(counter);
+
+  // let raw_pointer = unsafe { addr_of_mut!(COUNTER) };
+  int *raw_pointer = &counter;
+  SHARED_RW_FROM_LOCAL(raw_pointer, counter);
+
+  // unsafe { *raw_pointer += 1 };
+  USE2(raw_pointer);
+  *raw_pointer += 1;
+
+  // unsafe { COUNTER += 1 };
+  USE2_LOCAL(counter);
+  counter += 1;
+
+  // unsafe { *raw_pointer += 1 };
+  USE2(raw_pointer);
+  *raw_pointer += 1;
+  return 0;
+}
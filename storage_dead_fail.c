@@ -0,0 +1,28 @@
+#ifdef DEMONIC
+#include "stacked_borrows_demonic.h"
+#else
+#include "stacked_borrows.h"
+#endif
+
+/// uses a reference to a local after the local went out of scope
+int main() {
+  SB_INIT(true, 16);
+
+  int *x;
+  {
+    // let local = 5;
+    int local = 5;
+    NEW_LOCAL(local);
+
+    // x = &mut local;
+    x = &local;
+    UNIQUE_FROM_LOCAL(x, local);
+
+    // end of scope
+    STORAGE_DEAD(local);
+  }
+
+  // *x = 42;
+  USE2(x);
+  return 0;
+}
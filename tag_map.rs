@@ -0,0 +1,147 @@
+// Copyright Jacob Salzberg
+// SPDX-License-Identifier: Apache-2.0
+
+// Rust counterpart of `__sb_id_map` in stacked_borrows.h.
+//
+// The tag of a pointer lives in shadow memory under the address of the
+// variable holding the pointer, so instrumented code only needs that
+// variable to find it, e.g. `use_2_at(&x)` instead of passing `x__pointer`,
+// `x__pointer_kind` and `x__id` around. The tag of a local is stored under
+// the address of the local itself, as with `sb_id_map_set_local`.
+//
+// Arguments do not live in the caller, so tags cross function calls through
+// a small buffer: the caller passes the tag of each pointer argument, the
+// callee binds it to its parameter. Instrumented functions thus keep their
+// original signatures.
+#![allow(dead_code)]
+
+use super::shadow_map::ShadowMap;
+
+// maximum number of pointer arguments of an instrumented function
+pub const MAX_CALL_ARGS: usize = 8;
+
+/// Retag of a parameter on function entry, see fn_entry! in macros.rs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Retag {
+    Unique,
+    Shared,
+    // raw pointers keep the tag they are passed with
+    None,
+}
+
+/// Pointer types whose variables carry a tag in the tag map.
+pub trait PointerSlot {
+    type Target;
+    const RETAG: Retag;
+    fn address(&self) -> *const Self::Target;
+}
+
+impl<T> PointerSlot for &T {
+    type Target = T;
+    const RETAG: Retag = Retag::Shared;
+    fn address(&self) -> *const T { *self as *const T }
+}
+
+impl<T> PointerSlot for &mut T {
+    type Target = T;
+    const RETAG: Retag = Retag::Unique;
+    fn address(&self) -> *const T { &**self as *const T }
+}
+
+impl<T> PointerSlot for *const T {
+    type Target = T;
+    const RETAG: Retag = Retag::None;
+    fn address(&self) -> *const T { *self }
+}
+
+impl<T> PointerSlot for *mut T {
+    type Target = T;
+    const RETAG: Retag = Retag::None;
+    fn address(&self) -> *const T { *self as *const T }
+}
+
+/// Returns the pointer held by the variable at slot. Only the variable is
+/// read, not the memory it points to.
+pub fn slot_target<P: PointerSlot>(slot: *const P) -> *const P::Target {
+    unsafe { (*slot).address() }
+}
+
+/// Address of a variable, to tell in the macros of macros.rs whether the
+/// variable holds a pointer although they do not see its type:
+/// `(&Variable(addr_of!(x))).holds_pointer()` resolves to PointerVariable
+/// when x is a PointerSlot, since that method applies without autoref, and
+/// to the fallback ValueVariable for any other type.
+pub struct Variable<T>(pub *const T);
+
+pub trait PointerVariable {
+    fn holds_pointer(&self) -> bool { true }
+}
+
+impl<P: PointerSlot> PointerVariable for Variable<P> {}
+
+pub trait ValueVariable {
+    fn holds_pointer(&self) -> bool { false }
+}
+
+impl<T> ValueVariable for &Variable<T> {}
+
+/// Pointer value kind and tag of every pointer variable and local.
+#[derive(Debug)]
+pub struct TagMap {
+    tags: ShadowMap<(u32, u32)>,
+    args: [(u32, u32); MAX_CALL_ARGS],
+    // tag of the pointer returned by the last call, until the caller binds it
+    ret: Option<(u32, u32)>,
+}
+
+impl TagMap {
+    /// Creates a tag map where every variable initially has the given kind
+    /// and tag.
+    pub const fn new(kind: u32, tag: u32) -> TagMap {
+        TagMap { tags: ShadowMap::new((kind, tag)), args: [(kind, tag); MAX_CALL_ARGS], ret: None }
+    }
+
+    pub fn set<P>(&mut self, slot: *const P, kind: u32, tag: u32) {
+        self.tags.set(slot, (kind, tag));
+    }
+
+    /// Returns the kind and the tag of the variable at slot.
+    pub fn get<P>(&self, slot: *const P) -> (u32, u32) {
+        self.tags.get(slot)
+    }
+
+    /// Caller side of a call: records the tag of the variable passed as
+    /// argument number index.
+    pub fn pass_arg<P>(&mut self, index: usize, slot: *const P) {
+        assert!(index < MAX_CALL_ARGS);
+        self.args[index] = self.get(slot);
+    }
+
+    /// Returns the kind and the tag passed by the caller as argument number
+    /// index.
+    pub fn arg(&self, index: usize) -> (u32, u32) {
+        assert!(index < MAX_CALL_ARGS);
+        self.args[index]
+    }
+
+    /// Callee side of a call: gives parameter number index the tag passed
+    /// by the caller.
+    pub fn bind_arg<P>(&mut self, index: usize, slot: *const P) {
+        assert!(index < MAX_CALL_ARGS);
+        let (kind, tag) = self.args[index];
+        self.set(slot, kind, tag);
+    }
+
+    /// Callee side of a return: records the tag of the returned pointer.
+    pub fn pass_return<P>(&mut self, slot: *const P) {
+        self.ret = Some(self.get(slot));
+    }
+
+    /// Caller side of a return: gives the variable the result of the call
+    /// is bound to the tag returned by the callee, if the callee passed one.
+    pub fn bind_return<P>(&mut self, slot: *const P) {
+        if let Some((kind, tag)) = self.ret.take() {
+            self.set(slot, kind, tag);
+        }
+    }
+}
@@ -33,4 +33,8 @@ int main() {
   USE1_LOCAL(my_struct.b);
   int *b = &my_struct.b;
   UNIQUE_FROM_LOCAL(a, my_struct.a);
+
+  // drop(toto);
+  FREE(toto);
+  free(toto);
 }
\ No newline at end of file
@@ -12,11 +12,41 @@ type StackItemKind = u32;
 
 const KIND_UNIQUE: StackItemKind = 0;
 const KIND_SHARED_RW: StackItemKind = 1;
+const KIND_SHARED_RO: StackItemKind = 2;
 
 type PointerValueKind = u32;
-const KIND_IDENTIFIED : StackItemKind = 0;
-const KIND_NONE: StackItemKind = 1;
+pub const KIND_IDENTIFIED : StackItemKind = 0;
+pub const KIND_NONE: StackItemKind = 1;
+// pointers cast back from integers, may use any exposed tag
+const KIND_WILDCARD: PointerValueKind = 2;
 
+// maximum number of tags exposed by pointer to integer casts
+const MAX_EXPOSED_TAGS: usize = 8;
+
+// maximum number of references protected by ongoing function calls
+const MAX_PROTECTED_TAGS: usize = 8;
+
+// maximum number of arguments in the reads, writes and protects of the
+// summary of a function, see summary! in macros.rs
+const MAX_SUMMARY_ARGS: usize = 8;
+
+// Kind of the allocation a location belongs to, decides the base item
+// of its borrow stacks.
+type AllocationKind = u32;
+// stack locals and heap allocations are owned by a Unique item
+pub const ALLOC_STACK: AllocationKind = 0;
+const ALLOC_HEAP: AllocationKind = 1;
+// `static mut` starts with a SharedRW item, any raw pointer may write to it
+const ALLOC_STATIC_MUT: AllocationKind = 2;
+// `static` and string literals start with a SharedRO item, writes are UB
+const ALLOC_STATIC: AllocationKind = 3;
+
+mod shadow_map;
+use shadow_map::ShadowMap;
+mod tag_map;
+use tag_map::{slot_target, PointerSlot, Retag, TagMap};
+#[macro_use]
+mod macros;
 
 /*
 // Uncomment to debug with println
@@ -60,145 +90,2180 @@ fn pointer_offset<U: Sized>(ptr: *const U) -> usize {
     kani::mem::pointer_offset(ptr)
 }
 
+/// Borrow stack of a byte, bottom first.
+#[derive(Clone, Copy, Debug)]
+struct BorrowStack {
+    ids: [PtrId; STACK_DEPTH],
+    kinds: [StackItemKind; STACK_DEPTH],
+    top: usize,
+}
+
+impl BorrowStack {
+    const EMPTY: BorrowStack = BorrowStack { ids: [0; STACK_DEPTH], kinds: [KIND_SHARED_RW; STACK_DEPTH], top: 0 };
+}
+
 #[derive(Debug)]
-struct SState {
-    stack_ids: [[[PtrId; STACK_DEPTH]; MAX_OBJECT_SIZE]; MAX_NUM_OBJECTS],
-    stack_kinds: [[[StackItemKind; STACK_DEPTH]; MAX_OBJECT_SIZE]; MAX_NUM_OBJECTS],
-    stack_tops: [[usize; MAX_OBJECT_SIZE]; MAX_NUM_OBJECTS],
+pub struct SState {
+    // borrow stack of every byte, empty until its allocation is created
+    stacks: ShadowMap<BorrowStack>,
+    alloc_kinds: [AllocationKind; MAX_NUM_OBJECTS],
+    alloc_sizes: [usize; MAX_NUM_OBJECTS],
+    // set by StorageDead and deallocation, the stacks of a dead allocation
+    // are empty
+    alloc_dead: [bool; MAX_NUM_OBJECTS],
+    protected_ids: [PtrId; MAX_PROTECTED_TAGS],
+    protected_count: usize,
+    exposed_objects: [usize; MAX_EXPOSED_TAGS],
+    exposed_kinds: [PointerValueKind; MAX_EXPOSED_TAGS],
+    exposed_ids: [PtrId; MAX_EXPOSED_TAGS],
+    exposed_count: usize,
+    // set while a function body is checked against its summary: accesses
+    // to the allocations that existed before the call must fall in one of
+    // the ranges of the summary, writes in one that allows them, and
+    // protectors in one of its protects
+    summary_checked: bool,
+    summary_objects: [usize; MAX_SUMMARY_ARGS],
+    summary_offsets: [usize; MAX_SUMMARY_ARGS],
+    summary_sizes: [usize; MAX_SUMMARY_ARGS],
+    summary_writes: [bool; MAX_SUMMARY_ARGS],
+    summary_protects: [bool; MAX_SUMMARY_ARGS],
+    summary_count: usize,
+    // allocations created by the body being checked
+    body_objects: [bool; MAX_NUM_OBJECTS],
+    // rejects integer to pointer casts instead of giving them wildcard
+    // provenance
+    strict_provenance: bool,
+    // Stacked Borrows 2.1: raw pointers get fresh tags recorded in their
+    // SharedRW items instead of all sharing the bottom tag 0
+    tagged_raw_pointers: bool,
+    // provenance of the pointers stored in memory, kept on every byte of
+    // the stored pointer and cleared by any other write to that byte
+    stored_pointers: ShadowMap<Option<TaggedPointer>>,
+    // kind and tag of every pointer variable and local, see tag_map.rs
+    tags: TagMap,
+    // prefix of the messages of failing checks, names the variable the
+    // current operation goes through, see macros.rs
+    context: &'static str,
     next_ptr_id: PtrId,
 }
 
 impl Default for SState {
     fn default() -> SState {
         SState {
-            stack_ids: [[[0; STACK_DEPTH]; MAX_OBJECT_SIZE]; MAX_NUM_OBJECTS],
-            stack_kinds: [[[KIND_SHARED_RW; STACK_DEPTH]; MAX_OBJECT_SIZE]; MAX_NUM_OBJECTS],
-            stack_tops: [[0; MAX_OBJECT_SIZE]; MAX_NUM_OBJECTS],
+            stacks: ShadowMap::new(BorrowStack::EMPTY),
+            alloc_kinds: [ALLOC_STACK; MAX_NUM_OBJECTS],
+            alloc_sizes: [0; MAX_NUM_OBJECTS],
+            alloc_dead: [false; MAX_NUM_OBJECTS],
+            protected_ids: [0; MAX_PROTECTED_TAGS],
+            protected_count: 0,
+            exposed_objects: [0; MAX_EXPOSED_TAGS],
+            exposed_kinds: [KIND_NONE; MAX_EXPOSED_TAGS],
+            exposed_ids: [0; MAX_EXPOSED_TAGS],
+            exposed_count: 0,
+            summary_checked: false,
+            summary_objects: [0; MAX_SUMMARY_ARGS],
+            summary_offsets: [0; MAX_SUMMARY_ARGS],
+            summary_sizes: [0; MAX_SUMMARY_ARGS],
+            summary_writes: [false; MAX_SUMMARY_ARGS],
+            summary_protects: [false; MAX_SUMMARY_ARGS],
+            summary_count: 0,
+            body_objects: [false; MAX_NUM_OBJECTS],
+            strict_provenance: false,
+            tagged_raw_pointers: false,
+            stored_pointers: ShadowMap::new(None),
+            tags: TagMap::new(KIND_NONE, 0),
+            context: "",
             next_ptr_id: 0,
         }
     }
 }
 
+/// A raw pointer value with the provenance it was derived with. The offset
+/// is relative to the allocation the pointer was derived from and may go out
+/// of bounds through wrapping arithmetic.
+#[derive(Clone, Copy, Debug)]
+struct TaggedPointer {
+    object: usize,
+    offset: isize,
+    kind: PointerValueKind,
+    tag: PtrId,
+}
+
+impl TaggedPointer {
+    fn new<U>(ptr: *const U, kind: PointerValueKind, tag: PtrId) -> TaggedPointer {
+        TaggedPointer {
+            object: pointer_object(ptr),
+            offset: pointer_offset(ptr) as isize,
+            kind,
+            tag,
+        }
+    }
+}
+
+// Transmutes change no stack, they only decide the provenance of the
+// result. A transmute between two references keeps the tag of the
+// reference, like TRANSMUTE_REF in stacked_borrows.h, and needs no function.
+
+/// `transmute::<&T, *mut T>(reference)`: unlike a cast the stack is left
+/// untouched and the raw pointer keeps the tag of the reference, so it is only
+/// granted what the reference was granted.
+fn transmute_ref_to_raw<U>(loc: *const U, kind: PointerValueKind, tag: PtrId) -> TaggedPointer {
+    TaggedPointer::new(loc, kind, tag)
+}
+
+/// `transmute::<*mut T, &mut T>(ptr)`: the reference keeps the tag of the raw
+/// pointer, no new item is pushed.
+fn transmute_raw_to_ref(ptr: TaggedPointer) -> (PointerValueKind, PtrId) {
+    (ptr.kind, ptr.tag)
+}
+
+// Returns the object, the offset and the size of the bytes covered by ptr.
+fn pointer_range<U>(ptr: *const U) -> (usize, usize, usize) {
+    let obj = pointer_object(ptr);
+    let offset = pointer_offset(ptr);
+    let size = std::mem::size_of::<U>();
+    assert!(obj < MAX_NUM_OBJECTS);
+    assert!(offset <= MAX_OBJECT_SIZE && size <= MAX_OBJECT_SIZE - offset);
+    (obj, offset, size)
+}
+
 impl SState {
-    pub fn push_shared<U>(&mut self, ptr: *const U) {
-        let obj = pointer_object(ptr);
-        let offset = pointer_offset(ptr);
-        assert!(obj < MAX_NUM_OBJECTS);
-        assert!(offset < MAX_OBJECT_SIZE);
-        let top = self.stack_tops[obj][offset];
-        assert!(top < STACK_DEPTH);
-        self.stack_tops[obj][offset] += 1;
+    /// Registers the allocation `ptr` points to and pushes the base item
+    /// matching its kind. Returns the tag of the base item.
+    pub fn new_allocation<U>(&mut self, ptr: *const U, alloc_kind: AllocationKind) -> PtrId {
+        let (obj, offset, size) = pointer_range(ptr);
+        self.alloc_kinds[obj] = alloc_kind;
+        self.alloc_sizes[obj] = offset + size;
+        self.body_objects[obj] = self.summary_checked;
+        if alloc_kind == ALLOC_STATIC_MUT {
+            self.push_shared(ptr)
+        } else if alloc_kind == ALLOC_STATIC {
+            self.push_shared_ro(ptr)
+        } else {
+            self.push_unique(ptr)
+        }
     }
 
-    pub fn push_unique<U>(&mut self, ptr: *const U) -> PtrId {
-        let obj = pointer_object(ptr);
-        let offset = pointer_offset(ptr);
-        assert!(obj < MAX_NUM_OBJECTS);
-        assert!(offset < MAX_OBJECT_SIZE);
-        let top = self.stack_tops[obj][offset];
-        assert!(top < STACK_DEPTH);
-        self.stack_kinds[obj][offset][top] = KIND_UNIQUE;
+    // Pushes the item on the stack of every byte in [offset, offset + size).
+    fn push_range(&mut self, obj: usize, offset: usize, size: usize, item_kind: StackItemKind, id: PtrId) {
+        assert!(!self.alloc_dead[obj], "Use after scope.");
+        let mut byte = offset;
+        while byte < offset + size {
+            let stack = self.stacks.get_mut_at(obj, byte);
+            assert!(stack.top < STACK_DEPTH);
+            stack.kinds[stack.top] = item_kind;
+            stack.ids[stack.top] = id;
+            stack.top += 1;
+            byte += 1;
+        }
+    }
+
+    // Returns the tag of a new item of the given kind: SharedRW items all
+    // get the bottom tag 0 unless raw pointers are tagged.
+    fn fresh_tag(&mut self, item_kind: StackItemKind) -> PtrId {
+        if item_kind == KIND_SHARED_RW && !self.tagged_raw_pointers {
+            return 0;
+        }
         let ptr_id_old = self.next_ptr_id;
-        self.stack_ids[obj][offset][top] = ptr_id_old;
-        self.stack_tops[obj][offset] += 1;
         self.next_ptr_id += 1;
         ptr_id_old
     }
 
-    fn use_2<U>(&mut self, ptr: *const U, kind: PointerValueKind, id: PtrId) {
-        let obj = pointer_object(ptr);
-        let offset = pointer_offset(ptr);
+    pub fn push_shared<U>(&mut self, ptr: *const U) -> PtrId {
+        let (obj, offset, size) = pointer_range(ptr);
+        let ptr_id_old = self.fresh_tag(KIND_SHARED_RW);
+        self.push_range(obj, offset, size, KIND_SHARED_RW, ptr_id_old);
+        ptr_id_old
+    }
+
+    pub fn push_shared_ro<U>(&mut self, ptr: *const U) -> PtrId {
+        let (obj, offset, size) = pointer_range(ptr);
+        let ptr_id_old = self.fresh_tag(KIND_SHARED_RO);
+        self.push_range(obj, offset, size, KIND_SHARED_RO, ptr_id_old);
+        ptr_id_old
+    }
+
+    pub fn push_unique<U>(&mut self, ptr: *const U) -> PtrId {
+        let (obj, offset, size) = pointer_range(ptr);
+        let ptr_id_old = self.fresh_tag(KIND_UNIQUE);
+        self.push_range(obj, offset, size, KIND_UNIQUE, ptr_id_old);
+        ptr_id_old
+    }
+
+    // Whether the item is a reference protected by an ongoing call.
+    fn item_protected(&self, item_kind: StackItemKind, item_id: PtrId) -> bool {
+        let mut protected = false;
+        let mut i = 0;
+        while (i < MAX_PROTECTED_TAGS) && (i < self.protected_count) {
+            protected |= item_kind != KIND_SHARED_RW && self.protected_ids[i] == item_id;
+            i += 1;
+        }
+        protected
+    }
+
+    // Whether a protected item sits at or above height new_top in the stack
+    // of byte (obj, offset).
+    fn protected_above(&self, obj: usize, offset: usize, new_top: usize) -> bool {
+        let stack = self.stacks.get_ref_at(obj, offset);
+        let mut protected = false;
+        let mut i = new_top;
+        while (i < STACK_DEPTH) && (i < stack.top) {
+            protected |= self.item_protected(stack.kinds[i], stack.ids[i]);
+            i += 1;
+        }
+        protected
+    }
+
+    /// Adds a protector on the reference tag for the duration of a call, as
+    /// done by the retag of reference arguments on function entry.
+    fn protect(&mut self, tag: PtrId) {
+        assert!(self.protected_count < MAX_PROTECTED_TAGS);
+        self.protected_ids[self.protected_count] = tag;
+        self.protected_count += 1;
+    }
+
+    /// Removes the protectors added since the call started, protectors end
+    /// in the reverse order of calls.
+    fn end_protect(&mut self, count: usize) {
+        assert!(count <= self.protected_count);
+        self.protected_count -= count;
+    }
+
+    /// StorageDead: the local goes out of scope. Its stacks are emptied so
+    /// that every tag derived from it becomes invalid, and using any of them
+    /// is reported as a use after scope. A local still protected by an
+    /// ongoing call must not die.
+    fn storage_dead<U>(&mut self, local: *const U) {
+        let obj = pointer_object(local);
         assert!(obj < MAX_NUM_OBJECTS);
-        assert!(offset < MAX_OBJECT_SIZE);
-        let top = self.stack_tops[obj][offset];
-        let mut found = false;
-        if kind == KIND_IDENTIFIED {
-            let mut i = 0;
-            let mut new_top = 0;
-            while (i < STACK_DEPTH) && (i < top) {
-                if self.stack_kinds[obj][offset][i] == KIND_UNIQUE && self.stack_ids[obj][offset][i] == id {
-                    new_top = i+1;
-                    found = true;
+        assert!(!self.alloc_dead[obj], "StorageDead on a dead local.");
+        let mut byte = 0;
+        while byte < self.alloc_sizes[obj] {
+            assert!(!self.protected_above(obj, byte, 0), "Protected local goes out of scope.");
+            byte += 1;
+        }
+        self.stacks.clear_object(obj);
+        self.stored_pointers.clear_object(obj);
+        self.alloc_dead[obj] = true;
+    }
+
+    /// `drop(Box::from_raw(ptr))` or `dealloc(ptr)`: frees the heap
+    /// allocation ptr points to. Like in Miri, deallocating is a write to
+    /// every byte of the allocation that must not pop a protected item, and
+    /// afterwards every tag of the allocation is invalid.
+    fn deallocate(&mut self, ptr: TaggedPointer) {
+        let obj = ptr.object;
+        assert!(obj < MAX_NUM_OBJECTS);
+        assert!(self.alloc_kinds[obj] == ALLOC_HEAP, "Deallocation of memory not on the heap.");
+        assert!(ptr.offset == 0, "Deallocation not at the start of the allocation.");
+        let size = self.alloc_sizes[obj];
+        self.access_range(obj, 0, size, ptr.kind, ptr.tag, true);
+        let mut byte = 0;
+        while byte < size {
+            assert!(!self.protected_above(obj, byte, 0), "Protected item deallocated.");
+            byte += 1;
+        }
+        self.stacks.clear_object(obj);
+        self.stored_pointers.clear_object(obj);
+        self.alloc_dead[obj] = true;
+    }
+
+    /// StorageLive: the local comes (back) into scope, e.g. on the next
+    /// iteration of a loop, with fresh stacks. Returns the tag of the local.
+    fn storage_live<U>(&mut self, local: *const U) -> PtrId {
+        let obj = pointer_object(local);
+        assert!(obj < MAX_NUM_OBJECTS);
+        self.stacks.clear_object(obj);
+        self.stored_pointers.clear_object(obj);
+        self.alloc_dead[obj] = false;
+        self.new_allocation(local, ALLOC_STACK)
+    }
+
+    // Whether the item was exposed by a pointer to integer cast.
+    fn item_exposed(&self, obj: usize, item_kind: StackItemKind, item_id: PtrId) -> bool {
+        let mut exposed = false;
+        let mut i = 0;
+        while (i < MAX_EXPOSED_TAGS) && (i < self.exposed_count) {
+            if self.exposed_objects[i] == obj {
+                if self.exposed_kinds[i] == KIND_IDENTIFIED {
+                    exposed |= item_kind != KIND_SHARED_RW && self.exposed_ids[i] == item_id;
+                } else {
+                    exposed |= item_kind == KIND_SHARED_RW
+                        && (!self.tagged_raw_pointers || self.exposed_ids[i] == item_id);
                 }
-                i += 1;
             }
-            self.stack_tops[obj][offset] = new_top;
+            i += 1;
+        }
+        exposed
+    }
+
+    // Whether the item grants an access with the given tag. A wildcard
+    // pointer is granted what any exposed item grants.
+    fn grants(&self, obj: usize, item_kind: StackItemKind, item_id: PtrId, kind: PointerValueKind, id: PtrId,
+              write: bool) -> bool {
+        if kind == KIND_IDENTIFIED {
+            let matching = item_id == id;
+            if write { item_kind == KIND_UNIQUE && matching } else { item_kind != KIND_SHARED_RW && matching }
+        } else if kind == KIND_WILDCARD {
+            (!write || item_kind != KIND_SHARED_RO) && self.item_exposed(obj, item_kind, item_id)
         } else {
-            let mut i = 0;
-            let mut new_top = 0;
-            while (i < STACK_DEPTH) && (i < top) {
-                if self.stack_kinds[obj][offset][i] == KIND_SHARED_RW {
-                    new_top = i+1;
-                    found = true;
-                }
-                i += 1;
+            item_kind == KIND_SHARED_RW && (!self.tagged_raw_pointers || item_id == id)
+        }
+    }
+
+    // Returns the height of the stack of byte (obj, offset) after an access
+    // with the given tag, or None if no item grants the access.
+    // A write (USE-2) keeps everything up to the granting item, a read
+    // (READ-1) also keeps the SharedRO items right above it. With tagged raw
+    // pointers a write granted by a SharedRW item also keeps the SharedRW
+    // items right above it, the sibling raw pointers, as in Miri. A wildcard
+    // access uses the topmost exposed item that grants it, so that it
+    // invalidates as little as possible.
+    fn granted_top(&self, obj: usize, offset: usize, kind: PointerValueKind, id: PtrId, write: bool) -> Option<usize> {
+        let stack = self.stacks.get_ref_at(obj, offset);
+        let mut found = false;
+        let mut i = 0;
+        let mut new_top = 0;
+        while (i < STACK_DEPTH) && (i < stack.top) {
+            let item_kind = stack.kinds[i];
+            if self.grants(obj, item_kind, stack.ids[i], kind, id, write) {
+                new_top = i+1;
+                found = true;
+            } else if !write && found && new_top == i && item_kind == KIND_SHARED_RO {
+                new_top = i+1;
+            } else if write && found && new_top == i && item_kind == KIND_SHARED_RW && self.tagged_raw_pointers
+                && stack.kinds[i - 1] == KIND_SHARED_RW {
+                new_top = i+1;
+            }
+            i += 1;
+        }
+        if found { Some(new_top) } else { None }
+    }
+
+    /// Starts checking a function body against its summary. Until
+    /// end_summary_check, the body may only access its own allocations and
+    /// the ranges added with summary_arg.
+    fn begin_summary_check(&mut self) {
+        assert!(!self.summary_checked, "Nested summary checks.");
+        self.summary_checked = true;
+        self.summary_count = 0;
+        self.body_objects = [false; MAX_NUM_OBJECTS];
+    }
+
+    /// Allows the body being checked to read the bytes ptr points to, and to
+    /// write them if write is set.
+    fn summary_arg<U>(&mut self, ptr: *const U, write: bool) {
+        self.add_summary_range(ptr, write, false);
+    }
+
+    /// Allows the body being checked to protect the bytes ptr points to.
+    fn summary_protect<U>(&mut self, ptr: *const U) {
+        self.add_summary_range(ptr, false, true);
+    }
+
+    fn add_summary_range<U>(&mut self, ptr: *const U, write: bool, protect: bool) {
+        let (obj, offset, size) = pointer_range(ptr);
+        assert!(self.summary_count < MAX_SUMMARY_ARGS);
+        self.summary_objects[self.summary_count] = obj;
+        self.summary_offsets[self.summary_count] = offset;
+        self.summary_sizes[self.summary_count] = size;
+        self.summary_writes[self.summary_count] = write;
+        self.summary_protects[self.summary_count] = protect;
+        self.summary_count += 1;
+    }
+
+    fn end_summary_check(&mut self) {
+        self.summary_checked = false;
+        self.summary_count = 0;
+    }
+
+    // Whether the summary being checked, if any, allows the access to byte
+    // (obj, offset), or a protector on it when protect is set.
+    fn within_summary(&self, obj: usize, offset: usize, write: bool, protect: bool) -> bool {
+        let mut allowed = !self.summary_checked || self.body_objects[obj];
+        let mut i = 0;
+        while (i < MAX_SUMMARY_ARGS) && (i < self.summary_count) {
+            allowed |= self.summary_objects[i] == obj
+                && self.summary_offsets[i] <= offset && offset < self.summary_offsets[i] + self.summary_sizes[i]
+                && self.summary_protects[i] == protect
+                && (!write || self.summary_writes[i]);
+            i += 1;
+        }
+        allowed
+    }
+
+    // Index of the topmost item of the stack of byte (obj, offset) granting
+    // the access with the given tag, if any.
+    fn granting_index(&self, obj: usize, offset: usize, kind: PointerValueKind, id: PtrId, write: bool) -> Option<usize> {
+        let stack = self.stacks.get_ref_at(obj, offset);
+        let mut index = None;
+        let mut i = 0;
+        while (i < STACK_DEPTH) && (i < stack.top) {
+            if self.grants(obj, stack.kinds[i], stack.ids[i], kind, id, write) {
+                index = Some(i);
+            }
+            i += 1;
+        }
+        index
+    }
+
+    /// Checks that the pointer held by result, returned by the body being
+    /// checked, is a reborrow of the pointer held by arg as its summary
+    /// declares with `returns(&mut *arg)` or `returns(&*arg)`: it points to
+    /// the same bytes, and on each of them the item granting it sits at or
+    /// above the one granting arg.
+    fn check_summary_return<P: PointerSlot, Q: PointerSlot>(&self, result: *const P, arg: *const Q, write: bool) {
+        let (kind, tag) = self.tags.get(result);
+        let (arg_kind, arg_tag) = self.tags.get(arg);
+        let (obj, offset, size) = pointer_range(slot_target(result));
+        assert!((obj, offset, size) == pointer_range(slot_target(arg)),
+                "{}Returned pointer does not point to the argument.", self.context);
+        let mut byte = offset;
+        while byte < offset + size {
+            let derived = match (self.granting_index(obj, byte, kind, tag, write),
+                                 self.granting_index(obj, byte, arg_kind, arg_tag, write)) {
+                (Some(index), Some(arg_index)) => index >= arg_index,
+                _ => false,
+            };
+            assert!(derived, "{}Returned pointer not derived from the argument at offset {}.", self.context, byte);
+            byte += 1;
+        }
+    }
+
+    // Checks the access on every byte in [offset, offset + size) before
+    // updating any stack, so that a failing access reports the first
+    // offending byte and leaves the stacks consistent.
+    fn access_range(&mut self, obj: usize, offset: usize, size: usize, kind: PointerValueKind, id: PtrId, write: bool) {
+        assert!(!self.alloc_dead[obj], "{}Use after scope.", self.context);
+        let mut byte = offset;
+        while byte < offset + size {
+            let new_top = self.granted_top(obj, byte, kind, id, write);
+            assert!(new_top.is_some(), "{}Stack violated at offset {}.", self.context, byte);
+            assert!(!self.protected_above(obj, byte, new_top.unwrap()),
+                    "{}Protected item popped at offset {}.", self.context, byte);
+            assert!(self.within_summary(obj, byte, write, false),
+                    "{}Access outside the function summary at offset {}.", self.context, byte);
+            byte += 1;
+        }
+        let mut byte = offset;
+        while byte < offset + size {
+            let new_top = self.granted_top(obj, byte, kind, id, write).unwrap();
+            self.stacks.get_mut_at(obj, byte).top = new_top;
+            if write {
+                self.stored_pointers.set_at(obj, byte, None);
+            }
+            byte += 1;
+        }
+    }
+
+    // Whether every byte ptr points to has an item granting the access with
+    // the given tag and, when protectors is set, whether the access would
+    // pop no protected item. Changes nothing.
+    fn granted<U>(&self, ptr: *const U, kind: PointerValueKind, id: PtrId, write: bool, protectors: bool) -> bool {
+        let (obj, offset, size) = pointer_range(ptr);
+        let mut granted = !self.alloc_dead[obj];
+        let mut byte = offset;
+        while granted && byte < offset + size {
+            granted = match self.granted_top(obj, byte, kind, id, write) {
+                Some(new_top) => !protectors || !self.protected_above(obj, byte, new_top),
+                None => false,
+            };
+            byte += 1;
+        }
+        granted
+    }
+
+    /// Whether reading through ptr with the given tag is allowed. Unlike
+    /// read_1 it updates no stack, so it can be used in contracts.
+    pub fn can_read<U>(&self, ptr: *const U, kind: PointerValueKind, id: PtrId) -> bool {
+        self.granted(ptr, kind, id, false, true)
+    }
+
+    /// Whether writing through ptr with the given tag is allowed. Unlike
+    /// use_2 it updates no stack, so it can be used in contracts.
+    pub fn can_write<U>(&self, ptr: *const U, kind: PointerValueKind, id: PtrId) -> bool {
+        self.granted(ptr, kind, id, true, true)
+    }
+
+    /// Whether the tag is still on the stacks of the bytes ptr points to,
+    /// i.e. whether it has not been invalidated by a conflicting access.
+    /// Protectors are ignored: a live tag may still not be usable because
+    /// its access would pop a protected item.
+    pub fn tag_live<U>(&self, ptr: *const U, kind: PointerValueKind, id: PtrId) -> bool {
+        self.granted(ptr, kind, id, false, false)
+    }
+
+    /// Borrow stack of the byte ptr points to, bottom first, as the kinds
+    /// and the tags of its items.
+    pub fn stack_at<U>(&self, ptr: *const U) -> (&[StackItemKind], &[PtrId]) {
+        let (obj, offset, _) = pointer_range(ptr);
+        let stack = self.stacks.get_ref_at(obj, offset);
+        let top = stack.top.min(STACK_DEPTH);
+        (&stack.kinds[..top], &stack.ids[..top])
+    }
+
+    /// `*slot = value` where the value is a reference or a raw pointer: a
+    /// write to the slot that also records the provenance of the value, so
+    /// that it can be loaded back from memory with its tag.
+    fn store_pointer<P>(&mut self, slot: *const P, kind: PointerValueKind, id: PtrId, value: TaggedPointer) {
+        let (obj, offset, size) = pointer_range(slot);
+        self.store_pointer_range(obj, offset, size, kind, id, value);
+    }
+
+    // Writes the bytes [offset, offset + size) and records value on each.
+    fn store_pointer_range(&mut self, obj: usize, offset: usize, size: usize, kind: PointerValueKind, id: PtrId,
+                           value: TaggedPointer) {
+        self.access_range(obj, offset, size, kind, id, true);
+        let mut byte = offset;
+        while byte < offset + size {
+            self.stored_pointers.set_at(obj, byte, Some(value));
+            byte += 1;
+        }
+    }
+
+    /// `*slot` where the slot holds a reference or a raw pointer: a read of
+    /// the slot that returns the provenance stored with the value. Every
+    /// byte must still carry it, bytes written as plain data lose it.
+    fn load_pointer<P>(&mut self, slot: *const P, kind: PointerValueKind, id: PtrId) -> TaggedPointer {
+        let (obj, offset, size) = pointer_range(slot);
+        self.load_pointer_range(obj, offset, size, kind, id)
+    }
+
+    // Reads the bytes [offset, offset + size) and returns the pointer
+    // recorded on them.
+    fn load_pointer_range(&mut self, obj: usize, offset: usize, size: usize, kind: PointerValueKind,
+                          id: PtrId) -> TaggedPointer {
+        self.access_range(obj, offset, size, kind, id, false);
+        let mut byte = offset;
+        while byte < offset + size {
+            assert!(self.stored_pointers.get_at(obj, byte).is_some(), "{}Loaded pointer without provenance.",
+                    self.context);
+            byte += 1;
+        }
+        self.stored_pointers.get_at(obj, offset).unwrap()
+    }
+
+    pub fn use_2<U>(&mut self, ptr: *const U, kind: PointerValueKind, id: PtrId) {
+        let (obj, offset, size) = pointer_range(ptr);
+        self.access_range(obj, offset, size, kind, id, true);
+    }
+
+    pub fn read_1<U>(&mut self, ptr: *const U, kind: PointerValueKind, id: PtrId) {
+        let (obj, offset, size) = pointer_range(ptr);
+        self.access_range(obj, offset, size, kind, id, false);
+    }
+
+    // Retags the bytes [offset, offset + size) only: the parent tag is used
+    // on those bytes, then the new item is pushed on them.
+    fn reborrow_range(&mut self, obj: usize, offset: usize, size: usize, kind: PointerValueKind, tag: PtrId,
+                      item_kind: StackItemKind) -> PtrId {
+        if item_kind == KIND_SHARED_RW && self.tagged_raw_pointers {
+            return self.insert_shared_range(obj, offset, size, kind, tag);
+        }
+        self.access_range(obj, offset, size, kind, tag, item_kind != KIND_SHARED_RO);
+        let ptr_id_old = self.fresh_tag(item_kind);
+        self.push_range(obj, offset, size, item_kind, ptr_id_old);
+        ptr_id_old
+    }
+
+    // Raw reborrow of Stacked Borrows 2.1: no access, the SharedRW item is
+    // inserted where a write with the parent tag would start popping, right
+    // above the granting item and its sibling raw pointers, so that raw
+    // pointers derived from the same parent stay usable together.
+    fn insert_shared_range(&mut self, obj: usize, offset: usize, size: usize, kind: PointerValueKind,
+                           tag: PtrId) -> PtrId {
+        assert!(!self.alloc_dead[obj], "{}Use after scope.", self.context);
+        let mut byte = offset;
+        while byte < offset + size {
+            let index = self.granted_top(obj, byte, kind, tag, true);
+            assert!(index.is_some(), "{}Stack violated at offset {}.", self.context, byte);
+            byte += 1;
+        }
+        let ptr_id_old = self.fresh_tag(KIND_SHARED_RW);
+        let mut byte = offset;
+        while byte < offset + size {
+            let index = self.granted_top(obj, byte, kind, tag, true).unwrap();
+            let stack = self.stacks.get_mut_at(obj, byte);
+            assert!(stack.top < STACK_DEPTH);
+            let mut i = stack.top;
+            while i > index {
+                stack.kinds[i] = stack.kinds[i - 1];
+                stack.ids[i] = stack.ids[i - 1];
+                i -= 1;
             }
-            self.stack_tops[obj][offset] = new_top;
+            stack.kinds[index] = KIND_SHARED_RW;
+            stack.ids[index] = ptr_id_old;
+            stack.top += 1;
+            byte += 1;
+        }
+        ptr_id_old
+    }
+
+    // Returns the range of the field at field_offset in the object parent
+    // points to.
+    fn field_range<U>(parent: *const U, field_offset: usize, field_size: usize) -> (usize, usize, usize) {
+        let (obj, offset, size) = pointer_range(parent);
+        assert!(field_offset <= size && field_size <= size - field_offset);
+        (obj, offset + field_offset, field_size)
+    }
+
+    /// `&mut (*parent).field`: reborrows the field bytes only, so borrows of
+    /// disjoint fields can be alive at the same time.
+    fn new_mutable_field_ref<U>(&mut self, parent: *const U, kind: PointerValueKind, tag: PtrId,
+                                field_offset: usize, field_size: usize) -> PtrId {
+        let (obj, offset, size) = Self::field_range(parent, field_offset, field_size);
+        self.reborrow_range(obj, offset, size, kind, tag, KIND_UNIQUE)
+    }
+
+    /// `&(*parent).field`
+    fn new_shared_field_ref<U>(&mut self, parent: *const U, kind: PointerValueKind, tag: PtrId,
+                               field_offset: usize, field_size: usize) -> PtrId {
+        let (obj, offset, size) = Self::field_range(parent, field_offset, field_size);
+        self.reborrow_range(obj, offset, size, kind, tag, KIND_SHARED_RO)
+    }
+
+    // Returns the range of the count elements of elem_size bytes starting
+    // at base.
+    fn slice_range<U>(base: *const U, elem_size: usize, count: usize) -> (usize, usize, usize) {
+        let obj = pointer_object(base);
+        let offset = pointer_offset(base);
+        assert!(obj < MAX_NUM_OBJECTS);
+        assert!(elem_size == 0 || count <= MAX_OBJECT_SIZE / elem_size);
+        let size = elem_size * count;
+        assert!(offset <= MAX_OBJECT_SIZE && size <= MAX_OBJECT_SIZE - offset);
+        (obj, offset, size)
+    }
+
+    /// `&mut *slice_from_raw_parts_mut(base, count)`: reborrows the elements
+    /// of the sub-slice only, as done by `split_at_mut` or `chunks_mut`.
+    fn new_mutable_slice_ref<U>(&mut self, base: *const U, elem_size: usize, count: usize,
+                                kind: PointerValueKind, tag: PtrId) -> PtrId {
+        let (obj, offset, size) = Self::slice_range(base, elem_size, count);
+        self.reborrow_range(obj, offset, size, kind, tag, KIND_UNIQUE)
+    }
+
+    /// `&*slice_from_raw_parts(base, count)`
+    fn new_shared_slice_ref<U>(&mut self, base: *const U, elem_size: usize, count: usize,
+                               kind: PointerValueKind, tag: PtrId) -> PtrId {
+        let (obj, offset, size) = Self::slice_range(base, elem_size, count);
+        self.reborrow_range(obj, offset, size, kind, tag, KIND_SHARED_RO)
+    }
+
+    /// Write to the count elements starting at base.
+    fn use_2_slice<U>(&mut self, base: *const U, elem_size: usize, count: usize, kind: PointerValueKind, id: PtrId) {
+        let (obj, offset, size) = Self::slice_range(base, elem_size, count);
+        self.access_range(obj, offset, size, kind, id, true);
+    }
+
+    /// Read of the count elements starting at base.
+    fn read_1_slice<U>(&mut self, base: *const U, elem_size: usize, count: usize, kind: PointerValueKind, id: PtrId) {
+        let (obj, offset, size) = Self::slice_range(base, elem_size, count);
+        self.access_range(obj, offset, size, kind, id, false);
+    }
+
+    /// `ptr.offset(count)` and `ptr.add(count)`: the result keeps the
+    /// provenance of ptr and must stay in bounds of its allocation, one past
+    /// the end included.
+    fn offset_raw<U>(&self, ptr: TaggedPointer, count: isize) -> TaggedPointer {
+        let result = self.wrapping_offset_raw::<U>(ptr, count);
+        assert!(0 <= result.offset && result.offset as usize <= self.alloc_sizes[ptr.object],
+                "Pointer arithmetic out of bounds.");
+        result
+    }
+
+    /// `ptr.wrapping_offset(count)` and `ptr.wrapping_add(count)`: the
+    /// result keeps the provenance of ptr but may be out of bounds, which is
+    /// only checked when it gets dereferenced.
+    fn wrapping_offset_raw<U>(&self, ptr: TaggedPointer, count: isize) -> TaggedPointer {
+        let delta = count.wrapping_mul(std::mem::size_of::<U>() as isize);
+        TaggedPointer { offset: ptr.offset.wrapping_add(delta), ..ptr }
+    }
+
+    // Returns the range of the U pointed to by ptr, which must be in bounds
+    // of the allocation ptr was derived from.
+    fn raw_range<U>(&self, ptr: TaggedPointer) -> (usize, usize, usize) {
+        self.raw_bytes(ptr, std::mem::size_of::<U>())
+    }
+
+    // Returns the range of the size bytes pointed to by ptr.
+    fn raw_bytes(&self, ptr: TaggedPointer, size: usize) -> (usize, usize, usize) {
+        assert!(ptr.object < MAX_NUM_OBJECTS);
+        let alloc_size = self.alloc_sizes[ptr.object];
+        assert!(0 <= ptr.offset && ptr.offset as usize <= alloc_size && size <= alloc_size - ptr.offset as usize,
+                "Dereference out of bounds.");
+        (ptr.object, ptr.offset as usize, size)
+    }
+
+    /// Write through a raw pointer: checked against the stacks of the bytes
+    /// the pointer reaches, not the ones it was derived from.
+    fn use_2_raw<U>(&mut self, ptr: TaggedPointer) {
+        let (obj, offset, size) = self.raw_range::<U>(ptr);
+        self.access_range(obj, offset, size, ptr.kind, ptr.tag, true);
+    }
+
+    /// Read through a raw pointer.
+    fn read_1_raw<U>(&mut self, ptr: TaggedPointer) {
+        let (obj, offset, size) = self.raw_range::<U>(ptr);
+        self.access_range(obj, offset, size, ptr.kind, ptr.tag, false);
+    }
+
+    /// `ptr.expose_provenance()` or `ptr as usize`: the tag of ptr may from
+    /// now on be used by pointers cast back from integers.
+    fn expose(&mut self, ptr: TaggedPointer) {
+        if ptr.kind == KIND_WILDCARD {
+            return;
+        }
+        assert!(self.exposed_count < MAX_EXPOSED_TAGS);
+        self.exposed_objects[self.exposed_count] = ptr.object;
+        self.exposed_kinds[self.exposed_count] = ptr.kind;
+        self.exposed_ids[self.exposed_count] = ptr.tag;
+        self.exposed_count += 1;
+    }
+
+    /// `ptr::with_exposed_provenance(addr)` or `addr as *const T`, where ptr
+    /// is the pointer value obtained from the integer. The result has
+    /// wildcard provenance, unless strict provenance is enforced in which
+    /// case the cast itself is rejected.
+    fn from_exposed<U>(&self, ptr: *const U) -> TaggedPointer {
+        assert!(!self.strict_provenance, "Integer to pointer cast under strict provenance.");
+        TaggedPointer::new(ptr, KIND_WILDCARD, 0)
+    }
+
+    // Returns the range of the count values of type U starting at ptr.
+    fn raw_count_range<U>(&self, ptr: TaggedPointer, count: usize) -> (usize, usize, usize) {
+        let elem_size = std::mem::size_of::<U>();
+        assert!(elem_size == 0 || count <= MAX_OBJECT_SIZE / elem_size);
+        self.raw_bytes(ptr, elem_size * count)
+    }
+
+    // Moves the provenance stored in size bytes from src to dst, as if
+    // through an intermediate buffer so that the ranges may overlap.
+    fn copy_provenance(&mut self, src_obj: usize, src_offset: usize, dst_obj: usize, dst_offset: usize, size: usize) {
+        let mut pointers = [None; MAX_OBJECT_SIZE];
+        let mut i = 0;
+        while i < size {
+            pointers[i] = self.stored_pointers.get_at(src_obj, src_offset + i);
+            i += 1;
+        }
+        let mut i = 0;
+        while i < size {
+            self.stored_pointers.set_at(dst_obj, dst_offset + i, pointers[i]);
+            i += 1;
+        }
+    }
+
+    /// `ptr::copy(src, dst, count)`: a read of the source followed by a
+    /// write of the destination. Pointers stored in the source keep their
+    /// provenance in the destination.
+    fn copy<U>(&mut self, src: TaggedPointer, dst: TaggedPointer, count: usize) {
+        let (src_obj, src_offset, size) = self.raw_count_range::<U>(src, count);
+        let (dst_obj, dst_offset, _) = self.raw_count_range::<U>(dst, count);
+        self.access_range(src_obj, src_offset, size, src.kind, src.tag, false);
+        self.access_range(dst_obj, dst_offset, size, dst.kind, dst.tag, true);
+        self.copy_provenance(src_obj, src_offset, dst_obj, dst_offset, size);
+    }
+
+    /// `ptr::copy_nonoverlapping(src, dst, count)`, also used for `clone`
+    /// of structs holding references.
+    fn copy_nonoverlapping<U>(&mut self, src: TaggedPointer, dst: TaggedPointer, count: usize) {
+        let (src_obj, src_offset, size) = self.raw_count_range::<U>(src, count);
+        let (dst_obj, dst_offset, _) = self.raw_count_range::<U>(dst, count);
+        assert!(src_obj != dst_obj || src_offset + size <= dst_offset || dst_offset + size <= src_offset,
+                "copy_nonoverlapping on overlapping ranges.");
+        self.copy::<U>(src, dst, count);
+    }
+
+    /// `ptr::swap(a, b)` and `mem::swap(a, b)`: both values are read then
+    /// written, their provenance is exchanged.
+    fn swap<U>(&mut self, a: TaggedPointer, b: TaggedPointer) {
+        let (a_obj, a_offset, size) = self.raw_range::<U>(a);
+        let (b_obj, b_offset, _) = self.raw_range::<U>(b);
+        self.access_range(a_obj, a_offset, size, a.kind, a.tag, false);
+        self.access_range(b_obj, b_offset, size, b.kind, b.tag, false);
+        let mut a_pointers = [None; MAX_OBJECT_SIZE];
+        let mut b_pointers = [None; MAX_OBJECT_SIZE];
+        let mut i = 0;
+        while i < size {
+            a_pointers[i] = self.stored_pointers.get_at(a_obj, a_offset + i);
+            b_pointers[i] = self.stored_pointers.get_at(b_obj, b_offset + i);
+            i += 1;
         }
-        assert!(found);
+        self.access_range(a_obj, a_offset, size, a.kind, a.tag, true);
+        self.access_range(b_obj, b_offset, size, b.kind, b.tag, true);
+        let mut i = 0;
+        while i < size {
+            self.stored_pointers.set_at(a_obj, a_offset + i, b_pointers[i]);
+            self.stored_pointers.set_at(b_obj, b_offset + i, a_pointers[i]);
+            i += 1;
+        }
+    }
+
+    /// `mem::replace(dst, value)` where U is a reference or raw pointer type:
+    /// reads the old pointer from dst with its provenance, then stores value.
+    fn replace<U>(&mut self, dst: TaggedPointer, value: TaggedPointer) -> TaggedPointer {
+        let (obj, offset, size) = self.raw_range::<U>(dst);
+        let old = self.load_pointer_range(obj, offset, size, dst.kind, dst.tag);
+        self.store_pointer_range(obj, offset, size, dst.kind, dst.tag, value);
+        old
     }
 
-    fn new_mutable_ref<U>(&mut self, loc: *const U, kind: PointerValueKind, tag: PtrId) -> PtrId {
+    pub fn new_mutable_ref<U>(&mut self, loc: *const U, kind: PointerValueKind, tag: PtrId) -> PtrId {
         self.use_2(loc, kind, tag);
         self.push_unique(loc)
     }
 
-    fn new_mutable_raw<U>(&mut self, loc: *const U, kind: PointerValueKind, tag: PtrId) -> PtrId {
-        self.use_2(loc, kind, tag);
-        self.push_shared(loc);
-        0
-    }
-}
-
-fn example1(x: &mut i32, x__pointer: *const i32, x__pointer_kind: PointerValueKind, x__id: PtrId,
-            y: &mut i32, y__pointer: *const i32, y__pointer_kind: PointerValueKind, y__id: PtrId,
-            sstate: &mut SState) -> i32 {
-    let x_rename = &mut *x;
-    let x_rename__pointer = x__pointer;
-    let x_rename__pointer_kind = KIND_IDENTIFIED;
-    let x_rename__pointer_id = sstate.new_mutable_ref(x__pointer, x__pointer_kind, x__id);
-
-    let y_rename = &mut *y;
-    let y_rename__pointer = y__pointer;
-    let y_rename__pointer_kind = KIND_IDENTIFIED;
-    let y_rename__pointer_id = sstate.new_mutable_ref(y__pointer, y__pointer_kind, y__id);
-    *x_rename = 42;
-    sstate.use_2(x_rename__pointer, x_rename__pointer_kind, x_rename__pointer_id);
-    *y_rename = 13;
-    sstate.use_2(y_rename__pointer, y_rename__pointer_kind, y_rename__pointer_id);
-    *x
+    pub fn new_mutable_raw<U>(&mut self, loc: *const U, kind: PointerValueKind, tag: PtrId) -> PtrId {
+        let (obj, offset, size) = pointer_range(loc);
+        self.reborrow_range(obj, offset, size, kind, tag, KIND_SHARED_RW)
+    }
+
+    pub fn new_shared_ref<U>(&mut self, loc: *const U, kind: PointerValueKind, tag: PtrId) -> PtrId {
+        self.read_1(loc, kind, tag);
+        self.push_shared_ro(loc)
+    }
+
+    // The operations below find the tags of pointers in the tag map from
+    // the address of the variable holding them, like the macros of
+    // stacked_borrows.h.
+
+    /// `let local = ...;`: allocates the local and records its tag.
+    fn new_local<U>(&mut self, local: *const U) {
+        let id = self.new_allocation(local, ALLOC_STACK);
+        self.tags.set(local, KIND_IDENTIFIED, id);
+    }
+
+    /// `let new_ref = &mut local;`
+    fn unique_from_local<P: PointerSlot>(&mut self, new_ref: *const P, local: *const P::Target) {
+        let (kind, tag) = self.tags.get(local);
+        let id = self.new_mutable_ref(local, kind, tag);
+        self.tags.set(new_ref, KIND_IDENTIFIED, id);
+    }
+
+    /// `let new_ref = &local;`
+    fn shared_from_local<P: PointerSlot>(&mut self, new_ref: *const P, local: *const P::Target) {
+        let (kind, tag) = self.tags.get(local);
+        let id = self.new_shared_ref(local, kind, tag);
+        self.tags.set(new_ref, KIND_IDENTIFIED, id);
+    }
+
+    /// `let new_raw = &mut local as *mut _;`, through a temporary reference
+    fn raw_from_local<P: PointerSlot>(&mut self, new_raw: *const P, local: *const P::Target) {
+        let (kind, tag) = self.tags.get(local);
+        let temporary_ref__id = self.new_mutable_ref(local, kind, tag);
+        let id = self.new_mutable_raw(local, KIND_IDENTIFIED, temporary_ref__id);
+        self.tags.set(new_raw, KIND_NONE, id);
+    }
+
+    /// `let new_ref = &mut *old_ref;`
+    fn unique_from_ref<P, Q>(&mut self, new_ref: *const P, old_ref: *const Q)
+    where P: PointerSlot, Q: PointerSlot<Target = P::Target> {
+        let (kind, tag) = self.tags.get(old_ref);
+        let id = self.new_mutable_ref(slot_target(old_ref), kind, tag);
+        self.tags.set(new_ref, KIND_IDENTIFIED, id);
+    }
+
+    /// `let new_ref = &*old_ref;`
+    fn shared_from_ref<P, Q>(&mut self, new_ref: *const P, old_ref: *const Q)
+    where P: PointerSlot, Q: PointerSlot<Target = P::Target> {
+        let (kind, tag) = self.tags.get(old_ref);
+        let id = self.new_shared_ref(slot_target(old_ref), kind, tag);
+        self.tags.set(new_ref, KIND_IDENTIFIED, id);
+    }
+
+    /// `let new_raw = old_ref as *mut _;`
+    fn raw_from_ref<P, Q>(&mut self, new_raw: *const P, old_ref: *const Q)
+    where P: PointerSlot, Q: PointerSlot<Target = P::Target> {
+        let (kind, tag) = self.tags.get(old_ref);
+        let id = self.new_mutable_raw(slot_target(old_ref), kind, tag);
+        self.tags.set(new_raw, KIND_NONE, id);
+    }
+
+    /// `*ptr = ...` through the pointer held by the variable at slot.
+    fn use_2_at<P: PointerSlot>(&mut self, slot: *const P) {
+        let (kind, tag) = self.tags.get(slot);
+        self.use_2(slot_target(slot), kind, tag);
+    }
+
+    /// `... = *ptr` through the pointer held by the variable at slot.
+    fn read_1_at<P: PointerSlot>(&mut self, slot: *const P) {
+        let (kind, tag) = self.tags.get(slot);
+        self.read_1(slot_target(slot), kind, tag);
+    }
+
+    /// `*ptr = value` where value is a pointer: a write through the pointer
+    /// held by the variable at slot that stores the tag of value with it.
+    fn store_pointer_at<P: PointerSlot, V>(&mut self, slot: *const P, value: *const V) {
+        let (kind, tag) = self.tags.get(slot);
+        let (value_kind, value_tag) = self.tags.get(value);
+        // value holds a pointer, read it as such to find what it points to
+        let pointer = unsafe { *(value as *const *const u8) };
+        self.store_pointer(slot_target(slot), kind, tag, TaggedPointer::new(pointer, value_kind, value_tag));
+    }
+
+    /// `let x = *ptr` where x is a pointer: a read through the pointer held
+    /// by the variable at slot, x gets the tag stored with the value.
+    fn load_pointer_at<X, P: PointerSlot>(&mut self, x: *const X, slot: *const P) {
+        let (kind, tag) = self.tags.get(slot);
+        let pointer = self.load_pointer(slot_target(slot), kind, tag);
+        self.tags.set(x, pointer.kind, pointer.tag);
+    }
+
+    /// Protects the reference held by the variable at slot for the duration
+    /// of a call. Returns the number of protectors added, 0 for raw
+    /// pointers which are not protected.
+    fn protect_at<P: PointerSlot>(&mut self, slot: *const P) -> usize {
+        let (kind, tag) = self.tags.get(slot);
+        if kind != KIND_IDENTIFIED {
+            return 0;
+        }
+        let (obj, offset, size) = pointer_range(slot_target(slot));
+        let mut byte = offset;
+        while byte < offset + size {
+            assert!(self.within_summary(obj, byte, false, true),
+                    "{}Protector outside the function summary at offset {}.", self.context, byte);
+            byte += 1;
+        }
+        self.protect(tag);
+        1
+    }
+
+    /// Retag of the parameter held by the variable at slot on function
+    /// entry, `let x = &mut *x;` or `let x = &*x;` as written by fn_entry!.
+    fn fn_entry_at<P: PointerSlot>(&mut self, slot: *const P) {
+        match P::RETAG {
+            Retag::Unique => self.unique_from_ref(slot, slot),
+            Retag::Shared => self.shared_from_ref(slot, slot),
+            Retag::None => {}
+        }
+    }
+
+    /// `local = ...`
+    fn use_2_local<U>(&mut self, local: *const U) {
+        let (kind, tag) = self.tags.get(local);
+        self.use_2(local, kind, tag);
+    }
+
+    /// `... = local`
+    fn read_1_local<U>(&mut self, local: *const U) {
+        let (kind, tag) = self.tags.get(local);
+        self.read_1(local, kind, tag);
+    }
 }
 
-#[kani::proof]
-fn main() {
-    let mut sstate = SState::default();
-    let mut local = 5;
-    let local__pointer = &local as *const i32;
-    let local__pointer_kind = KIND_IDENTIFIED;
-    let local__id = sstate.push_unique(local__pointer);
+// Model state of the instrumented functions. They keep their original
+// signatures, so they cannot be handed the state and look it up here.
+static mut SSTATE: Option<SState> = None;
 
-    let raw_pointer = &mut local as *mut i32;
-    let temporary_ref__pointer = &local as *const i32;
-    let temporary_ref__pointer_kind = KIND_IDENTIFIED;
-    let temporary_ref__id = sstate.new_mutable_ref(local__pointer, local__pointer_kind, local__id);
+// Runs f on the model state. SSTATE is only reached through a raw pointer and
+// every model function borrows it once, for the duration of f, so that no two
+// mutable references to it are ever live at the same time.
+fn with_sstate<R>(f: impl FnOnce(&mut SState) -> R) -> R {
+    let sstate = unsafe { &mut *std::ptr::addr_of_mut!(SSTATE) };
+    f(sstate.get_or_insert_with(SState::default))
+}
 
-    let raw_pointer__pointer = &local as *const i32;
-    let raw_pointer__pointer_kind = KIND_NONE;
-    let raw_pointer__id = sstate.new_mutable_raw(temporary_ref__pointer, temporary_ref__pointer_kind, temporary_ref__id);
+// Same, with the context naming the variable in the messages of the checks
+// failing in f.
+fn with_context<R>(context: &'static str, f: impl FnOnce(&mut SState) -> R) -> R {
+    with_sstate(|sstate| {
+        sstate.context = context;
+        let result = f(sstate);
+        sstate.context = "";
+        result
+    })
+}
 
-    let x__pointer = &local as *const i32;
-    let x__pointer_kind = KIND_IDENTIFIED;
-    let x__id = sstate.new_mutable_ref(raw_pointer__pointer, raw_pointer__pointer_kind, raw_pointer__id);
+// Read-only access for the queries of sb, None before the first operation. As
+// for with_sstate, the borrow of SSTATE does not outlive f.
+fn with_sstate_query<R>(f: impl FnOnce(Option<&SState>) -> R) -> R {
+    f(unsafe { (*std::ptr::addr_of!(SSTATE)).as_ref() })
+}
 
-    let y__pointer = &local as *const i32;
-    let y__pointer_kind = KIND_IDENTIFIED;
-    let y__id = sstate.new_mutable_ref(raw_pointer__pointer, raw_pointer__pointer_kind, raw_pointer__id);
+// Model functions the macros of macros.rs expand to. The context names the
+// variable in the messages of the checks failing during the operation.
 
-    let result = unsafe {
-        example1(&mut *raw_pointer, x__pointer, x__pointer_kind, x__id,
-                 &mut *raw_pointer, y__pointer, y__pointer_kind, y__id,
-                 &mut sstate)
-    };
-    assert_eq!(result, 13);
+fn sb_new_local<U>(local: *const U, context: &'static str) {
+    with_context(context, |sstate| sstate.new_local(local));
+}
+
+fn sb_unique_from_local<P: PointerSlot>(new_ref: *const P, local: *const P::Target, context: &'static str) {
+    with_context(context, |sstate| sstate.unique_from_local(new_ref, local));
+}
+
+fn sb_shared_from_local<P: PointerSlot>(new_ref: *const P, local: *const P::Target, context: &'static str) {
+    with_context(context, |sstate| sstate.shared_from_local(new_ref, local));
+}
+
+fn sb_unique_from_ref<P, Q>(new_ref: *const P, old_ref: *const Q, context: &'static str)
+where P: PointerSlot, Q: PointerSlot<Target = P::Target> {
+    with_context(context, |sstate| sstate.unique_from_ref(new_ref, old_ref));
+}
+
+fn sb_shared_from_ref<P, Q>(new_ref: *const P, old_ref: *const Q, context: &'static str)
+where P: PointerSlot, Q: PointerSlot<Target = P::Target> {
+    with_context(context, |sstate| sstate.shared_from_ref(new_ref, old_ref));
+}
+
+fn sb_raw_from_ref<P, Q>(new_raw: *const P, old_ref: *const Q, context: &'static str)
+where P: PointerSlot, Q: PointerSlot<Target = P::Target> {
+    with_context(context, |sstate| sstate.raw_from_ref(new_raw, old_ref));
+}
+
+fn sb_raw_from_local<P: PointerSlot>(new_raw: *const P, local: *const P::Target, context: &'static str) {
+    with_context(context, |sstate| sstate.raw_from_local(new_raw, local));
+}
+
+fn sb_use2<P: PointerSlot>(used: *const P, context: &'static str) {
+    with_context(context, |sstate| sstate.use_2_at(used));
+}
+
+fn sb_read1<P: PointerSlot>(used: *const P, context: &'static str) {
+    with_context(context, |sstate| sstate.read_1_at(used));
+}
+
+fn sb_use2_local<U>(local: *const U, context: &'static str) {
+    with_context(context, |sstate| sstate.use_2_local(local));
+}
+
+fn sb_read1_local<U>(local: *const U, context: &'static str) {
+    with_context(context, |sstate| sstate.read_1_local(local));
+}
+
+fn sb_copy_pointer<X, P>(x: *const X, source: *const P) {
+    with_sstate(|sstate| {
+        let (kind, tag) = sstate.tags.get(source);
+        sstate.tags.set(x, kind, tag);
+    });
+}
+
+fn sb_store_pointer<P: PointerSlot, V>(used: *const P, value: *const V, context: &'static str) {
+    with_context(context, |sstate| sstate.store_pointer_at(used, value));
+}
 
+fn sb_load_pointer<X, P: PointerSlot>(x: *const X, used: *const P, context: &'static str) {
+    with_context(context, |sstate| sstate.load_pointer_at(x, used));
+}
+
+fn sb_pass_arg<P>(index: usize, arg: *const P) {
+    with_sstate(|sstate| sstate.tags.pass_arg(index, arg));
+}
+
+fn sb_bind_arg<P>(index: usize, param: *const P) {
+    with_sstate(|sstate| sstate.tags.bind_arg(index, param));
+}
+
+fn sb_pass_return<P>(result: *const P) {
+    with_sstate(|sstate| sstate.tags.pass_return(result));
+}
+
+fn sb_bind_return<P>(result: *const P) {
+    with_sstate(|sstate| sstate.tags.bind_return(result));
+}
+
+fn sb_fn_entry<P: PointerSlot>(param: *const P, context: &'static str) {
+    with_context(context, |sstate| sstate.fn_entry_at(param));
+}
+
+fn sb_protect<P: PointerSlot>(arg: *const P) -> usize {
+    with_sstate(|sstate| sstate.protect_at(arg))
+}
+
+fn sb_end_protect(count: usize) {
+    with_sstate(|sstate| sstate.end_protect(count));
+}
+
+fn sb_begin_summary_check() {
+    with_sstate(|sstate| sstate.begin_summary_check());
+}
+
+fn sb_summary_arg<P: PointerSlot>(arg: *const P, write: bool) {
+    with_sstate(|sstate| sstate.summary_arg(slot_target(arg), write));
+}
+
+fn sb_summary_protect<P: PointerSlot>(arg: *const P) {
+    with_sstate(|sstate| sstate.summary_protect(slot_target(arg)));
+}
+
+fn sb_check_summary_return<P: PointerSlot, Q: PointerSlot>(result: *const P, arg: *const Q, write: bool,
+                                                            context: &'static str) {
+    with_context(context, |sstate| sstate.check_summary_return(result, arg, write));
+}
+
+fn sb_end_summary_check() {
+    with_sstate(|sstate| sstate.end_summary_check());
+}
+
+/// Queries of the model without side effects, usable in Kani function
+/// contracts:
+///
+///     #[kani::requires(sb::can_write(&x, sb::arg_provenance(0)))]
+///     #[kani::ensures(|_| sb::tag_live(&x, sb::arg_provenance(0)))]
+///
+/// A pointer is given as a reference to the variable holding it, as for the
+/// macros of macros.rs, and its provenance as a (kind, tag) pair.
+mod sb {
+    use super::{with_sstate_query, PointerValueKind, PointerSlot, PtrId, StackItemKind};
+
+    pub type Provenance = (PointerValueKind, PtrId);
+
+    /// Provenance of the pointer held by the variable.
+    pub fn provenance<P>(slot: &P) -> Provenance {
+        with_sstate_query(|s| s.map_or((super::KIND_NONE, 0), |s| s.tags.get(slot as *const P)))
+    }
+
+    /// Provenance passed by the caller as argument number index, i.e. the
+    /// provenance of a parameter in a precondition, before the body binds
+    /// it.
+    pub fn arg_provenance(index: usize) -> Provenance {
+        with_sstate_query(|s| s.map_or((super::KIND_NONE, 0), |s| s.tags.arg(index)))
+    }
+
+    pub fn can_read<P: PointerSlot>(slot: &P, (kind, tag): Provenance) -> bool {
+        with_sstate_query(|s| s.is_some_and(|s| s.can_read(slot.address(), kind, tag)))
+    }
+
+    pub fn can_write<P: PointerSlot>(slot: &P, (kind, tag): Provenance) -> bool {
+        with_sstate_query(|s| s.is_some_and(|s| s.can_write(slot.address(), kind, tag)))
+    }
+
+    pub fn tag_live<P: PointerSlot>(slot: &P, (kind, tag): Provenance) -> bool {
+        with_sstate_query(|s| s.is_some_and(|s| s.tag_live(slot.address(), kind, tag)))
+    }
+
+    /// Runs f on the borrow stack of the byte the pointer points to, see
+    /// SState::stack_at, e.g. `sb::stack(&x, |kinds, _| kinds.len() == 1)`.
+    pub fn stack<P: PointerSlot, R>(slot: &P, f: impl FnOnce(&[StackItemKind], &[PtrId]) -> R) -> R {
+        with_sstate_query(|s| match s {
+            Some(s) => {
+                let (kinds, ids) = s.stack_at(slot.address());
+                f(kinds, ids)
+            }
+            None => f(&[], &[]),
+        })
+    }
+}
+
+////// end of model //////
+// instrumenter.rs copies everything above into the harnesses it generates.
+
+// Both arguments must be writable when example1 is called, and are still
+// valid when it returns, so they cannot alias.
+#[stacked_borrows::instrument]
+#[kani::requires(sb::can_write(&x, sb::arg_provenance(0)) && sb::can_write(&y, sb::arg_provenance(1)))]
+#[kani::ensures(|_| sb::tag_live(&x, sb::arg_provenance(0)) && sb::tag_live(&y, sb::arg_provenance(1)))]
+#[kani::modifies(x, y)]
+fn example1(x: &mut i32, y: &mut i32) -> i32 {
+    *x = 42;
+    *y = 13;
+    *x
+}
+
+#[kani::proof]
+#[stacked_borrows::instrument]
+fn main() {
+    let mut local = 5;
+    let raw_pointer = &mut local as *mut i32;
+
+    let result = unsafe {
+        example1(&mut *raw_pointer, &mut *raw_pointer)
+    };
+    assert_eq!(result, 13);
+}
+
+summary! {
+    fn example1(x: &mut i32, y: &mut i32) -> i32;
+    stub example1__summary;
+    check example1__check;
+    reads(); writes(x, y); protects(x, y); returns();
+}
+
+// wrong, example1 also writes through y
+summary! {
+    fn example1(x: &mut i32, y: &mut i32) -> i32;
+    stub example1__summary_x;
+    check example1__check_x;
+    reads(); writes(x); protects(x, y); returns();
+}
+
+#[kani::proof]
+#[stacked_borrows::instrument]
+fn example1_within_summary() {
+    let mut a = 1;
+    let mut b = 2;
+    let result = example1__check(&mut a, &mut b);
+    assert_eq!(result, 42);
+}
+
+#[kani::proof]
+#[kani::should_panic]
+#[stacked_borrows::instrument]
+fn example1_beyond_summary() {
+    let mut a = 1;
+    let mut b = 2;
+    example1__check_x(&mut a, &mut b);
+}
+
+// main with example1 replaced by its summary, which is enough to reject it
+#[kani::proof]
+#[kani::should_panic]
+#[kani::stub(example1, example1__summary)]
+#[stacked_borrows::instrument]
+fn main_with_summary() {
+    let mut local = 5;
+    let raw_pointer = &mut local as *mut i32;
+
+    let result = unsafe {
+        example1(&mut *raw_pointer, &mut *raw_pointer)
+    };
+    assert_eq!(result, 13);
+}
+
+#[kani::proof]
+#[kani::stub(example1, example1__summary)]
+#[stacked_borrows::instrument]
+fn distinct_arguments_with_summary() {
+    let mut a = 1;
+    let mut b = 2;
+    example1(&mut a, &mut b);
+}
+
+// wrong, example1 also protects y
+summary! {
+    fn example1(x: &mut i32, y: &mut i32) -> i32;
+    stub example1__summary_protects_x;
+    check example1__check_protects_x;
+    reads(); writes(x, y); protects(x); returns();
+}
+
+#[kani::proof]
+#[kani::should_panic]
+#[stacked_borrows::instrument]
+fn example1_protects_beyond_summary() {
+    let mut a = 1;
+    let mut b = 2;
+    example1__check_protects_x(&mut a, &mut b);
+}
+
+#[stacked_borrows::instrument]
+fn reborrow<'a>(x: &'a mut i32) -> &'a mut i32 {
+    &mut *x
+}
+
+// The reference returned by an instrumented function carries the tag the
+// function returned it with.
+#[kani::proof]
+#[stacked_borrows::instrument]
+fn returned_reference_keeps_tag() {
+    let mut a = 1;
+    let q = reborrow(&mut a);
+    *q = 2;
+    assert_eq!(a, 2);
+}
+
+summary! {
+    fn reborrow<'a>(x: &'a mut i32) -> &'a mut i32;
+    stub reborrow__summary;
+    check reborrow__check;
+    reads(); writes(x); protects(x); returns(&mut *x);
+}
+
+#[kani::proof]
+#[stacked_borrows::instrument]
+fn reborrow_within_summary() {
+    let mut a = 1;
+    let q = reborrow__check(&mut a);
+    *q = 2;
+    assert_eq!(a, 2);
+}
+
+#[stacked_borrows::instrument]
+fn second<'a>(x: &'a mut i32, y: &'a mut i32) -> &'a mut i32 {
+    *x = 1;
+    &mut *y
+}
+
+// wrong, second returns a reborrow of y
+summary! {
+    fn second<'a>(x: &'a mut i32, y: &'a mut i32) -> &'a mut i32;
+    stub second__summary;
+    check second__check;
+    reads(); writes(x, y); protects(x, y); returns(&mut *x);
+}
+
+#[kani::proof]
+#[kani::should_panic]
+#[stacked_borrows::instrument]
+fn second_returns_beyond_summary() {
+    let mut a = 1;
+    let mut b = 2;
+    second__check(&mut a, &mut b);
+}
+
+#[stacked_borrows::instrument]
+fn get(x: &mut i32) -> i32 {
+    *x
+}
+
+// Each call of get gets a reference of its own.
+#[kani::proof]
+#[stacked_borrows::instrument]
+fn repeated_callee() {
+    let mut a = 1;
+    let mut b = 2;
+    let sum = get(&mut a) + get(&mut b);
+    assert_eq!(sum, 3);
+}
+
+// The reference reborrow returns is passed to example1 after reborrow's own
+// argument.
+#[kani::proof]
+#[stacked_borrows::instrument]
+fn nested_calls() {
+    let mut a = 1;
+    let mut b = 2;
+    let result = example1(&mut a, reborrow(&mut b));
+    assert_eq!(result, 42);
+    assert_eq!(b, 13);
+}
+
+// A copy of a pointer writes with the tag of the pointer it copies.
+#[kani::proof]
+#[stacked_borrows::instrument]
+fn copied_pointer_keeps_tag() {
+    let mut a = 1;
+    let p = &mut a as *mut i32;
+    let x = p;
+    unsafe { *x = 5; }
+    let v = a;
+    assert_eq!(v, 5);
+}
+
+// Writing the local pops the raw pointer derived from it.
+#[kani::proof]
+#[kani::should_panic]
+#[stacked_borrows::instrument]
+fn local_write_pops_raw_pointer() {
+    let mut a = 1;
+    let p = &mut a as *mut i32;
+    a = 3;
+    unsafe { *p = a; }
+}
+
+#[stacked_borrows::instrument]
+fn write_through_raw(x: &mut i32, p: *mut i32) {
+    unsafe { *p = 2; }
+    *x = 3;
+}
+
+// x is retagged and protected on entry, the write through p, from which x
+// was derived, pops a protected item.
+#[kani::proof]
+#[kani::should_panic]
+#[stacked_borrows::instrument]
+fn protected_argument_popped() {
+    let mut a = 1;
+    let p = &mut a as *mut i32;
+    unsafe { write_through_raw(&mut *p, p); }
+}
+
+// Checks example1 against its contract once, for distinct arguments.
+#[kani::proof_for_contract(example1)]
+#[stacked_borrows::instrument]
+fn example1_contract() {
+    let mut a = 1;
+    let mut b = 2;
+    example1(&mut a, &mut b);
+}
+
+// Callers then use the contract instead of the body of example1.
+#[kani::proof]
+#[kani::stub_verified(example1)]
+#[stacked_borrows::instrument]
+fn distinct_arguments_with_contract() {
+    let mut a = 1;
+    let mut b = 2;
+    let x = &mut a;
+    let y = &mut b;
+    example1(x, y);
+    *x = 3;
+    *y = 4;
+}
+
+static ANSWER: i32 = 42;
+static mut COUNTER: i32 = 0;
+
+/// A pointer of the harnesses below with its provenance, in place of the
+/// x__pointer, x__pointer_kind and x__id variables of the instrumented code.
+/// Reborrows take the provenance of the fixture they are derived from.
+struct Fixture<U> {
+    pointer: *const U,
+    kind: PointerValueKind,
+    id: PtrId,
+}
+
+impl<U> Clone for Fixture<U> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<U> Copy for Fixture<U> {}
+
+impl<U> Fixture<U> {
+    fn new(pointer: *const U, kind: PointerValueKind, id: PtrId) -> Fixture<U> {
+        Fixture { pointer, kind, id }
+    }
+
+    /// A new allocation for the value pointer points to.
+    fn allocation(sstate: &mut SState, pointer: *const U, alloc_kind: AllocationKind) -> Fixture<U> {
+        let id = sstate.new_allocation(pointer, alloc_kind);
+        Fixture::new(pointer, KIND_IDENTIFIED, id)
+    }
+
+    /// The same provenance for another pointer, e.g. a cast, a transmute or
+    /// a field.
+    fn at<V>(self, pointer: *const V) -> Fixture<V> {
+        Fixture::new(pointer, self.kind, self.id)
+    }
+
+    /// `&mut *self`
+    fn mutable_ref(self, sstate: &mut SState) -> Fixture<U> {
+        let id = sstate.new_mutable_ref(self.pointer, self.kind, self.id);
+        Fixture::new(self.pointer, KIND_IDENTIFIED, id)
+    }
+
+    /// `&*self`
+    fn shared_ref(self, sstate: &mut SState) -> Fixture<U> {
+        let id = sstate.new_shared_ref(self.pointer, self.kind, self.id);
+        Fixture::new(self.pointer, KIND_IDENTIFIED, id)
+    }
+
+    /// `&mut *self as *mut U`
+    fn mutable_raw(self, sstate: &mut SState) -> Fixture<U> {
+        let id = sstate.new_mutable_raw(self.pointer, self.kind, self.id);
+        Fixture::new(self.pointer, KIND_NONE, id)
+    }
+
+    /// `&mut (*self).field` for the field at offset field_offset.
+    fn mutable_field_ref<V>(self, sstate: &mut SState, field: *const V, field_offset: usize) -> Fixture<V> {
+        let id = sstate.new_mutable_field_ref(self.pointer, self.kind, self.id,
+                                              field_offset, std::mem::size_of::<V>());
+        Fixture::new(field, KIND_IDENTIFIED, id)
+    }
+
+    /// `&mut *slice_from_raw_parts_mut(base, count)` within `*self`.
+    fn mutable_slice_ref<V>(self, sstate: &mut SState, base: *const V, count: usize) -> Fixture<V> {
+        let id = sstate.new_mutable_slice_ref(base, std::mem::size_of::<V>(), count, self.kind, self.id);
+        Fixture::new(base, KIND_IDENTIFIED, id)
+    }
+
+    /// `*self = ...`
+    fn use_2(self, sstate: &mut SState) {
+        sstate.use_2(self.pointer, self.kind, self.id);
+    }
+
+    /// `... = *self`
+    fn read_1(self, sstate: &mut SState) {
+        sstate.read_1(self.pointer, self.kind, self.id);
+    }
+
+    /// Write to the count elements starting at self.
+    fn use_2_slice(self, sstate: &mut SState, count: usize) {
+        sstate.use_2_slice(self.pointer, std::mem::size_of::<U>(), count, self.kind, self.id);
+    }
+
+    /// Read of the count elements starting at self.
+    fn read_1_slice(self, sstate: &mut SState, count: usize) {
+        sstate.read_1_slice(self.pointer, std::mem::size_of::<U>(), count, self.kind, self.id);
+    }
+
+    /// `*self = value` where self points to a reference or a raw pointer.
+    fn store_pointer(self, sstate: &mut SState, value: TaggedPointer) {
+        sstate.store_pointer(self.pointer, self.kind, self.id, value);
+    }
+
+    /// `... = *self` where self points to a reference or a raw pointer.
+    fn load_pointer(self, sstate: &mut SState) -> TaggedPointer {
+        sstate.load_pointer(self.pointer, self.kind, self.id)
+    }
+
+    fn tagged(self) -> TaggedPointer {
+        TaggedPointer::new(self.pointer, self.kind, self.id)
+    }
+}
+
+#[kani::proof]
+fn static_read() {
+    let mut sstate = SState::default();
+    let answer__fixture = Fixture::allocation(&mut sstate, &ANSWER as *const i32, ALLOC_STATIC);
+
+    // let shared = &ANSWER;
+    let shared__fixture = answer__fixture.shared_ref(&mut sstate);
+    shared__fixture.read_1(&mut sstate);
+    assert_eq!(ANSWER, 42);
+}
+
+#[kani::proof]
+#[kani::should_panic]
+fn static_write() {
+    let mut sstate = SState::default();
+    let answer__fixture = Fixture::allocation(&mut sstate, &ANSWER as *const i32, ALLOC_STATIC);
+
+    // unsafe { *(&ANSWER as *const i32 as *mut i32) = 13 };
+    // the write itself is not performed, only checked against the model
+    answer__fixture.use_2(&mut sstate);
+}
+
+#[kani::proof]
+fn static_mut_write() {
+    let mut sstate = SState::default();
+    let counter__pointer = std::ptr::addr_of!(COUNTER);
+    let counter__id = sstate.new_allocation(counter__pointer, ALLOC_STATIC_MUT);
+    let counter__fixture = Fixture::new(counter__pointer, KIND_NONE, counter__id);
+
+    unsafe { *std::ptr::addr_of_mut!(COUNTER) += 1 };
+    counter__fixture.use_2(&mut sstate);
+}
+
+#[kani::proof]
+#[kani::should_panic]
+fn partial_reborrow_write() {
+    let mut sstate = SState::default();
+    let mut local: i32 = 5;
+    let local__fixture = Fixture::allocation(&mut sstate, &local as *const i32, ALLOC_STACK);
+
+    // let first = unsafe { &mut *(&mut local as *mut i32 as *mut u8) };
+    let first = unsafe { &mut *(&mut local as *mut i32 as *mut u8) };
+    let first__fixture = local__fixture.at(first as *const u8).mutable_ref(&mut sstate);
+
+    // writing an i32 with the tag of `first`: the reborrow only covers
+    // byte 0, the write fails on byte 1
+    first__fixture.at(first__fixture.pointer as *const i32).use_2(&mut sstate);
+    *first = 42;
+}
+
+struct Pair {
+    a: i32,
+    b: i32,
+}
+
+#[kani::proof]
+fn disjoint_field_refs() {
+    let mut sstate = SState::default();
+    let mut pair = Pair { a: 1, b: 2 };
+    let pair__fixture = Fixture::allocation(&mut sstate, &pair as *const Pair, ALLOC_STACK);
+
+    // let a = &mut pair.a;
+    let a = &mut pair.a;
+    let a__fixture = pair__fixture.mutable_field_ref(&mut sstate, a as *const i32, std::mem::offset_of!(Pair, a));
+
+    // let b = &mut pair.b;
+    let b = &mut pair.b;
+    let b__fixture = pair__fixture.mutable_field_ref(&mut sstate, b as *const i32, std::mem::offset_of!(Pair, b));
+
+    *a = 42;
+    a__fixture.use_2(&mut sstate);
+    *b = 13;
+    b__fixture.use_2(&mut sstate);
+}
+
+#[kani::proof]
+#[kani::should_panic]
+fn overlapping_field_refs() {
+    let mut sstate = SState::default();
+    let pair = Pair { a: 1, b: 2 };
+    let pair__fixture = Fixture::allocation(&mut sstate, &pair as *const Pair, ALLOC_STACK);
+
+    // let a1 = &mut pair.a;
+    let a1__fixture = pair__fixture.mutable_field_ref(&mut sstate, &pair.a as *const i32, std::mem::offset_of!(Pair, a));
+
+    // let a2 = &mut pair.a;
+    let a2__fixture = pair__fixture.mutable_field_ref(&mut sstate, &pair.a as *const i32, std::mem::offset_of!(Pair, a));
+
+    // *a2 = 42; *a1 = 13;
+    a2__fixture.use_2(&mut sstate);
+    // a1 was popped by the reborrow of a2
+    a1__fixture.use_2(&mut sstate);
+}
+
+#[kani::proof]
+fn split_at_mut_halves() {
+    let mut sstate = SState::default();
+    let mut array = [0u32; 4];
+    let array__fixture = Fixture::allocation(&mut sstate, &array as *const [u32; 4], ALLOC_STACK);
+
+    // let (left, right) = array.split_at_mut(2);
+    let (left, right) = array.split_at_mut(2);
+    let left__fixture = array__fixture.mutable_slice_ref(&mut sstate, left.as_ptr(), left.len());
+    let right__fixture = array__fixture.mutable_slice_ref(&mut sstate, right.as_ptr(), right.len());
+
+    // both halves stay usable in any order
+    right[1] = 13;
+    right__fixture.at(&right[1] as *const u32).use_2(&mut sstate);
+    left[0] = 42;
+    left__fixture.at(&left[0] as *const u32).use_2(&mut sstate);
+    right.fill(7);
+    right__fixture.use_2_slice(&mut sstate, right.len());
+    left__fixture.read_1_slice(&mut sstate, left.len());
+}
+
+#[kani::proof]
+#[kani::should_panic]
+fn stale_sub_slice() {
+    let mut sstate = SState::default();
+    let array = [0u32; 4];
+    let array__fixture = Fixture::allocation(&mut sstate, &array as *const [u32; 4], ALLOC_STACK);
+
+    // let chunk = &mut array[1..3];
+    let chunk__fixture = array__fixture.mutable_slice_ref(&mut sstate, &array[1] as *const u32, 2);
+
+    // let tail = &mut array[2..];
+    let tail__fixture = array__fixture.mutable_slice_ref(&mut sstate, &array[2] as *const u32, 2);
+    tail__fixture.use_2_slice(&mut sstate, 2);
+
+    // chunk[0] is disjoint from tail, chunk[1] was invalidated by it
+    chunk__fixture.use_2_slice(&mut sstate, 1);
+    chunk__fixture.use_2_slice(&mut sstate, 2);
+}
+
+#[kani::proof]
+fn raw_pointer_walk() {
+    let mut sstate = SState::default();
+    let mut array = [0u32; 4];
+    let array__fixture = Fixture::allocation(&mut sstate, &array as *const [u32; 4], ALLOC_STACK);
+
+    // let raw_pointer = &mut array as *mut [u32; 4] as *mut u32;
+    let raw_pointer = &mut array as *mut [u32; 4] as *mut u32;
+    let raw_pointer__tagged = array__fixture.mutable_raw(&mut sstate).at(raw_pointer as *const u32).tagged();
+
+    // for i in 0..4 { unsafe { *raw_pointer.add(i) = i as u32 } }
+    let mut i = 0;
+    while i < 4 {
+        let element__tagged = sstate.offset_raw::<u32>(raw_pointer__tagged, i as isize);
+        sstate.use_2_raw::<u32>(element__tagged);
+        unsafe { *raw_pointer.add(i) = i as u32 };
+        i += 1;
+    }
+
+    // let end = raw_pointer.add(4); is fine as long as it is not dereferenced
+    let _end__tagged = sstate.offset_raw::<u32>(raw_pointer__tagged, 4);
+}
+
+#[kani::proof]
+#[kani::should_panic]
+fn raw_pointer_past_the_end() {
+    let mut sstate = SState::default();
+    let mut array = [0u32; 4];
+    let array__fixture = Fixture::allocation(&mut sstate, &array as *const [u32; 4], ALLOC_STACK);
+
+    // let raw_pointer = &mut array as *mut [u32; 4] as *mut u32;
+    let raw_pointer__fixture = array__fixture.mutable_raw(&mut sstate);
+    let raw_pointer__tagged = raw_pointer__fixture.at(&mut array as *mut [u32; 4] as *const u32).tagged();
+
+    // let end = raw_pointer.wrapping_add(4); unsafe { *end = 1 };
+    let end__tagged = sstate.wrapping_offset_raw::<u32>(raw_pointer__tagged, 4);
+    sstate.use_2_raw::<u32>(end__tagged);
+}
+
+#[kani::proof]
+#[kani::should_panic]
+fn raw_pointer_outside_parent() {
+    let mut sstate = SState::default();
+    let array = [0u32; 4];
+    let array__fixture = Fixture::allocation(&mut sstate, &array as *const [u32; 4], ALLOC_STACK);
+
+    // let first = &mut array[0] as *mut u32;
+    // the raw pointer is derived from a reference to the first element only
+    let first__tagged = array__fixture.at(&array[0] as *const u32).mutable_raw(&mut sstate).tagged();
+
+    // unsafe { *first.add(1) = 1 };
+    let second__tagged = sstate.offset_raw::<u32>(first__tagged, 1);
+    sstate.use_2_raw::<u32>(second__tagged);
+}
+
+// fn example2(x: &i32, f: impl FnOnce(&i32)) -> i32 {
+//     let val = *x / 3;
+//     f(x);
+//     return *x / 3;
+// }
+//
+// let result = example2(&local, |inner_x| {
+//     let raw_pointer: *mut i32 = unsafe { mem::transmute(inner_x) };
+//     unsafe { *raw_pointer = 15 };
+// });
+#[kani::proof]
+#[kani::should_panic]
+fn transmute_shared_to_raw() {
+    let mut sstate = SState::default();
+    let local = 6;
+    let local__fixture = Fixture::allocation(&mut sstate, &local as *const i32, ALLOC_STACK);
+
+    // let x = &local;
+    let x__fixture = local__fixture.shared_ref(&mut sstate);
+
+    // retag of inner_x on closure entry
+    let inner_x__fixture = x__fixture.shared_ref(&mut sstate);
+
+    // let raw_pointer: *mut i32 = unsafe { mem::transmute(inner_x) };
+    let raw_pointer__tagged = transmute_ref_to_raw(inner_x__fixture.pointer, inner_x__fixture.kind, inner_x__fixture.id);
+
+    // unsafe { *raw_pointer = 15 };
+    sstate.use_2_raw::<i32>(raw_pointer__tagged);
+}
+
+#[kani::proof]
+fn transmute_raw_to_mut() {
+    let mut sstate = SState::default();
+    let mut local = 6;
+    let local__fixture = Fixture::allocation(&mut sstate, &local as *const i32, ALLOC_STACK);
+
+    // let raw_pointer = &mut local as *mut i32;
+    let raw_pointer = &mut local as *mut i32;
+    let raw_pointer__tagged = local__fixture.mutable_raw(&mut sstate).at(raw_pointer as *const i32).tagged();
+
+    // let x: &mut i32 = unsafe { mem::transmute(raw_pointer) };
+    let x: &mut i32 = unsafe { &mut *raw_pointer };
+    let (x__kind, x__id) = transmute_raw_to_ref(raw_pointer__tagged);
+    let x__fixture = Fixture::new(raw_pointer as *const i32, x__kind, x__id);
+
+    // let y: &mut u32 = unsafe { mem::transmute(x) }; keeps the tag of x
+    let y = unsafe { &mut *(x as *mut i32 as *mut u32) };
+    let y__fixture = x__fixture.at(x__fixture.pointer as *const u32);
+
+    // *y = 15;
+    *y = 15;
+    y__fixture.use_2(&mut sstate);
+}
+
+#[kani::proof]
+fn exposed_round_trip() {
+    let mut sstate = SState::default();
+    let mut local = 5;
+    let local__fixture = Fixture::allocation(&mut sstate, &local as *const i32, ALLOC_STACK);
+
+    // let addr = &mut local as *mut i32 as usize;
+    let raw_pointer = &mut local as *mut i32;
+    let raw_pointer__tagged = local__fixture.mutable_raw(&mut sstate).at(raw_pointer as *const i32).tagged();
+    let addr = raw_pointer as usize;
+    sstate.expose(raw_pointer__tagged);
+
+    // let ptr = addr as *mut i32; unsafe { *ptr = 42 };
+    let ptr = addr as *mut i32;
+    let ptr__tagged = sstate.from_exposed(ptr);
+    sstate.use_2_raw::<i32>(ptr__tagged);
+    unsafe { *ptr = 42 };
+}
+
+#[kani::proof]
+#[kani::should_panic]
+fn invalidated_exposed_tag() {
+    let mut sstate = SState::default();
+    let local = 5;
+    let local__fixture = Fixture::allocation(&mut sstate, &local as *const i32, ALLOC_STACK);
+
+    // let x = &mut local;
+    let x__fixture = local__fixture.mutable_ref(&mut sstate);
+
+    // let addr = x as *const i32 as usize;
+    let addr = x__fixture.pointer as usize;
+    sstate.expose(transmute_ref_to_raw(x__fixture.pointer, x__fixture.kind, x__fixture.id));
+
+    // let y = &mut local; invalidates x and with it the exposed tag
+    let y__fixture = local__fixture.mutable_ref(&mut sstate);
+    y__fixture.use_2(&mut sstate);
+
+    // unsafe { *(addr as *mut i32) = 42 };
+    let ptr__tagged = sstate.from_exposed(addr as *const i32);
+    sstate.use_2_raw::<i32>(ptr__tagged);
+}
+
+#[kani::proof]
+#[kani::should_panic]
+fn strict_int_cast() {
+    let mut sstate = SState::default();
+    sstate.strict_provenance = true;
+    let mut local = 5;
+    let local__fixture = Fixture::allocation(&mut sstate, &local as *const i32, ALLOC_STACK);
+
+    // let addr = &mut local as *mut i32 as usize;
+    let raw_pointer = &mut local as *mut i32;
+    let raw_pointer__tagged = local__fixture.mutable_raw(&mut sstate).at(raw_pointer as *const i32).tagged();
+    sstate.expose(raw_pointer__tagged);
+
+    // let ptr = addr as *mut i32;
+    let _ptr__tagged = sstate.from_exposed(raw_pointer as usize as *const i32);
+}
+
+// let x = &mut local;
+// let raw_pointer1 = x as *mut i32;
+// let raw_pointer2 = x as *mut i32;
+// unsafe { *raw_pointer1 = 42 };
+// Untagged, creating raw_pointer2 pops the SharedRW of raw_pointer1 and
+// pushes an identical one. Tagged, the SharedRW of raw_pointer2 is inserted
+// next to that of raw_pointer1, which stays usable.
+fn interleaved_raw_pointers(tagged_raw_pointers: bool) {
+    let mut sstate = SState::default();
+    sstate.tagged_raw_pointers = tagged_raw_pointers;
+    let mut local = 5;
+    let local__fixture = Fixture::allocation(&mut sstate, &local as *const i32, ALLOC_STACK);
+
+    let x = &mut local;
+    let x__fixture = local__fixture.mutable_ref(&mut sstate);
+
+    let raw_pointer1 = x as *mut i32;
+    let raw_pointer1__fixture = x__fixture.mutable_raw(&mut sstate);
+
+    let _raw_pointer2 = x as *mut i32;
+    let _raw_pointer2__fixture = x__fixture.mutable_raw(&mut sstate);
+
+    unsafe { *raw_pointer1 = 42 };
+    raw_pointer1__fixture.use_2(&mut sstate);
+}
+
+#[kani::proof]
+fn interleaved_raw_pointers_untagged() {
+    interleaved_raw_pointers(false);
+}
+
+#[kani::proof]
+fn interleaved_raw_pointers_tagged() {
+    interleaved_raw_pointers(true);
+}
+
+// let raw_pointer1 = &mut local as *mut i32;
+// let raw_pointer2 = &mut local as *mut i32;
+// unsafe { *raw_pointer1 = 42 };
+// The write through the temporary reference to local creating raw_pointer2
+// pops the SharedRW of raw_pointer1, which is only noticed when raw pointers
+// are tagged.
+fn raw_pointers_from_local(tagged_raw_pointers: bool) {
+    let mut sstate = SState::default();
+    sstate.tagged_raw_pointers = tagged_raw_pointers;
+    let mut local = 5;
+    let local__fixture = Fixture::allocation(&mut sstate, &local as *const i32, ALLOC_STACK);
+
+    let raw_pointer1 = &mut local as *mut i32;
+    let temporary_ref1__fixture = local__fixture.mutable_ref(&mut sstate);
+    let raw_pointer1__fixture = temporary_ref1__fixture.mutable_raw(&mut sstate);
+
+    let _raw_pointer2 = &mut local as *mut i32;
+    let temporary_ref2__fixture = local__fixture.mutable_ref(&mut sstate);
+    let _raw_pointer2__fixture = temporary_ref2__fixture.mutable_raw(&mut sstate);
+
+    unsafe { *raw_pointer1 = 42 };
+    raw_pointer1__fixture.use_2(&mut sstate);
+}
+
+#[kani::proof]
+fn raw_pointers_from_local_untagged() {
+    raw_pointers_from_local(false);
+}
+
+#[kani::proof]
+#[kani::should_panic]
+fn raw_pointers_from_local_tagged() {
+    raw_pointers_from_local(true);
+}
+
+#[kani::proof]
+fn reference_through_box() {
+    let mut sstate = SState::default();
+    let mut local = 5;
+    let local__fixture = Fixture::allocation(&mut sstate, &local as *const i32, ALLOC_STACK);
+
+    // let x = &mut local;
+    let x = &mut local;
+    let x__fixture = local__fixture.mutable_ref(&mut sstate);
+
+    // let boxed = Box::new(x);
+    let boxed = Box::new(x);
+    let boxed__fixture = Fixture::allocation(&mut sstate, &*boxed as *const &mut i32, ALLOC_HEAP);
+    boxed__fixture.store_pointer(&mut sstate, x__fixture.tagged());
+
+    // let y = *boxed; *y = 42;
+    let y = *boxed;
+    let y__tagged = boxed__fixture.load_pointer(&mut sstate);
+    *y = 42;
+    sstate.use_2_raw::<i32>(y__tagged);
+}
+
+// A raw pointer stored in a local and loaded back, instrumented by the
+// attribute: `*p = q;` stores the tag of q with it, `let x = *p;` loads it.
+#[kani::proof]
+#[stacked_borrows::instrument]
+fn stored_pointer_keeps_tag() {
+    let mut a = 1;
+    let mut cell: *mut i32 = std::ptr::null_mut();
+    let p = &mut cell;
+    let q = &mut a as *mut i32;
+    *p = q;
+    let x = *p;
+    unsafe { *x = 5; }
+    assert_eq!(a, 5);
+}
+
+#[kani::proof]
+#[kani::should_panic]
+#[stacked_borrows::instrument]
+fn stored_pointer_invalidated() {
+    let mut a = 1;
+    let mut cell: *mut i32 = std::ptr::null_mut();
+    let p = &mut cell;
+    let q = &mut a as *mut i32;
+    *p = q;
+    let y = &mut a;
+    *y = 3;
+    let x = *p;
+    unsafe { *x = 5; }
+}
+
+struct Holder<'a> {
+    r: &'a mut i32,
+}
+
+#[kani::proof]
+#[kani::should_panic]
+fn stale_reference_in_struct() {
+    let mut sstate = SState::default();
+    let mut local = 5;
+    let local__fixture = Fixture::allocation(&mut sstate, &local as *const i32, ALLOC_STACK);
+
+    // let raw_pointer = &mut local as *mut i32;
+    let raw_pointer = &mut local as *mut i32;
+    let raw_pointer__fixture = local__fixture.mutable_raw(&mut sstate);
+
+    // let holder = Holder { r: unsafe { &mut *raw_pointer } };
+    let r__fixture = raw_pointer__fixture.mutable_ref(&mut sstate);
+    let holder = Holder { r: unsafe { &mut *raw_pointer } };
+    let holder__fixture = Fixture::allocation(&mut sstate, &holder as *const Holder, ALLOC_STACK);
+    let r__slot = holder__fixture.at(&holder.r as *const &mut i32);
+    r__slot.store_pointer(&mut sstate, r__fixture.tagged());
+
+    // let y = unsafe { &mut *raw_pointer }; *y = 13;
+    let y__fixture = raw_pointer__fixture.mutable_ref(&mut sstate);
+    y__fixture.use_2(&mut sstate);
+
+    // *holder.r = 42; the loaded reference still has the tag popped by y
+    let r__tagged = r__slot.load_pointer(&mut sstate);
+    sstate.use_2_raw::<i32>(r__tagged);
+}
+
+#[kani::proof]
+fn swap_references() {
+    let mut sstate = SState::default();
+    let mut local1 = 1;
+    let local1__fixture = Fixture::allocation(&mut sstate, &local1 as *const i32, ALLOC_STACK);
+    let mut local2 = 2;
+    let local2__fixture = Fixture::allocation(&mut sstate, &local2 as *const i32, ALLOC_STACK);
+
+    // let mut a = &mut local1; let mut b = &mut local2;
+    let mut a = &mut local1;
+    let a__fixture = local1__fixture.mutable_ref(&mut sstate);
+    let a__slot = Fixture::allocation(&mut sstate, &a as *const &mut i32, ALLOC_STACK);
+    a__slot.store_pointer(&mut sstate, a__fixture.tagged());
+    let mut b = &mut local2;
+    let b__fixture = local2__fixture.mutable_ref(&mut sstate);
+    let b__slot = Fixture::allocation(&mut sstate, &b as *const &mut i32, ALLOC_STACK);
+    b__slot.store_pointer(&mut sstate, b__fixture.tagged());
+
+    // mem::swap(&mut a, &mut b);
+    std::mem::swap(&mut a, &mut b);
+    sstate.swap::<&mut i32>(a__slot.tagged(), b__slot.tagged());
+
+    // *a = 42; a now holds the tag of the borrow of local2
+    let a__tagged = a__slot.load_pointer(&mut sstate);
+    *a = 42;
+    sstate.use_2_raw::<i32>(a__tagged);
+    assert_eq!(a__tagged.tag, b__fixture.id);
+    assert_eq!(*b, 1);
+}
+
+#[derive(Clone, Copy)]
+struct SharedHolder<'a> {
+    r: &'a i32,
+}
+
+#[kani::proof]
+fn copied_reference_outlives_write() {
+    let mut sstate = SState::default();
+    let mut local = 5;
+    let local__fixture = Fixture::allocation(&mut sstate, &local as *const i32, ALLOC_STACK);
+
+    // let raw_pointer = &mut local as *mut i32;
+    let raw_pointer = &mut local as *mut i32;
+    let raw_pointer__fixture = local__fixture.mutable_raw(&mut sstate);
+
+    // let holder = SharedHolder { r: unsafe { &*raw_pointer } };
+    let r__fixture = raw_pointer__fixture.shared_ref(&mut sstate);
+    let holder = SharedHolder { r: unsafe { &*raw_pointer } };
+    let holder__fixture = Fixture::allocation(&mut sstate, &holder as *const SharedHolder, ALLOC_STACK);
+    holder__fixture.at(&holder.r as *const &i32).store_pointer(&mut sstate, r__fixture.tagged());
+
+    // let copy = holder.clone();
+    let copy = holder.clone();
+    let copy__fixture = Fixture::allocation(&mut sstate, &copy as *const SharedHolder, ALLOC_STACK);
+    sstate.copy_nonoverlapping::<SharedHolder>(holder__fixture.tagged(), copy__fixture.tagged(), 1);
+
+    // unsafe { *raw_pointer = 13 };
+    sstate.use_2_raw::<i32>(raw_pointer__fixture.at(raw_pointer as *const i32).tagged());
+
+    // let val = *copy.r; the copy carries the tag of r, which the write
+    // invalidated
+    let r__tagged = copy__fixture.at(&copy.r as *const &i32).load_pointer(&mut sstate);
+    assert!(r__tagged.kind == KIND_IDENTIFIED && r__tagged.tag == r__fixture.id);
+    assert!(!sstate.can_read(local__fixture.pointer, r__tagged.kind, r__tagged.tag));
+    let _val = *copy.r;
+}
+
+#[kani::proof]
+#[kani::should_panic]
+fn reference_outlives_local() {
+    let mut sstate = SState::default();
+    let x__fixture;
+    {
+        let local = 5;
+        let local__fixture = Fixture::allocation(&mut sstate, &local as *const i32, ALLOC_STACK);
+
+        // x = &local;
+        x__fixture = local__fixture.shared_ref(&mut sstate);
+
+        // StorageDead(local)
+        sstate.storage_dead(local__fixture.pointer);
+    }
+    // let val = *x;
+    x__fixture.read_1(&mut sstate);
+}
+
+#[kani::proof]
+#[kani::should_panic]
+fn reference_from_previous_iteration() {
+    let mut sstate = SState::default();
+    let mut local = 0;
+    let mut local__fixture = Fixture::allocation(&mut sstate, &local as *const i32, ALLOC_STACK);
+    let mut previous__fixture = local__fixture;
+    let mut i = 0;
+    while i < 2 {
+        // StorageLive(local) on every iteration but the first
+        if i > 0 {
+            local__fixture.id = sstate.storage_live(local__fixture.pointer);
+        }
+
+        // let x = &mut local; *x = i;
+        let x = &mut local;
+        let x__fixture = local__fixture.mutable_ref(&mut sstate);
+        *x = i;
+        x__fixture.use_2(&mut sstate);
+
+        // the reference kept from the previous iteration is dead
+        if i > 0 {
+            previous__fixture.use_2(&mut sstate);
+        }
+        previous__fixture = x__fixture;
+
+        // StorageDead(local)
+        sstate.storage_dead(local__fixture.pointer);
+        i += 1;
+    }
+}
+
+#[kani::proof]
+#[kani::should_panic]
+fn protected_local_dies() {
+    let mut sstate = SState::default();
+    let local = 5;
+    let local__fixture = Fixture::allocation(&mut sstate, &local as *const i32, ALLOC_STACK);
+
+    // fn f(x: &i32) { ... } called with f(&local), x is protected during
+    // the call, the local cannot go out of scope before the call returns
+    let x__fixture = local__fixture.shared_ref(&mut sstate);
+    sstate.protect(x__fixture.id);
+    sstate.storage_dead(local__fixture.pointer);
+    sstate.end_protect(1);
+}
+
+#[kani::proof]
+#[kani::should_panic]
+fn use_after_free() {
+    let mut sstate = SState::default();
+    let boxed = Box::new(5);
+    let boxed__fixture = Fixture::allocation(&mut sstate, &*boxed as *const i32, ALLOC_HEAP);
+
+    // let raw = Box::into_raw(boxed); let copy = raw;
+    let raw__tagged = boxed__fixture.tagged();
+    let copy__tagged = raw__tagged;
+
+    // drop(Box::from_raw(raw)); *copy = 42;
+    sstate.deallocate(raw__tagged);
+    sstate.use_2_raw::<i32>(copy__tagged);
+}
+
+#[kani::proof]
+#[kani::should_panic]
+fn deallocate_local() {
+    let mut sstate = SState::default();
+    let local = 5;
+    let local__fixture = Fixture::allocation(&mut sstate, &local as *const i32, ALLOC_STACK);
+
+    // dealloc(&local as *const i32 as *mut u8, ...)
+    sstate.deallocate(local__fixture.tagged());
+}
+
+#[kani::proof]
+fn queries_follow_invalidation() {
+    let mut sstate = SState::default();
+    let local = 5;
+    let local__fixture = Fixture::allocation(&mut sstate, &local as *const i32, ALLOC_STACK);
+    let local__pointer = local__fixture.pointer;
+
+    // let x = &mut local; let y = &mut *x;
+    let x__fixture = local__fixture.mutable_ref(&mut sstate);
+    let y__fixture = x__fixture.mutable_ref(&mut sstate);
+    let (local__id, x__id, y__id) = (local__fixture.id, x__fixture.id, y__fixture.id);
+    assert!(sstate.can_write(local__pointer, KIND_IDENTIFIED, y__id));
+    assert!(sstate.can_write(local__pointer, KIND_IDENTIFIED, x__id));
+    assert_eq!(sstate.stack_at(local__pointer).1, &[local__id, x__id, y__id]);
+
+    // *x = 42; pops y, the queries did not
+    x__fixture.use_2(&mut sstate);
+    assert!(!sstate.tag_live(local__pointer, KIND_IDENTIFIED, y__id));
+    assert!(!sstate.can_read(local__pointer, KIND_IDENTIFIED, y__id));
+    assert!(sstate.can_read(local__pointer, KIND_IDENTIFIED, x__id));
+    assert_eq!(sstate.stack_at(local__pointer).0, &[KIND_UNIQUE, KIND_UNIQUE]);
+
+    // a protected x is live but cannot be written through by the local
+    sstate.protect(x__id);
+    assert!(sstate.tag_live(local__pointer, KIND_IDENTIFIED, x__id));
+    assert!(!sstate.can_write(local__pointer, KIND_IDENTIFIED, local__id));
+    sstate.end_protect(1);
+}
+
+#[kani::proof]
+fn sb_queries_of_variables() {
+    new_local!(let mut local = 5);
+    raw_from_local!(let raw_pointer = &mut local as *mut i32);
+    unique_from_ref!(let x = unsafe { &mut *raw_pointer });
+    unique_from_ref!(let y = unsafe { &mut *raw_pointer });
+    assert!(sb::can_write(&y, sb::provenance(&y)));
+    assert!(!sb::tag_live(&x, sb::provenance(&x)));
+    // what a contract on example1(x, y) would see as provenance of x
+    pass_arg!(0, x);
+    assert!(!sb::can_read(&x, sb::arg_provenance(0)));
+    *y = 13;
+    use2!(y);
+}
+
+#[stacked_borrows::instrument]
+fn r#match(r#ref: &mut i32) -> i32 {
+    *r#ref = 2;
+    *r#ref
+}
+
+// Raw identifiers are lexed as identifiers, not as raw strings.
+#[kani::proof]
+#[stacked_borrows::instrument]
+fn raw_identifiers() {
+    let mut r#type = 1;
+    let result = r#match(&mut r#type);
+    assert_eq!(result, 2);
+    assert_eq!(r#type, 2);
 }
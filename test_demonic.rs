@@ -7,16 +7,26 @@
 const STACK_DEPTH: usize = 15;
 const MAX_NUM_OBJECTS: usize = 1024;
 const MAX_OBJECT_SIZE: usize = 64;
+// Number of bytes monitored by the instrumented functions, each with its own
+// stack. More monitors cover more locations in a single run at the cost of
+// more state, `instrumenter --demonic --monitors K` changes it.
+const MONITORS: usize = 1;
 type PtrId = u32;
 type StackItemKind = u32;
 
 const KIND_UNIQUE: StackItemKind = 0;
 const KIND_SHARED_RW: StackItemKind = 1;
+const KIND_SHARED_RO: StackItemKind = 2;
 
 type PointerValueKind = u32;
-const KIND_IDENTIFIED : StackItemKind = 0;
-const KIND_NONE: StackItemKind = 1;
+pub const KIND_IDENTIFIED : StackItemKind = 0;
+pub const KIND_NONE: StackItemKind = 1;
 
+mod shadow_map;
+mod tag_map;
+use tag_map::{slot_target, PointerSlot, TagMap};
+#[macro_use]
+mod macros;
 
 /*
 // Uncomment to debug with println
@@ -60,166 +70,654 @@ fn pointer_offset<U: Sized>(ptr: *const U) -> usize {
     kani::mem::pointer_offset(ptr)
 }
 
-#[derive(Debug)]
-struct SState {
-    monitor_object: usize,
-    monitor_offset: usize,
-    monitor_on: bool,
+fn pointer_range<U>(ptr: *const U) -> (usize, usize, usize) {
+    let obj = pointer_object(ptr);
+    let offset = pointer_offset(ptr);
+    let size = std::mem::size_of::<U>();
+    assert!(obj < MAX_NUM_OBJECTS);
+    assert!(offset <= MAX_OBJECT_SIZE && size <= MAX_OBJECT_SIZE - offset);
+    (obj, offset, size)
+}
+
+// A monitored byte and its borrow stack.
+#[derive(Debug, Clone, Copy)]
+struct Monitor {
+    object: usize,
+    offset: usize,
+    on: bool,
     stack_ids: [PtrId; STACK_DEPTH],
     stack_kinds: [StackItemKind; STACK_DEPTH],
     stack_tops: usize,
+}
+
+impl Monitor {
+    const OFF: Monitor = Monitor {
+        object: 0,
+        offset: 0,
+        on: false,
+        stack_ids: [0; STACK_DEPTH],
+        stack_kinds: [0; STACK_DEPTH],
+        stack_tops: 0,
+    };
+
+    // Whether the monitored byte is one of the size bytes at offset of obj.
+    fn covers(&self, obj: usize, offset: usize, size: usize) -> bool {
+        self.on && self.object == obj && offset <= self.offset && self.offset < offset + size
+    }
+
+    fn push(&mut self, kind: StackItemKind, id: PtrId) {
+        let top = self.stack_tops;
+        assert!(top < STACK_DEPTH);
+        self.stack_kinds[top] = kind;
+        self.stack_ids[top] = id;
+        self.stack_tops += 1;
+    }
+
+    // Inserts an item at index, moving the items above it up.
+    fn insert(&mut self, index: usize, kind: StackItemKind, id: PtrId) {
+        let top = self.stack_tops;
+        assert!(top < STACK_DEPTH);
+        let mut i = top;
+        while i > index {
+            self.stack_kinds[i] = self.stack_kinds[i - 1];
+            self.stack_ids[i] = self.stack_ids[i - 1];
+            i -= 1;
+        }
+        self.stack_kinds[index] = kind;
+        self.stack_ids[index] = id;
+        self.stack_tops += 1;
+    }
+
+    // Height of the stack once the items above the topmost one granting an
+    // access through the pointer are popped, 0 when no item grants it. A
+    // write (USE-2) keeps everything up to the granting item, a read
+    // (READ-1) also keeps the SharedRO items right above it. With tagged raw
+    // pointers a write granted by a SharedRW item also keeps the SharedRW
+    // items right above it, as in test.rs.
+    fn granting_top(&self, kind: PointerValueKind, id: PtrId, tagged_raw_pointers: bool, write: bool) -> usize {
+        let mut new_top = 0;
+        let mut i = 0;
+        while (i < STACK_DEPTH) && (i < self.stack_tops) {
+            let item_kind = self.stack_kinds[i];
+            let granting = if kind == KIND_IDENTIFIED {
+                let matching = self.stack_ids[i] == id;
+                if write { item_kind == KIND_UNIQUE && matching } else { item_kind != KIND_SHARED_RW && matching }
+            } else {
+                item_kind == KIND_SHARED_RW && (!tagged_raw_pointers || self.stack_ids[i] == id)
+            };
+            let kept = new_top > 0 && new_top == i && if write {
+                tagged_raw_pointers && item_kind == KIND_SHARED_RW && self.stack_kinds[i - 1] == KIND_SHARED_RW
+            } else {
+                item_kind == KIND_SHARED_RO
+            };
+            if granting || kept {
+                new_top = i + 1;
+            }
+            i += 1;
+        }
+        new_top
+    }
+}
+
+// K is the number of monitored bytes, each with its own stack. The full model
+// of test.rs tracks every byte, a single monitor tracks one byte per run.
+#[derive(Debug)]
+pub struct SState<const K: usize> {
+    monitors: [Monitor; K],
+    // replaces the demonic choice by monitoring this location from its
+    // allocation on, the witness choice of the soundness proofs
+    pinned_monitor: Option<(usize, usize)>,
+    // Stacked Borrows 2.1: raw pointers get fresh tags recorded in their
+    // SharedRW items instead of all sharing the bottom tag 0
+    tagged_raw_pointers: bool,
+    // kind and tag of every pointer variable and local, see tag_map.rs
+    tags: TagMap,
+    // prefix of the messages of failing checks, names the variable the
+    // current operation goes through, see macros.rs
+    context: &'static str,
     next_ptr_id: PtrId,
 }
 
-impl Default for SState {
-    fn default() -> SState {
+impl<const K: usize> Default for SState<K> {
+    fn default() -> SState<K> {
         SState {
-            monitor_object: 0,
-            monitor_offset: 0,
-            monitor_on: false,
-            stack_ids: [0; STACK_DEPTH],
-            stack_kinds: [0; STACK_DEPTH],
-            stack_tops: 0,
+            monitors: [Monitor::OFF; K],
+            pinned_monitor: None,
+            tagged_raw_pointers: false,
+            tags: TagMap::new(KIND_NONE, 0),
+            context: "",
             next_ptr_id: 0
         }
     }
 }
 
-impl SState {
-    pub fn push_shared<U>(&mut self, ptr: *const U) {
-        let obj = pointer_object(ptr);
-        let offset = pointer_offset(ptr);
-        // switch monitor to this one
-        if kani::any::<bool>() {
-            self.monitor_object = obj;
-            self.monitor_offset = offset;
-            self.monitor_on = true;
-        }
-        if self.monitor_object == obj && self.monitor_offset == offset && self.monitor_on {
-            let top = self.stack_tops;
-            assert!(top < STACK_DEPTH);
-            self.stack_tops += 1;
+impl<const K: usize> SState<K> {
+    /// Whether a monitor is on the byte at offset of obj.
+    fn is_monitored(&self, obj: usize, offset: usize) -> bool {
+        let mut m = 0;
+        while m < K {
+            if self.monitors[m].covers(obj, offset, 1) {
+                return true;
+            }
+            m += 1;
         }
+        false
     }
 
-    pub fn push_unique<U>(&mut self, ptr: *const U) -> PtrId {
-        let obj = pointer_object(ptr);
-        let offset = pointer_offset(ptr);
-        if kani::any::<bool>() {
-            self.monitor_object = obj;
-            self.monitor_offset = offset;
-            self.monitor_on = true;
-        }
-
-        if self.monitor_object == obj && self.monitor_offset == offset && self.monitor_on {
-            let top = self.stack_tops;
-            assert!(top < STACK_DEPTH);
-            self.stack_kinds[top] = KIND_UNIQUE;
-            let ptr_id_old = self.next_ptr_id;
-            self.stack_ids[top] = ptr_id_old;
-            self.stack_tops += 1;
-            self.next_ptr_id += 1;
-            ptr_id_old
-        } else {
-            0
+    // Whether any monitor is on one of the size bytes at offset of obj.
+    fn covers(&self, obj: usize, offset: usize, size: usize) -> bool {
+        let mut m = 0;
+        while m < K {
+            if self.monitors[m].covers(obj, offset, size) {
+                return true;
+            }
+            m += 1;
         }
+        false
     }
 
-    fn use_2<U>(&mut self, ptr: *const U, kind: PointerValueKind, id: PtrId) {
-        let obj = pointer_object(ptr);
-        let offset = pointer_offset(ptr);
-
-        if self.monitor_object == obj && self.monitor_offset == offset && self.monitor_on {
-            let top = self.stack_tops;
-            let mut found = false;
-            if kind == KIND_IDENTIFIED {
-                let mut i = 0;
-                let mut new_top = 0;
-                while (i < STACK_DEPTH) && (i < top) {
-                    if self.stack_kinds[i] == KIND_UNIQUE && self.stack_ids[i] == id {
-                        new_top = i+1;
-                        found = true;
-                    }
-                    i += 1;
+    // Byte of a new allocation a free monitor starts on, if any.
+    fn choose_location(&self, obj: usize, offset: usize, size: usize) -> Option<usize> {
+        let location = match self.pinned_monitor {
+            Some((pinned_obj, pinned_offset)) => {
+                if pinned_obj != obj || pinned_offset < offset || pinned_offset >= offset + size {
+                    return None;
                 }
-                self.stack_tops = new_top;
-            } else {
-                let mut i = 0;
-                let mut new_top = 0;
-                while (i < STACK_DEPTH) && (i < top) {
-                    if self.stack_kinds[i] == KIND_SHARED_RW {
-                        new_top = i+1;
-                        found = true;
+                pinned_offset
+            }
+            None => {
+                if size == 0 || !kani::any::<bool>() {
+                    return None;
+                }
+                let mut location = offset;
+                let mut i = 1;
+                while i < size {
+                    if kani::any::<bool>() {
+                        location = offset + i;
                     }
                     i += 1;
                 }
-                self.stack_tops = new_top;
+                location
             }
-            assert!(found);
+        };
+        if self.is_monitored(obj, location) { None } else { Some(location) }
+    }
+
+    /// Replaces the demonic choice by a monitor on the byte ptr points to,
+    /// from the allocation of that byte on.
+    pub fn pin_monitor<U>(&mut self, ptr: *const U) {
+        self.pinned_monitor = Some((pointer_object(ptr), pointer_offset(ptr)));
+    }
+
+    /// Creates the borrow stacks of a new allocation with their base Unique
+    /// item and returns its tag. The monitored locations are chosen here
+    /// once: each free monitor may start on one byte of the allocation with a
+    /// fresh stack and never moves to a later allocation, so a stack only
+    /// ever holds items of its own location.
+    pub fn new_allocation<U>(&mut self, ptr: *const U) -> PtrId {
+        let (obj, offset, size) = pointer_range(ptr);
+        let mut m = 0;
+        while m < K {
+            if !self.monitors[m].on {
+                if let Some(location) = self.choose_location(obj, offset, size) {
+                    self.monitors[m] = Monitor { object: obj, offset: location, on: true, ..Monitor::OFF };
+                }
+            }
+            m += 1;
         }
+        self.push_range(obj, offset, size, KIND_UNIQUE)
     }
 
-    fn new_mutable_ref<U>(&mut self, loc: *const U, kind: PointerValueKind, tag: PtrId) -> PtrId {
+    // Pushes an item of the given kind with a new tag on the stacks of the
+    // monitored bytes of the range and returns the tag. Unique and SharedRO
+    // items always get fresh tags, so that tags do not depend on the monitor
+    // choice, SharedRW items only with tagged raw pointers.
+    fn push_range(&mut self, obj: usize, offset: usize, size: usize, kind: StackItemKind) -> PtrId {
+        let fresh = kind != KIND_SHARED_RW || self.tagged_raw_pointers;
+        let id = if fresh { self.next_ptr_id } else { 0 };
+        if fresh {
+            self.next_ptr_id += 1;
+        }
+        let mut m = 0;
+        while m < K {
+            if self.monitors[m].covers(obj, offset, size) {
+                self.monitors[m].push(kind, id);
+            }
+            m += 1;
+        }
+        id
+    }
+
+    pub fn push_shared<U>(&mut self, ptr: *const U) -> PtrId {
+        let (obj, offset, size) = pointer_range(ptr);
+        self.push_range(obj, offset, size, KIND_SHARED_RW)
+    }
+
+    pub fn push_unique<U>(&mut self, ptr: *const U) -> PtrId {
+        let (obj, offset, size) = pointer_range(ptr);
+        self.push_range(obj, offset, size, KIND_UNIQUE)
+    }
+
+    pub fn push_shared_ro<U>(&mut self, ptr: *const U) -> PtrId {
+        let (obj, offset, size) = pointer_range(ptr);
+        self.push_range(obj, offset, size, KIND_SHARED_RO)
+    }
+
+    // Pops the items above the one granting the access on every monitored
+    // byte of the range, failing if no item grants it.
+    fn access_range(&mut self, obj: usize, offset: usize, size: usize, kind: PointerValueKind, id: PtrId,
+                    write: bool) {
+        let mut m = 0;
+        while m < K {
+            if self.monitors[m].covers(obj, offset, size) {
+                let new_top = self.monitors[m].granting_top(kind, id, self.tagged_raw_pointers, write);
+                self.monitors[m].stack_tops = new_top;
+                assert!(new_top > 0, "{}Stack violated.", self.context);
+            }
+            m += 1;
+        }
+    }
+
+    fn use_2_range(&mut self, obj: usize, offset: usize, size: usize, kind: PointerValueKind, id: PtrId) {
+        self.access_range(obj, offset, size, kind, id, true);
+    }
+
+    pub fn use_2<U>(&mut self, ptr: *const U, kind: PointerValueKind, id: PtrId) {
+        let (obj, offset, size) = pointer_range(ptr);
+        self.use_2_range(obj, offset, size, kind, id);
+    }
+
+    pub fn read_1<U>(&mut self, ptr: *const U, kind: PointerValueKind, id: PtrId) {
+        let (obj, offset, size) = pointer_range(ptr);
+        self.access_range(obj, offset, size, kind, id, false);
+    }
+
+    /// Whether use_2 would succeed. Changes nothing.
+    pub fn can_use_2<U>(&self, ptr: *const U, kind: PointerValueKind, id: PtrId) -> bool {
+        self.can_access(ptr, kind, id, true)
+    }
+
+    /// Whether read_1 would succeed. Changes nothing.
+    pub fn can_read_1<U>(&self, ptr: *const U, kind: PointerValueKind, id: PtrId) -> bool {
+        self.can_access(ptr, kind, id, false)
+    }
+
+    fn can_access<U>(&self, ptr: *const U, kind: PointerValueKind, id: PtrId, write: bool) -> bool {
+        let (obj, offset, size) = pointer_range(ptr);
+        let mut m = 0;
+        while m < K {
+            if self.monitors[m].covers(obj, offset, size)
+                && self.monitors[m].granting_top(kind, id, self.tagged_raw_pointers, write) == 0 {
+                return false;
+            }
+            m += 1;
+        }
+        true
+    }
+
+    pub fn new_mutable_ref<U>(&mut self, loc: *const U, kind: PointerValueKind, tag: PtrId) -> PtrId {
         self.use_2(loc, kind, tag);
         self.push_unique(loc)
     }
 
-    fn new_mutable_raw<U>(&mut self, loc: *const U, kind: PointerValueKind, tag: PtrId) -> PtrId {
+    pub fn new_mutable_raw<U>(&mut self, loc: *const U, kind: PointerValueKind, tag: PtrId) -> PtrId {
+        if self.tagged_raw_pointers {
+            return self.insert_shared(loc, kind, tag);
+        }
         self.use_2(loc, kind, tag);
-        self.push_shared(loc);
-        0
-    }
-}
-
-fn example1(x: &mut i32, x__pointer: *const i32, x__pointer_kind: PointerValueKind, x__id: PtrId,
-            y: &mut i32, y__pointer: *const i32, y__pointer_kind: PointerValueKind, y__id: PtrId,
-            sstate: &mut SState) -> i32 {
-    let x_rename = &mut *x;
-    let x_rename__pointer = x__pointer;
-    let x_rename__pointer_kind = KIND_IDENTIFIED;
-    let x_rename__pointer_id = sstate.new_mutable_ref(x__pointer, x__pointer_kind, x__id);
-
-    let y_rename = &mut *y;
-    let y_rename__pointer = y__pointer;
-    let y_rename__pointer_kind = KIND_IDENTIFIED;
-    let y_rename__pointer_id = sstate.new_mutable_ref(y__pointer, y__pointer_kind, y__id);
-    *x_rename = 42;
-    sstate.use_2(x_rename__pointer, x_rename__pointer_kind, x_rename__pointer_id);
-    *y_rename = 13;
-    sstate.use_2(y_rename__pointer, y_rename__pointer_kind, y_rename__pointer_id);
+        self.push_shared(loc)
+    }
+
+    // Raw reborrow of Stacked Borrows 2.1, see insert_shared_range in
+    // test.rs: no access, the SharedRW item is inserted right above the
+    // granting item and its sibling raw pointers on every monitored byte.
+    fn insert_shared<U>(&mut self, loc: *const U, kind: PointerValueKind, tag: PtrId) -> PtrId {
+        let (obj, offset, size) = pointer_range(loc);
+        let id = self.next_ptr_id;
+        self.next_ptr_id += 1;
+        let mut m = 0;
+        while m < K {
+            if self.monitors[m].covers(obj, offset, size) {
+                let index = self.monitors[m].granting_top(kind, tag, true, true);
+                assert!(index > 0, "{}Stack violated.", self.context);
+                self.monitors[m].insert(index, KIND_SHARED_RW, id);
+            }
+            m += 1;
+        }
+        id
+    }
+
+    pub fn new_shared_ref<U>(&mut self, loc: *const U, kind: PointerValueKind, tag: PtrId) -> PtrId {
+        self.read_1(loc, kind, tag);
+        self.push_shared_ro(loc)
+    }
+
+    /// `&mut (*parent).field`: reborrows the field bytes only, so that the
+    /// monitors on the other fields keep their stacks.
+    fn new_mutable_field_ref<U>(&mut self, parent: *const U, kind: PointerValueKind, tag: PtrId,
+                                field_offset: usize, field_size: usize) -> PtrId {
+        let (obj, offset, size) = pointer_range(parent);
+        assert!(field_offset <= size && field_size <= size - field_offset);
+        self.use_2_range(obj, offset + field_offset, field_size, kind, tag);
+        self.push_range(obj, offset + field_offset, field_size, KIND_UNIQUE)
+    }
+
+    // The operations below find the tags of pointers in the tag map from
+    // the address of the variable holding them.
+
+    /// `let local = ...;`
+    fn new_local<U>(&mut self, local: *const U) {
+        let id = self.new_allocation(local);
+        self.tags.set(local, KIND_IDENTIFIED, id);
+    }
+
+    /// `let new_raw = &mut local as *mut _;`, through a temporary reference
+    fn raw_from_local<P: PointerSlot>(&mut self, new_raw: *const P, local: *const P::Target) {
+        let (kind, tag) = self.tags.get(local);
+        let temporary_ref__id = self.new_mutable_ref(local, kind, tag);
+        let id = self.new_mutable_raw(local, KIND_IDENTIFIED, temporary_ref__id);
+        self.tags.set(new_raw, KIND_NONE, id);
+    }
+
+    /// `let new_ref = &mut local;`
+    fn unique_from_local<P: PointerSlot>(&mut self, new_ref: *const P, local: *const P::Target) {
+        let (kind, tag) = self.tags.get(local);
+        let id = self.new_mutable_ref(local, kind, tag);
+        self.tags.set(new_ref, KIND_IDENTIFIED, id);
+    }
+
+    /// `let new_ref = &local;`
+    fn shared_from_local<P: PointerSlot>(&mut self, new_ref: *const P, local: *const P::Target) {
+        let (kind, tag) = self.tags.get(local);
+        let id = self.new_shared_ref(local, kind, tag);
+        self.tags.set(new_ref, KIND_IDENTIFIED, id);
+    }
+
+    /// `let new_ref = &mut *old_ref;`
+    fn unique_from_ref<P, Q>(&mut self, new_ref: *const P, old_ref: *const Q)
+    where P: PointerSlot, Q: PointerSlot<Target = P::Target> {
+        let (kind, tag) = self.tags.get(old_ref);
+        let id = self.new_mutable_ref(slot_target(old_ref), kind, tag);
+        self.tags.set(new_ref, KIND_IDENTIFIED, id);
+    }
+
+    /// `let new_ref = &*old_ref;`
+    fn shared_from_ref<P, Q>(&mut self, new_ref: *const P, old_ref: *const Q)
+    where P: PointerSlot, Q: PointerSlot<Target = P::Target> {
+        let (kind, tag) = self.tags.get(old_ref);
+        let id = self.new_shared_ref(slot_target(old_ref), kind, tag);
+        self.tags.set(new_ref, KIND_IDENTIFIED, id);
+    }
+
+    /// `let new_raw = old_ref as *mut _;`
+    fn raw_from_ref<P, Q>(&mut self, new_raw: *const P, old_ref: *const Q)
+    where P: PointerSlot, Q: PointerSlot<Target = P::Target> {
+        let (kind, tag) = self.tags.get(old_ref);
+        let id = self.new_mutable_raw(slot_target(old_ref), kind, tag);
+        self.tags.set(new_raw, KIND_NONE, id);
+    }
+
+    /// `*ptr = ...` through the pointer held by the variable at slot.
+    fn use_2_at<P: PointerSlot>(&mut self, slot: *const P) {
+        let (kind, tag) = self.tags.get(slot);
+        self.use_2(slot_target(slot), kind, tag);
+    }
+
+    /// `... = *ptr` through the pointer held by the variable at slot.
+    fn read_1_at<P: PointerSlot>(&mut self, slot: *const P) {
+        let (kind, tag) = self.tags.get(slot);
+        self.read_1(slot_target(slot), kind, tag);
+    }
+
+    /// `*ptr = value` where value is a pointer: a write through the pointer
+    /// held by the variable at slot. The tag of value is kept in the tag map
+    /// under the address it is stored at, as for a variable.
+    fn store_pointer_at<P: PointerSlot, V>(&mut self, slot: *const P, value: *const V) {
+        self.use_2_at(slot);
+        let (kind, tag) = self.tags.get(value);
+        self.tags.set(slot_target(slot), kind, tag);
+    }
+
+    /// `let x = *ptr` where x is a pointer: a read through the pointer held
+    /// by the variable at slot, x gets the tag stored with the value.
+    fn load_pointer_at<X, P: PointerSlot>(&mut self, x: *const X, slot: *const P) {
+        self.read_1_at(slot);
+        let (kind, tag) = self.tags.get(slot_target(slot));
+        self.tags.set(x, kind, tag);
+    }
+
+    /// `local = ...`
+    fn use_2_local<U>(&mut self, local: *const U) {
+        let (kind, tag) = self.tags.get(local);
+        self.use_2(local, kind, tag);
+    }
+
+    /// `... = local`
+    fn read_1_local<U>(&mut self, local: *const U) {
+        let (kind, tag) = self.tags.get(local);
+        self.read_1(local, kind, tag);
+    }
+}
+
+// Model state of the instrumented functions. They keep their original
+// signatures, so they cannot be handed the state and look it up here.
+static mut SSTATE: Option<SState<MONITORS>> = None;
+
+// Runs f on the model state, borrowing SSTATE only for the duration of f as in
+// test.rs.
+fn with_sstate<R>(f: impl FnOnce(&mut SState<MONITORS>) -> R) -> R {
+    let sstate = unsafe { &mut *std::ptr::addr_of_mut!(SSTATE) };
+    f(sstate.get_or_insert_with(SState::default))
+}
+
+// Same, with the context naming the variable in the messages of the checks
+// failing in f.
+fn with_context<R>(context: &'static str, f: impl FnOnce(&mut SState<MONITORS>) -> R) -> R {
+    with_sstate(|sstate| {
+        sstate.context = context;
+        let result = f(sstate);
+        sstate.context = "";
+        result
+    })
+}
+
+// Model functions the macros of macros.rs expand to. The context names the
+// variable in the messages of the checks failing during the operation.
+
+fn sb_new_local<U>(local: *const U, context: &'static str) {
+    with_context(context, |sstate| sstate.new_local(local));
+}
+
+fn sb_unique_from_local<P: PointerSlot>(new_ref: *const P, local: *const P::Target, context: &'static str) {
+    with_context(context, |sstate| sstate.unique_from_local(new_ref, local));
+}
+
+fn sb_shared_from_local<P: PointerSlot>(new_ref: *const P, local: *const P::Target, context: &'static str) {
+    with_context(context, |sstate| sstate.shared_from_local(new_ref, local));
+}
+
+fn sb_unique_from_ref<P, Q>(new_ref: *const P, old_ref: *const Q, context: &'static str)
+where P: PointerSlot, Q: PointerSlot<Target = P::Target> {
+    with_context(context, |sstate| sstate.unique_from_ref(new_ref, old_ref));
+}
+
+fn sb_shared_from_ref<P, Q>(new_ref: *const P, old_ref: *const Q, context: &'static str)
+where P: PointerSlot, Q: PointerSlot<Target = P::Target> {
+    with_context(context, |sstate| sstate.shared_from_ref(new_ref, old_ref));
+}
+
+fn sb_raw_from_ref<P, Q>(new_raw: *const P, old_ref: *const Q, context: &'static str)
+where P: PointerSlot, Q: PointerSlot<Target = P::Target> {
+    with_context(context, |sstate| sstate.raw_from_ref(new_raw, old_ref));
+}
+
+fn sb_raw_from_local<P: PointerSlot>(new_raw: *const P, local: *const P::Target, context: &'static str) {
+    with_context(context, |sstate| sstate.raw_from_local(new_raw, local));
+}
+
+fn sb_use2<P: PointerSlot>(used: *const P, context: &'static str) {
+    with_context(context, |sstate| sstate.use_2_at(used));
+}
+
+fn sb_use2_local<U>(local: *const U, context: &'static str) {
+    with_context(context, |sstate| sstate.use_2_local(local));
+}
+
+fn sb_read1_local<U>(local: *const U, context: &'static str) {
+    with_context(context, |sstate| sstate.read_1_local(local));
+}
+
+fn sb_copy_pointer<X, P>(x: *const X, source: *const P) {
+    with_sstate(|sstate| {
+        let (kind, tag) = sstate.tags.get(source);
+        sstate.tags.set(x, kind, tag);
+    });
+}
+
+fn sb_store_pointer<P: PointerSlot, V>(used: *const P, value: *const V, context: &'static str) {
+    with_context(context, |sstate| sstate.store_pointer_at(used, value));
+}
+
+fn sb_load_pointer<X, P: PointerSlot>(x: *const X, used: *const P, context: &'static str) {
+    with_context(context, |sstate| sstate.load_pointer_at(x, used));
+}
+
+fn sb_read1<P: PointerSlot>(used: *const P, context: &'static str) {
+    with_context(context, |sstate| sstate.read_1_at(used));
+}
+
+fn sb_pass_arg<P>(index: usize, arg: *const P) {
+    with_sstate(|sstate| sstate.tags.pass_arg(index, arg));
+}
+
+fn sb_bind_arg<P>(index: usize, param: *const P) {
+    with_sstate(|sstate| sstate.tags.bind_arg(index, param));
+}
+
+fn sb_pass_return<P>(result: *const P) {
+    with_sstate(|sstate| sstate.tags.pass_return(result));
+}
+
+fn sb_bind_return<P>(result: *const P) {
+    with_sstate(|sstate| sstate.tags.bind_return(result));
+}
+
+// This model has no protectors, the retag on function entry is a plain
+// reborrow.
+fn sb_protect<P>(_arg: *const P) -> usize {
+    0
+}
+
+fn sb_end_protect(_count: usize) {}
+
+////// end of model //////
+// instrumenter.rs copies everything above into the harnesses it generates.
+
+#[stacked_borrows::instrument]
+fn example1(x: &mut i32, y: &mut i32) -> i32 {
+    *x = 42;
+    *y = 13;
     *x
 }
 
 #[kani::proof]
+#[stacked_borrows::instrument]
 fn main() {
-    let mut sstate = SState::default();
     let mut local = 5;
-    let local__pointer = &local as *const i32;
-    let local__pointer_kind = KIND_IDENTIFIED;
-    let local__id = sstate.push_unique(local__pointer);
-
     let raw_pointer = &mut local as *mut i32;
-    let temporary_ref__pointer = &local as *const i32;
-    let temporary_ref__pointer_kind = KIND_IDENTIFIED;
-    let temporary_ref__id = sstate.new_mutable_ref(local__pointer, local__pointer_kind, local__id);
-
-    let raw_pointer__pointer = &local as *const i32;
-    let raw_pointer__pointer_kind = KIND_NONE;
-    let raw_pointer__id = sstate.new_mutable_raw(temporary_ref__pointer, temporary_ref__pointer_kind, temporary_ref__id);
-
-    let x__pointer = &local as *const i32;
-    let x__pointer_kind = KIND_IDENTIFIED;
-    let x__id = sstate.new_mutable_ref(raw_pointer__pointer, raw_pointer__pointer_kind, raw_pointer__id);
-
-    let y__pointer = &local as *const i32;
-    let y__pointer_kind = KIND_IDENTIFIED;
-    let y__id = sstate.new_mutable_ref(raw_pointer__pointer, raw_pointer__pointer_kind, raw_pointer__id);
 
     let result = unsafe {
-        example1(&mut *raw_pointer, x__pointer, x__pointer_kind, x__id,
-                 &mut *raw_pointer, y__pointer, y__pointer_kind, y__id,
-                 &mut sstate)
+        example1(&mut *raw_pointer, &mut *raw_pointer)
     };
     assert_eq!(result, 13);
+}
+
+// Valid reborrows of a local allocated after the monitored one. They used to
+// move the monitor onto the second local with the stack of the first one, so
+// the second reborrow of b failed.
+#[kani::proof]
+#[stacked_borrows::instrument]
+fn monitor_stays_on_its_allocation() {
+    let mut a = 1;
+    let mut b = 2;
+    let x = &mut a;
+    *x = 3;
+    let x = &mut a;
+    *x = 4;
+    let y = &mut b;
+    *y = 5;
+    let y = &mut b;
+    *y = 6;
+    assert_eq!(a + b, 10);
+}
+
+// Reads through a unique reference keep the shared references derived from
+// it valid, a write through it would pop them.
+#[kani::proof]
+#[stacked_borrows::instrument]
+fn shared_references_read() {
+    let mut a = 1;
+    let m = &mut a;
+    let r = &*m;
+    let s = &*r;
+    let x = *s;
+    let y = *r;
+    let z = *m;
+    let w = *s;
+    assert_eq!(x + y + z + w, 4);
+}
+
+// A write through the raw pointer a shared reference was derived from pops
+// its SharedRO item, reading through the reference afterwards is a violation.
+#[kani::proof]
+#[kani::should_panic]
+#[stacked_borrows::instrument]
+fn shared_reference_read_after_raw_write() {
+    let mut a = 1;
+    let p = &mut a as *mut i32;
+    let r = unsafe { &*p };
+    unsafe { *p = 2; }
+    let x = *r;
+    assert_eq!(x, 2);
+}
+
+struct Pair {
+    a: i32,
+    b: i32,
+}
+
+// Reborrowing a pair through its own tag invalidates the references to both
+// of its fields. Two monitors can see both invalid references in one run,
+// a single one only sees the reference to the field it is on.
+#[kani::proof]
+fn parent_reborrow_with_two_monitors() {
+    let mut sstate = SState::<2>::default();
+    let pair = Pair { a: 1, b: 2 };
+    let pair__pointer = &pair as *const Pair;
+    let pair__pointer_kind = KIND_IDENTIFIED;
+    let pair__id = sstate.new_allocation(pair__pointer);
+
+    // let a = &mut pair.a;
+    let a__pointer = &pair.a as *const i32;
+    let a__pointer_kind = KIND_IDENTIFIED;
+    let a__id = sstate.new_mutable_field_ref(pair__pointer, pair__pointer_kind, pair__id,
+                                             std::mem::offset_of!(Pair, a), std::mem::size_of::<i32>());
+
+    // let b = &mut pair.b;
+    let b__pointer = &pair.b as *const i32;
+    let b__pointer_kind = KIND_IDENTIFIED;
+    let b__id = sstate.new_mutable_field_ref(pair__pointer, pair__pointer_kind, pair__id,
+                                             std::mem::offset_of!(Pair, b), std::mem::size_of::<i32>());
+
+    // let parent = &mut pair;
+    sstate.new_mutable_ref(pair__pointer, pair__pointer_kind, pair__id);
 
+    // *a = 42; and *b = 13; now fail on every monitored field
+    let (obj, a__offset, size) = pointer_range(a__pointer);
+    let a__monitored = sstate.covers(obj, a__offset, size);
+    let (obj, b__offset, size) = pointer_range(b__pointer);
+    let b__monitored = sstate.covers(obj, b__offset, size);
+    assert_eq!(sstate.can_use_2(a__pointer, a__pointer_kind, a__id), !a__monitored);
+    assert_eq!(sstate.can_use_2(b__pointer, b__pointer_kind, b__id), !b__monitored);
+    kani::cover!(a__monitored && b__monitored);
 }
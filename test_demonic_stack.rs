@@ -8,7 +8,13 @@
 #![feature(const_trait_impl)]
 #![cfg_attr(not(kani), feature(register_tool))]
 #![cfg_attr(not(kani), register_tool(kani))]
-use std::ptr::null;
+use std::ptr::{addr_of_mut, null};
+
+mod shadow_map;
+mod tag_map;
+use tag_map::{slot_target, PointerSlot, TagMap};
+#[macro_use]
+mod macros;
 
 const STACK_DEPTH: usize = 15;
 type PointerId = u32;
@@ -16,6 +22,7 @@ type StackItemKind = u32;
 
 const KIND_UNIQUE: StackItemKind = 0;
 const KIND_SHARED_RW: StackItemKind = 1;
+const KIND_SHARED_RO: StackItemKind = 2;
 
 type PointerValueKind = u32;
 const KIND_IDENTIFIED : PointerValueKind = 0;
@@ -26,11 +33,50 @@ fn demonic_nondet() -> bool {
     kani::any::<bool>()
 }
 
+// Natively the answers of demonic_nondet() come from a log: a run replays
+// the answers logged so far and answers the choices beyond them, logging
+// these answers. New choices are answered true, or pseudo-randomly when the
+// run has a seed. A plain native run answers true everywhere,
+// enumerate_demonic_choices backtracks over the log to run every
+// combination of answers and fuzz_demonic_choices runs many seeds.
+#[cfg(not(kani))]
+static mut DEMONIC_CHOICES: Vec<bool> = Vec::new();
+#[cfg(not(kani))]
+static mut DEMONIC_NEXT_CHOICE: usize = 0;
+// state of the pseudo-random generator of the run, if it has a seed
+#[cfg(not(kani))]
+static mut DEMONIC_RANDOM: Option<u64> = None;
+// whether the operations print the monitored stack and the argument tags, see
+// replay_demonic_choices
+#[cfg(not(kani))]
+static mut DEMONIC_TRACE: bool = false;
+
 #[cfg(not(kani))]
 fn demonic_nondet() -> bool {
-    true
+    unsafe {
+        let choices = &mut *addr_of_mut!(DEMONIC_CHOICES);
+        let next = DEMONIC_NEXT_CHOICE;
+        if next == choices.len() {
+            let answer = match &mut *addr_of_mut!(DEMONIC_RANDOM) {
+                Some(state) => next_random(state) & 1 == 1,
+                None => true,
+            };
+            choices.push(answer);
+        }
+        DEMONIC_NEXT_CHOICE += 1;
+        choices[next]
+    }
 }
 
+// SplitMix64, enough to spread the answers of nearby seeds.
+#[cfg(not(kani))]
+fn next_random(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
 
 #[cfg(any(kani))]
 fn same_pointer<T, U>(ptr1: *const T, ptr2: *const U) -> bool {
@@ -45,10 +91,191 @@ fn same_pointer<T, U>(ptr1: *const T, ptr2: *const U) -> bool {
 static mut SSTATE_MONITOR_OBJECT: *const u8 = null();
 static mut SSTATE_MONITOR_OFFSET: usize = 0;
 static mut SSTATE_MONITOR_ON: bool = false;
+static mut SSTATE_MONITOR_DEAD: bool = false;
 static mut SSTATE_STACK_IDS: [PointerId; STACK_DEPTH] = [0; STACK_DEPTH];
 static mut SSTATE_STACK_KINDS: [StackItemKind; STACK_DEPTH] = [0; STACK_DEPTH];
 static mut SSTATE_STACK_TOPS: usize = 0;
 static mut SSTATE_NEXT_PTR_ID: PointerId = 0;
+// kind and tag of every pointer variable and local, see tag_map.rs
+static mut SSTATE_TAGS: TagMap = TagMap::new(KIND_NONE, 0);
+// prefix of the messages of failing checks, names the variable the current
+// operation goes through, see macros.rs
+static mut SSTATE_CONTEXT: &str = "";
+
+fn tags() -> &'static mut TagMap {
+    unsafe { &mut *addr_of_mut!(SSTATE_TAGS) }
+}
+
+// Puts the model back in its initial state, before a native run of a harness.
+#[cfg(not(kani))]
+fn reset_sstate() {
+    unsafe {
+        SSTATE_MONITOR_OBJECT = null();
+        SSTATE_MONITOR_OFFSET = 0;
+        SSTATE_MONITOR_ON = false;
+        SSTATE_MONITOR_DEAD = false;
+        SSTATE_STACK_IDS = [0; STACK_DEPTH];
+        SSTATE_STACK_KINDS = [0; STACK_DEPTH];
+        SSTATE_STACK_TOPS = 0;
+        SSTATE_NEXT_PTR_ID = 0;
+        *addr_of_mut!(SSTATE_TAGS) = TagMap::new(KIND_NONE, 0);
+        SSTATE_CONTEXT = "";
+        DEMONIC_NEXT_CHOICE = 0;
+    }
+}
+
+// Prints the monitored stack after an operation of a traced native run, or
+// before the check of an access when it fails, prefixed by the context naming
+// the operation.
+#[cfg(not(kani))]
+fn trace_stack() {
+    unsafe {
+        if !DEMONIC_TRACE {
+            return;
+        }
+        let context = SSTATE_CONTEXT;
+        if !SSTATE_MONITOR_ON {
+            println!("{}no monitor", context);
+            return;
+        }
+        let mut items = Vec::new();
+        let mut i = 0;
+        while i < SSTATE_STACK_TOPS {
+            let id = SSTATE_STACK_IDS[i];
+            items.push(match SSTATE_STACK_KINDS[i] {
+                KIND_UNIQUE => format!("Unique({})", id),
+                KIND_SHARED_RO => format!("SharedRO({})", id),
+                _ => "SharedRW".to_string(),
+            });
+            i += 1;
+        }
+        let offset = SSTATE_MONITOR_OFFSET;
+        let dead = if SSTATE_MONITOR_DEAD { ", dead" } else { "" };
+        println!("{}offset {}{}: [{}]", context, offset, dead, items.join(", "));
+    }
+}
+
+#[cfg(kani)]
+fn trace_stack() {}
+
+// Prints the kind and tag an argument is passed or bound with in a traced
+// native run.
+#[cfg(not(kani))]
+fn trace_arg(operation: &str, index: usize, (kind, tag): (PointerValueKind, PointerId)) {
+    if unsafe { DEMONIC_TRACE } {
+        if kind == KIND_IDENTIFIED {
+            println!("{} {}: tag {}", operation, index, tag);
+        } else {
+            println!("{} {}: untagged", operation, index);
+        }
+    }
+}
+
+#[cfg(kani)]
+fn trace_arg(_operation: &str, _index: usize, _provenance: (PointerValueKind, PointerId)) {}
+
+// Runs the harness from the initial model state without printing its
+// panic, and returns the message of the check that failed if it fails.
+#[cfg(not(kani))]
+fn run_quietly(harness: fn()) -> Result<(), String> {
+    reset_sstate();
+    let hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(|_| {}));
+    let result = std::panic::catch_unwind(harness);
+    std::panic::set_hook(hook);
+    result.map_err(|payload| match payload.downcast_ref::<String>() {
+        Some(message) => message.clone(),
+        None => payload.downcast_ref::<&str>().map_or(String::new(), |m| m.to_string()),
+    })
+}
+
+/// Answers of demonic_nondet() in a failing native run and the message of
+/// the check that failed.
+#[cfg(not(kani))]
+pub type FailingRun = (Vec<bool>, String);
+
+/// Runs the harness natively once for every combination of answers of
+/// demonic_nondet(), each time from the initial model state, and returns
+/// the number of runs and every failing one. Runs are explored depth first:
+/// after a run the last true answer of the log becomes false and the answers
+/// after it are dropped, to be logged afresh by the next run.
+#[cfg(not(kani))]
+pub fn enumerate_demonic_choices(harness: fn()) -> (usize, Vec<FailingRun>) {
+    let mut runs = 0;
+    let mut failures = Vec::new();
+    let choices = unsafe { &mut *addr_of_mut!(DEMONIC_CHOICES) };
+    choices.clear();
+    loop {
+        let result = run_quietly(harness);
+        runs += 1;
+        choices.truncate(unsafe { DEMONIC_NEXT_CHOICE });
+        if let Err(message) = result {
+            failures.push((choices.clone(), message));
+        }
+        while choices.last() == Some(&false) {
+            choices.pop();
+        }
+        match choices.last_mut() {
+            Some(last) => *last = false,
+            None => break,
+        }
+    }
+    reset_sstate();
+    (runs, failures)
+}
+
+/// Runs the harness natively with pseudo-random answers of demonic_nondet()
+/// drawn from the seed, and returns the answers and the message of the check
+/// that failed if it fails. The same seed always gives the same answers.
+#[cfg(not(kani))]
+pub fn run_with_seed(harness: fn(), seed: u64) -> Result<(), FailingRun> {
+    let choices = unsafe { &mut *addr_of_mut!(DEMONIC_CHOICES) };
+    choices.clear();
+    unsafe { DEMONIC_RANDOM = Some(seed) };
+    let result = run_quietly(harness).map_err(|message| (choices.clone(), message));
+    unsafe { DEMONIC_RANDOM = None };
+    choices.clear();
+    reset_sstate();
+    result
+}
+
+/// Runs the harness natively with the given answers of demonic_nondet(),
+/// e.g. those of a failing run of enumerate_demonic_choices or of a Kani
+/// counterexample, and prints the monitored stack after every operation
+/// and the tags arguments are passed and bound with.
+/// Choices beyond the answers are answered true. Returns the answers used
+/// and the message of the check that failed if it fails.
+#[cfg(not(kani))]
+pub fn replay_demonic_choices(harness: fn(), answers: &[bool]) -> Result<(), FailingRun> {
+    let choices = unsafe { &mut *addr_of_mut!(DEMONIC_CHOICES) };
+    choices.clear();
+    choices.extend_from_slice(answers);
+    unsafe { DEMONIC_TRACE = true };
+    let result = run_quietly(harness);
+    unsafe { DEMONIC_TRACE = false };
+    choices.truncate(unsafe { DEMONIC_NEXT_CHOICE });
+    let result = result.map_err(|message| (choices.clone(), message));
+    choices.clear();
+    reset_sstate();
+    result
+}
+
+/// Runs the harness natively once for each of the runs seeds from
+/// first_seed on, for programs with too many choices to enumerate, and
+/// returns the seed of every failing run with the message of its check.
+#[cfg(not(kani))]
+pub fn fuzz_demonic_choices(harness: fn(), first_seed: u64, runs: u64) -> Vec<(u64, String)> {
+    let mut failures = Vec::new();
+    let mut i = 0;
+    while i < runs {
+        let seed = first_seed.wrapping_add(i);
+        if let Err((_, message)) = run_with_seed(harness, seed) {
+            failures.push((seed, message));
+        }
+        i += 1;
+    }
+    failures
+}
 
 pub fn new_local<U>(loc: *const U, size: usize) -> PointerId {
     // switch monitor to this one
@@ -67,10 +294,43 @@ pub fn new_local<U>(loc: *const U, size: usize) -> PointerId {
     push_unique(loc, size)
 }
 
+// StorageDead: the local goes out of scope. If it holds the monitored
+// location its stack is retired, any later use of a tag derived from it is a
+// use after scope. This model has no protectors to check.
+pub fn storage_dead<U>(loc: *const U) {
+    unsafe {
+        if same_pointer(SSTATE_MONITOR_OBJECT, loc) && SSTATE_MONITOR_ON {
+            SSTATE_STACK_TOPS = 0;
+            SSTATE_MONITOR_DEAD = true;
+        }
+    }
+}
+
+// StorageLive: the local comes back into scope, e.g. on the next iteration
+// of a loop, with a fresh stack.
+pub fn storage_live<U>(loc: *const U, size: usize) -> PointerId {
+    unsafe {
+        if same_pointer(SSTATE_MONITOR_OBJECT, loc) && SSTATE_MONITOR_ON {
+            SSTATE_STACK_TOPS = 0;
+            SSTATE_MONITOR_DEAD = false;
+        }
+    }
+    push_unique(loc, size)
+}
+
+// Whether the monitored location is one of the bytes [offset, size) of the
+// object ptr points to.
+fn monitors<U>(ptr: *const U, offset: usize, size: usize) -> bool {
+    unsafe {
+        same_pointer(SSTATE_MONITOR_OBJECT, ptr) && offset <= SSTATE_MONITOR_OFFSET && SSTATE_MONITOR_OFFSET < size &&
+            SSTATE_MONITOR_ON
+    }
+}
+
 pub fn push_shared<U>(ptr: *const U, offset: usize, size: usize) {
     assert!(offset < size);
     unsafe {
-        if same_pointer(SSTATE_MONITOR_OBJECT, ptr) && offset <= SSTATE_MONITOR_OFFSET && SSTATE_MONITOR_OFFSET < size && SSTATE_MONITOR_ON {
+        if monitors(ptr, offset, size) {
             let top = SSTATE_STACK_TOPS;
             assert!(top < STACK_DEPTH);
             SSTATE_STACK_KINDS[top] = KIND_SHARED_RW;
@@ -79,56 +339,82 @@ pub fn push_shared<U>(ptr: *const U, offset: usize, size: usize) {
     }
 }
 
-pub fn push_unique<U>(ptr: *const U, size: usize) -> PointerId {
+// Takes a fresh tag and pushes an item with it on the monitored stack if
+// the monitored location is one of the size bytes ptr points to. Every push
+// takes a tag, so that tags do not depend on the monitor choice.
+fn push_tagged<U>(ptr: *const U, size: usize, item_kind: StackItemKind) -> PointerId {
     unsafe {
         let ptr_id_old = SSTATE_NEXT_PTR_ID;
-        if same_pointer(SSTATE_MONITOR_OBJECT, ptr) &&
-            SSTATE_MONITOR_OFFSET < size && SSTATE_MONITOR_ON  {
-                let top = SSTATE_STACK_TOPS;
-                assert!(top < STACK_DEPTH);
-                SSTATE_STACK_KINDS[top] = KIND_UNIQUE;
-                SSTATE_STACK_IDS[top] = ptr_id_old;
-                SSTATE_STACK_TOPS += 1;
-                SSTATE_NEXT_PTR_ID += 1;
-            }
+        SSTATE_NEXT_PTR_ID += 1;
+        if monitors(ptr, 0, size) {
+            let top = SSTATE_STACK_TOPS;
+            assert!(top < STACK_DEPTH);
+            SSTATE_STACK_KINDS[top] = item_kind;
+            SSTATE_STACK_IDS[top] = ptr_id_old;
+            SSTATE_STACK_TOPS += 1;
+        }
         ptr_id_old
     }
 }
 
-fn use_2<U>(ptr: *const U, offset: usize, size: usize, kind: PointerValueKind, id: PointerId) {
+pub fn push_unique<U>(ptr: *const U, size: usize) -> PointerId {
+    push_tagged(ptr, size, KIND_UNIQUE)
+}
+
+pub fn push_shared_ro<U>(ptr: *const U, size: usize) -> PointerId {
+    push_tagged(ptr, size, KIND_SHARED_RO)
+}
+
+// Pops the items above the one granting the access on the monitored stack,
+// if the monitored location is accessed. A write (USE-2) keeps everything up
+// to the granting item, a read (READ-1) also keeps the SharedRO items right
+// above it.
+fn access<U>(ptr: *const U, offset: usize, size: usize, kind: PointerValueKind, id: PointerId, write: bool) {
     unsafe {
-        if same_pointer(SSTATE_MONITOR_OBJECT, ptr) && offset < SSTATE_MONITOR_OFFSET && SSTATE_MONITOR_OFFSET < size &&
-            SSTATE_MONITOR_ON {
+        if monitors(ptr, offset, size) {
+            let context = SSTATE_CONTEXT;
+            if SSTATE_MONITOR_DEAD {
+                trace_stack();
+            }
+            assert!(!SSTATE_MONITOR_DEAD, "{}Use after scope.", context);
             let top = SSTATE_STACK_TOPS;
             let mut found = false;
-            if kind == KIND_IDENTIFIED {
-                let mut i = 0;
-                let mut new_top = 0;
-                while (i < STACK_DEPTH) && (i < top) {
-                    if SSTATE_STACK_KINDS[i] == KIND_UNIQUE && SSTATE_STACK_IDS[i] == id {
-                        new_top = i+1;
-                        found = true;
-                    }
-                    i += 1;
-                }
-                SSTATE_STACK_TOPS = new_top;
-            } else {
-                let mut i = 0;
-                let mut new_top = 0;
-                while (i < STACK_DEPTH) && (i < top) {
-                    if SSTATE_STACK_KINDS[i] == KIND_SHARED_RW {
-                        new_top = i+1;
-                        found = true;
-                    }
-                    i += 1;
+            let mut i = 0;
+            let mut new_top = 0;
+            while (i < STACK_DEPTH) && (i < top) {
+                let item_kind = SSTATE_STACK_KINDS[i];
+                let granting = if kind == KIND_IDENTIFIED {
+                    let matching = SSTATE_STACK_IDS[i] == id;
+                    if write { item_kind == KIND_UNIQUE && matching } else { item_kind != KIND_SHARED_RW && matching }
+                } else {
+                    item_kind == KIND_SHARED_RW
+                };
+                if granting {
+                    new_top = i+1;
+                    found = true;
+                } else if !write && found && new_top == i && item_kind == KIND_SHARED_RO {
+                    new_top = i+1;
                 }
-                SSTATE_STACK_TOPS = new_top;
+                i += 1;
             }
-            assert!(found, "Stack violated.");
+            if !found {
+                // the stack the access is checked against, emptied below
+                trace_stack();
+            }
+            SSTATE_STACK_TOPS = new_top;
+            assert!(found, "{}Stack violated.", context);
         }
     }
 }
 
+fn use_2<U>(ptr: *const U, offset: usize, size: usize, kind: PointerValueKind, id: PointerId) {
+    access(ptr, offset, size, kind, id, true);
+}
+
+fn read_1<U>(ptr: *const U, offset: usize, size: usize, kind: PointerValueKind, id: PointerId) {
+    access(ptr, offset, size, kind, id, false);
+}
+
 fn new_mutable_ref<U>(loc: *const U, size: usize, kind: PointerValueKind, tag: PointerId) -> PointerId {
     use_2(loc, 0, size, kind, tag);
     push_unique(loc, size)
@@ -140,175 +426,373 @@ fn new_mutable_raw<U>(loc: *const U, offset: usize, size: usize, kind: PointerVa
     0
 }
 
-static mut PARAM_1__POINTER: *const u8 = null();
-static mut PARAM_1__POINTER_OFFSET: usize = 0;
-static mut PARAM_1__POINTER_SIZE: usize = 0;
-static mut PARAM_1__POINTER_KIND: PointerValueKind = 0;
-static mut PARAM_1__TAG: PointerId = 0;
-static mut PARAM_2__POINTER: *const u8 = null();
-static mut PARAM_2__POINTER_OFFSET: usize = 0;
-static mut PARAM_2__POINTER_SIZE: usize = 0;
-static mut PARAM_2__POINTER_KIND: PointerValueKind = 0;
-static mut PARAM_2__TAG: PointerId = 0;
+fn new_shared_ref<U>(loc: *const U, size: usize, kind: PointerValueKind, tag: PointerId) -> PointerId {
+    read_1(loc, 0, size, kind, tag);
+    push_shared_ro(loc, size)
+}
+
+// The operations below find the tags of pointers in the tag map from the
+// address of the variable holding them. The pointee is accessed as a whole,
+// from offset 0.
 
-fn get_param_1__pointer() -> *const u8 {
-    unsafe { PARAM_1__POINTER }
+// `let local = ...;`
+fn new_local_at<U>(local: *const U) {
+    let id = new_local(local, std::mem::size_of::<U>());
+    tags().set(local, KIND_IDENTIFIED, id);
 }
 
-fn get_param_1__pointer_offset() -> usize {
-    unsafe { PARAM_1__POINTER_OFFSET }
+// StorageDead(local)
+fn storage_dead_at<U>(local: *const U) {
+    storage_dead(local);
 }
 
-fn get_param_1__pointer_size() -> usize {
-    unsafe { PARAM_1__POINTER_SIZE }
+// StorageLive(local): the local gets a new tag.
+fn storage_live_at<U>(local: *const U) {
+    let id = storage_live(local, std::mem::size_of::<U>());
+    tags().set(local, KIND_IDENTIFIED, id);
 }
 
-fn set_param_1__pointer<T>(v: *const T) {
-    unsafe { PARAM_1__POINTER = v as *const _ as *const u8 };
+// `let new_raw = &mut local as *mut _;`, through a temporary reference
+fn raw_from_local<P: PointerSlot>(new_raw: *const P, local: *const P::Target) {
+    let (kind, tag) = tags().get(local);
+    let size = std::mem::size_of::<P::Target>();
+    let temporary_ref__tag = new_mutable_ref(local, size, kind, tag);
+    let id = new_mutable_raw(local, 0, size, KIND_IDENTIFIED, temporary_ref__tag);
+    tags().set(new_raw, KIND_NONE, id);
 }
 
-fn set_param_1__pointer_offset(v: usize) {
-    unsafe { PARAM_1__POINTER_OFFSET = v };
+// `let new_ref = &mut local;`
+fn unique_from_local<P: PointerSlot>(new_ref: *const P, local: *const P::Target) {
+    let (kind, tag) = tags().get(local);
+    let size = std::mem::size_of::<P::Target>();
+    let id = new_mutable_ref(local, size, kind, tag);
+    tags().set(new_ref, KIND_IDENTIFIED, id);
 }
 
-fn set_param_1__pointer_size(v: usize) {
-    unsafe { PARAM_1__POINTER_SIZE = v };
+// `let new_ref = &local;`
+fn shared_from_local<P: PointerSlot>(new_ref: *const P, local: *const P::Target) {
+    let (kind, tag) = tags().get(local);
+    let size = std::mem::size_of::<P::Target>();
+    let id = new_shared_ref(local, size, kind, tag);
+    tags().set(new_ref, KIND_IDENTIFIED, id);
 }
 
-fn get_param_1__pointer_kind() -> PointerValueKind {
-    unsafe { PARAM_1__POINTER_KIND }
+// `let new_ref = &mut *old_ref;`
+fn unique_from_ref<P, Q>(new_ref: *const P, old_ref: *const Q)
+where P: PointerSlot, Q: PointerSlot<Target = P::Target> {
+    let (kind, tag) = tags().get(old_ref);
+    let size = std::mem::size_of::<P::Target>();
+    let id = new_mutable_ref(slot_target(old_ref), size, kind, tag);
+    tags().set(new_ref, KIND_IDENTIFIED, id);
 }
 
-fn set_param_1__pointer_kind(v: PointerValueKind) {
-    unsafe { PARAM_1__POINTER_KIND = v };
+// `let new_ref = &*old_ref;`
+fn shared_from_ref<P, Q>(new_ref: *const P, old_ref: *const Q)
+where P: PointerSlot, Q: PointerSlot<Target = P::Target> {
+    let (kind, tag) = tags().get(old_ref);
+    let size = std::mem::size_of::<P::Target>();
+    let id = new_shared_ref(slot_target(old_ref), size, kind, tag);
+    tags().set(new_ref, KIND_IDENTIFIED, id);
 }
 
-fn get_param_1__tag() -> PointerId {
-    unsafe { PARAM_1__TAG }
+// `let new_raw = old_ref as *mut _;`
+fn raw_from_ref<P, Q>(new_raw: *const P, old_ref: *const Q)
+where P: PointerSlot, Q: PointerSlot<Target = P::Target> {
+    let (kind, tag) = tags().get(old_ref);
+    let size = std::mem::size_of::<P::Target>();
+    let id = new_mutable_raw(slot_target(old_ref), 0, size, kind, tag);
+    tags().set(new_raw, KIND_NONE, id);
 }
 
-fn set_param_1__tag(v: PointerId) {
-    unsafe { PARAM_1__TAG = v }
+// `*ptr = ...` through the pointer held by the variable at slot.
+fn use_2_at<P: PointerSlot>(slot: *const P) {
+    let (kind, tag) = tags().get(slot);
+    let size = std::mem::size_of::<P::Target>();
+    use_2(slot_target(slot), 0, size, kind, tag);
 }
 
-fn get_param_2__pointer() -> *const u8 {
-    unsafe { PARAM_2__POINTER }
+// `... = *ptr` through the pointer held by the variable at slot.
+fn read_1_at<P: PointerSlot>(slot: *const P) {
+    let (kind, tag) = tags().get(slot);
+    let size = std::mem::size_of::<P::Target>();
+    read_1(slot_target(slot), 0, size, kind, tag);
 }
 
-fn get_param_2__pointer_offset() -> usize {
-    unsafe { PARAM_2__POINTER_OFFSET }
+// `*ptr = value` where value is a pointer: a write through the pointer held
+// by the variable at slot. The tag of value is kept in the tag map under the
+// address it is stored at, as for a variable.
+fn store_pointer_at<P: PointerSlot, V>(slot: *const P, value: *const V) {
+    use_2_at(slot);
+    let (kind, tag) = tags().get(value);
+    tags().set(slot_target(slot), kind, tag);
 }
 
-fn get_param_2__pointer_size() -> usize {
-    unsafe { PARAM_2__POINTER_SIZE }
+// `let x = *ptr` where x is a pointer: a read through the pointer held by
+// the variable at slot, x gets the tag stored with the value.
+fn load_pointer_at<X, P: PointerSlot>(x: *const X, slot: *const P) {
+    read_1_at(slot);
+    let (kind, tag) = tags().get(slot_target(slot));
+    tags().set(x, kind, tag);
 }
 
-fn set_param_2__pointer<T>(v: *const T) {
-    unsafe { PARAM_2__POINTER = v as *const _ as *const u8 };
+// `local = ...`
+fn use_2_local<U>(local: *const U) {
+    let (kind, tag) = tags().get(local);
+    use_2(local, 0, std::mem::size_of::<U>(), kind, tag);
 }
 
-fn set_param_2__pointer_offset(v: usize) {
-    unsafe { PARAM_2__POINTER_OFFSET = v };
+// `... = local`
+fn read_1_local<U>(local: *const U) {
+    let (kind, tag) = tags().get(local);
+    read_1(local, 0, std::mem::size_of::<U>(), kind, tag);
 }
 
-fn set_param_2__pointer_size(v: usize) {
-    unsafe { PARAM_2__POINTER_SIZE = v };
+// Model functions the macros of macros.rs expand to. The context names the
+// variable in the messages of the checks failing during the operation.
+
+fn sb_new_local<U>(local: *const U, context: &'static str) {
+    unsafe { SSTATE_CONTEXT = context };
+    new_local_at(local);
+    trace_stack();
+    unsafe { SSTATE_CONTEXT = "" };
 }
 
-fn get_param_2__pointer_kind() -> PointerValueKind {
-    unsafe { PARAM_2__POINTER_KIND }
+fn sb_storage_dead<U>(local: *const U, context: &'static str) {
+    unsafe { SSTATE_CONTEXT = context };
+    storage_dead_at(local);
+    trace_stack();
+    unsafe { SSTATE_CONTEXT = "" };
 }
 
-fn set_param_2__pointer_kind(v: PointerValueKind) {
-    unsafe { PARAM_2__POINTER_KIND = v }
+fn sb_storage_live<U>(local: *const U, context: &'static str) {
+    unsafe { SSTATE_CONTEXT = context };
+    storage_live_at(local);
+    trace_stack();
+    unsafe { SSTATE_CONTEXT = "" };
 }
 
-fn get_param_2__tag() -> PointerId {
-    unsafe { PARAM_2__TAG }
+fn sb_unique_from_local<P: PointerSlot>(new_ref: *const P, local: *const P::Target, context: &'static str) {
+    unsafe { SSTATE_CONTEXT = context };
+    unique_from_local(new_ref, local);
+    trace_stack();
+    unsafe { SSTATE_CONTEXT = "" };
 }
 
-fn set_param_2__tag(v: PointerId) {
-    unsafe { PARAM_2__TAG = v };
+fn sb_shared_from_local<P: PointerSlot>(new_ref: *const P, local: *const P::Target, context: &'static str) {
+    unsafe { SSTATE_CONTEXT = context };
+    shared_from_local(new_ref, local);
+    trace_stack();
+    unsafe { SSTATE_CONTEXT = "" };
 }
 
-fn example1(x: &mut i32, y: &mut i32) -> i32 {
-    let x__pointer = get_param_1__pointer();
-    let x__pointer_offset = get_param_1__pointer_offset();
-    let x__pointer_size = get_param_1__pointer_size();
-    let x__pointer_kind = get_param_1__pointer_kind();
-    let x__tag = get_param_1__tag();
-    let y__pointer = get_param_2__pointer();
-    let y__pointer_offset= get_param_2__pointer_offset();
-    let y__pointer_size = get_param_2__pointer_size();
-    let y__pointer_kind = get_param_2__pointer_kind();
-    let y__tag = get_param_2__tag();
-
-    let x_rename = &mut *x;
-    let x_rename__pointer = x__pointer.clone();
-    let x_rename__pointer_offset = x__pointer_offset;
-    let x_rename__pointer_size = x__pointer_size;
-    let x_rename__pointer_kind = KIND_IDENTIFIED;
-    let x_rename__pointer_id = new_mutable_ref(x__pointer, x__pointer_size, x__pointer_kind, x__tag);
-
-    let y_rename = &mut *y;
-    let y_rename__pointer = y__pointer.clone();
-    let y_rename__pointer_offset = y__pointer_offset;
-    let y_rename__pointer_size = y__pointer_size;
-    let y_rename__pointer_kind = KIND_IDENTIFIED;
-    let y_rename__pointer_id = new_mutable_ref(y__pointer, x__pointer_size, y__pointer_kind, y__tag);
-    *x_rename = 42;
-    use_2(x_rename__pointer, x_rename__pointer_offset, x_rename__pointer_size, x_rename__pointer_kind, x_rename__pointer_id);
-    *y_rename = 13;
-    use_2(y_rename__pointer, y_rename__pointer_offset, y_rename__pointer_size, y_rename__pointer_kind, y_rename__pointer_id);
-    *x
+fn sb_unique_from_ref<P, Q>(new_ref: *const P, old_ref: *const Q, context: &'static str)
+where P: PointerSlot, Q: PointerSlot<Target = P::Target> {
+    unsafe { SSTATE_CONTEXT = context };
+    unique_from_ref(new_ref, old_ref);
+    trace_stack();
+    unsafe { SSTATE_CONTEXT = "" };
+}
+
+fn sb_shared_from_ref<P, Q>(new_ref: *const P, old_ref: *const Q, context: &'static str)
+where P: PointerSlot, Q: PointerSlot<Target = P::Target> {
+    unsafe { SSTATE_CONTEXT = context };
+    shared_from_ref(new_ref, old_ref);
+    trace_stack();
+    unsafe { SSTATE_CONTEXT = "" };
+}
+
+fn sb_raw_from_ref<P, Q>(new_raw: *const P, old_ref: *const Q, context: &'static str)
+where P: PointerSlot, Q: PointerSlot<Target = P::Target> {
+    unsafe { SSTATE_CONTEXT = context };
+    raw_from_ref(new_raw, old_ref);
+    trace_stack();
+    unsafe { SSTATE_CONTEXT = "" };
+}
+
+fn sb_raw_from_local<P: PointerSlot>(new_raw: *const P, local: *const P::Target, context: &'static str) {
+    unsafe { SSTATE_CONTEXT = context };
+    raw_from_local(new_raw, local);
+    trace_stack();
+    unsafe { SSTATE_CONTEXT = "" };
+}
+
+fn sb_use2<P: PointerSlot>(used: *const P, context: &'static str) {
+    unsafe { SSTATE_CONTEXT = context };
+    use_2_at(used);
+    trace_stack();
+    unsafe { SSTATE_CONTEXT = "" };
+}
+
+fn sb_use2_local<U>(local: *const U, context: &'static str) {
+    unsafe { SSTATE_CONTEXT = context };
+    use_2_local(local);
+    trace_stack();
+    unsafe { SSTATE_CONTEXT = "" };
+}
+
+fn sb_read1_local<U>(local: *const U, context: &'static str) {
+    unsafe { SSTATE_CONTEXT = context };
+    read_1_local(local);
+    trace_stack();
+    unsafe { SSTATE_CONTEXT = "" };
+}
+
+fn sb_copy_pointer<X, P>(x: *const X, source: *const P) {
+    let (kind, tag) = tags().get(source);
+    tags().set(x, kind, tag);
+}
+
+fn sb_store_pointer<P: PointerSlot, V>(used: *const P, value: *const V, context: &'static str) {
+    unsafe { SSTATE_CONTEXT = context };
+    store_pointer_at(used, value);
+    trace_stack();
+    unsafe { SSTATE_CONTEXT = "" };
+}
+
+fn sb_load_pointer<X, P: PointerSlot>(x: *const X, used: *const P, context: &'static str) {
+    unsafe { SSTATE_CONTEXT = context };
+    load_pointer_at(x, used);
+    trace_stack();
+    unsafe { SSTATE_CONTEXT = "" };
+}
+
+fn sb_read1<P: PointerSlot>(used: *const P, context: &'static str) {
+    unsafe { SSTATE_CONTEXT = context };
+    read_1_at(used);
+    trace_stack();
+    unsafe { SSTATE_CONTEXT = "" };
+}
+
+fn sb_pass_arg<P>(index: usize, arg: *const P) {
+    tags().pass_arg(index, arg);
+    trace_arg("PASS_ARG", index, tags().arg(index));
+}
+
+fn sb_bind_arg<P>(index: usize, param: *const P) {
+    tags().bind_arg(index, param);
+    trace_arg("BIND_ARG", index, tags().arg(index));
+}
+
+fn sb_pass_return<P>(result: *const P) {
+    tags().pass_return(result);
 }
 
-fn zero_usize() -> usize {
+fn sb_bind_return<P>(result: *const P) {
+    tags().bind_return(result);
+}
+
+// This model has no protectors, the retag on function entry is a plain
+// reborrow.
+fn sb_protect<P>(_arg: *const P) -> usize {
     0
 }
 
+fn sb_end_protect(_count: usize) {}
+
+#[stacked_borrows::instrument]
+fn example1(x: &mut i32, y: &mut i32) -> i32 {
+    *x = 42;
+    *y = 13;
+    *x
+}
+
 #[kani::proof]
+#[stacked_borrows::instrument]
 fn main() {
     let mut local = 5;
-    let local__size = std::mem::size_of_val(&local);
-    let _local__offset = zero_usize();
-    let local__pointer = &local as *const i32;
-    // Steps involved in creating pointer:
-    // create a ref to a local,
-    // create a raw pointer to the ref,
-    // create the pointer to this.
-    let local__pointer_kind = KIND_IDENTIFIED;
-
-    let local__tag = new_local(local__pointer, local__size);
-
     let raw_pointer = &mut local as *mut i32;
-    let _temporary_ref__size = std::mem::size_of_val(&local);
-    let _temporary_ref__offset = zero_usize();
-    let temporary_ref__pointer = &local as *const i32;
-    let temporary_ref__pointer_kind = KIND_IDENTIFIED;
-    let temporary_ref__tag = new_mutable_ref(local__pointer, local__size, local__pointer_kind, local__tag);
-
-    let raw_pointer__pointer = &local as *const i32;
-    let raw_pointer__size = std::mem::size_of_val(&local);
-    let raw_pointer__offset = zero_usize();
-    let raw_pointer__pointer_kind = KIND_NONE;
-    let raw_pointer__tag = new_mutable_raw(temporary_ref__pointer, raw_pointer__offset, raw_pointer__size, temporary_ref__pointer_kind, temporary_ref__tag);
-
-    set_param_1__pointer(&local);
-    set_param_1__pointer_size(raw_pointer__size);
-    set_param_1__pointer_offset(raw_pointer__offset);
-    set_param_1__pointer_kind(KIND_IDENTIFIED);
-    set_param_1__tag(new_mutable_ref(raw_pointer__pointer, raw_pointer__size, raw_pointer__pointer_kind, raw_pointer__tag));
-
-    set_param_2__pointer(&local);
-    set_param_2__pointer_size(raw_pointer__size);
-    set_param_2__pointer_offset(raw_pointer__offset);
-    set_param_2__pointer_kind(KIND_IDENTIFIED);
-    set_param_2__tag(new_mutable_ref(raw_pointer__pointer, raw_pointer__size, raw_pointer__pointer_kind, raw_pointer__tag));
 
     let result = unsafe {
-        example1(&mut *raw_pointer,
-                 &mut *raw_pointer)
+        example1(&mut *raw_pointer, &mut *raw_pointer)
     };
     assert_eq!(result, 13);
 }
+
+// A raw pointer stored through a reference and loaded back keeps its tag.
+#[kani::proof]
+#[stacked_borrows::instrument]
+fn stored_pointer_keeps_tag() {
+    let mut a = 1;
+    let mut cell: *mut i32 = std::ptr::null_mut();
+    let p = &mut cell;
+    let q = &mut a as *mut i32;
+    *p = q;
+    let x = *p;
+    unsafe { *x = 5; }
+    assert_eq!(a, 5);
+}
+
+// Reads through a unique reference keep the shared references derived from
+// it valid.
+#[kani::proof]
+#[stacked_borrows::instrument]
+fn shared_references_read() {
+    let mut a = 1;
+    let l = &a;
+    let v = *l;
+    let m = &mut a;
+    let r = &*m;
+    let s = &*r;
+    let x = *s;
+    let y = *r;
+    let z = *m;
+    let w = *s;
+    assert_eq!(v + x + y + z + w, 5);
+}
+
+// A write through the raw pointer a shared reference was derived from pops
+// its SharedRO item, reading through the reference afterwards is a violation.
+#[kani::proof]
+#[kani::should_panic]
+#[stacked_borrows::instrument]
+fn shared_reference_read_after_raw_write() {
+    let mut a = 1;
+    let m = &mut a;
+    let p = m as *mut i32;
+    let r = unsafe { &*p };
+    unsafe { *p = 2; }
+    let x = *r;
+    assert_eq!(x, 2);
+}
+
+// A reference created from a raw pointer to a local that went out of scope
+// dangles: reading through the raw pointer to create it is a use after scope.
+#[kani::proof]
+#[kani::should_panic]
+#[stacked_borrows::instrument]
+fn dangling_reference() {
+    let mut a = 1;
+    let p = &mut a as *mut i32;
+    storage_dead!(a);
+    let r = unsafe { &*p };
+    let x = *r;
+    assert_eq!(x, 1);
+}
+
+// A local coming back into scope gets a fresh stack and tag, references to
+// it taken afterwards are valid.
+#[kani::proof]
+#[stacked_borrows::instrument]
+fn local_back_in_scope() {
+    let mut a = 1;
+    let p = &mut a;
+    *p = 2;
+    storage_dead!(a);
+    storage_live!(a);
+    let q = &mut a;
+    *q = 3;
+    assert_eq!(a, 3);
+}
+
+// Harnesses run natively by enumerate_demonic.rs, with whether some choice
+// of the monitor is expected to make them fail: main, and the harnesses
+// marked #[kani::should_panic].
+#[cfg(not(kani))]
+pub const HARNESSES: &[(&str, fn(), bool)] = &[
+    ("main", main, true),
+    ("stored_pointer_keeps_tag", stored_pointer_keeps_tag, false),
+    ("shared_references_read", shared_references_read, false),
+    ("shared_reference_read_after_raw_write", shared_reference_read_after_raw_write, true),
+    ("dangling_reference", dangling_reference, true),
+    ("local_back_in_scope", local_back_in_scope, false),
+];
@@ -0,0 +1,59 @@
+#ifdef DEMONIC
+#include "stacked_borrows_demonic.h"
+#else
+#include "stacked_borrows.h"
+#endif
+
+/// valid reborrows of two locals, the demonic model tracks the location it
+/// picks at its allocation and never switches to the other local
+int main() {
+  SB_INIT(true, 16);
+
+  // let mut a = 1;
+  int32_t a = 1;
+  NEW_LOCAL(a);
+
+  // let mut b = 2;
+  int32_t b = 2;
+  NEW_LOCAL(b);
+
+  // let x = &mut a;
+  USE2_LOCAL(a);
+  int32_t *x = &a;
+  UNIQUE_FROM_LOCAL(x, a);
+
+  // *x = 3;
+  USE2(x);
+  *x = 3;
+
+  // let x = &mut a;
+  USE2_LOCAL(a);
+  x = &a;
+  UNIQUE_FROM_LOCAL(x, a);
+
+  // *x = 4;
+  USE2(x);
+  *x = 4;
+
+  // let y = &mut b;
+  USE2_LOCAL(b);
+  int32_t *y = &b;
+  UNIQUE_FROM_LOCAL(y, b);
+
+  // *y = 5;
+  USE2(y);
+  *y = 5;
+
+  // let y = &mut b;
+  USE2_LOCAL(b);
+  y = &b;
+  UNIQUE_FROM_LOCAL(y, b);
+
+  // *y = 6;
+  USE2(y);
+  *y = 6;
+
+  // assert_eq!(a + b, 10);
+  assert(a + b == 10);
+  return 0;
+}